@@ -1,20 +1,44 @@
 //! Simple embedded key/value database.
 
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fs;
 use std::io;
 use std::io::Read;
-use std::io::Seek;
 use std::io::Write;
 use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic;
+use std::sync::mpsc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::thread;
 use std::time;
 
+#[cfg(feature = "encryption")]
+use aes_gcm::{aead::Aead, aead::AeadCore, aead::KeyInit, aead::OsRng, Aes256Gcm, Nonce};
+
 /// Enum with all errors that might happen using this crate.
 #[derive(Debug)]
 pub enum Error {
+    ClockError(String),
+    Corruption(String),
     DecodeError(String),
+    #[cfg(feature = "encryption")]
+    DecryptionFailed,
     IoError(io::Error),
+    InvalidKey,
+    InvalidRange,
+    KeyNotFound(String),
     KeyTooLong,
+    FileSizeLimitExceeded,
+    ReadOnly,
+    StaleEntry(String),
     ValueTooLong,
 }
 
@@ -38,37 +62,131 @@ trait Encodable: Sized {
     fn decode(buf: &[u8]) -> Result<Self>;
 }
 
+/// Value size reserved to mark tombstones, i.e. records written when deleting a key.
+///
+/// A tombstone is made of a header and a key but doesn't have any value. Real values must thus
+/// be strictly shorter than this.
+const TOMBSTONE: u32 = u32::MAX;
+
+/// Largest size of a key.
+///
+/// The most significant byte of the encoded key size holds the flags of the record, which
+/// leaves 24 bits for the key size. Flags of records written by CaskDB are always zero since
+/// keys are never that large.
+const MAX_KEY_SIZE: u32 = (1 << 24) - 1;
+
 #[derive(Debug, PartialEq, Eq)]
 struct Header {
     timestamp: u32, // Useless but we keep it in order to stay file format compatible with CaskDB.
     key_size: u32,
     value_size: u32,
+    flags: u8, // high bits reserved for future record types, low bits give the header length
+}
+
+/// Sizes in bytes of the header fields known to this version, in the order in which they're
+/// encoded: the timestamp, the key size and the value size.
+const HEADER_FIELDS: [usize; 3] = [4, 4, 4];
+
+/// Size of the header fields known to this version, which is the size of the headers it writes.
+const HEADER_SIZE: usize = {
+    let mut size = 0;
+    let mut i = 0;
+    while i < HEADER_FIELDS.len() {
+        size += HEADER_FIELDS[i];
+        i += 1;
+    }
+    size
+};
+
+/// Bits of the flags giving the number of 4-byte words following the known header fields.
+///
+/// Future versions can extend the header with new fields this way. Older readers skip them, so
+/// the extended records can still be read. CaskDB never sets these bits.
+const HEADER_WORDS_MASK: u8 = 0x0f;
+
+/// Flag of merge operands, records whose value is combined with the previous value of the key
+/// instead of replacing it, see [`MyDB::merge`]. Flags are stored in the key size, so readers
+/// unaware of them (such as CaskDB) read a huge key size and can't read flagged records at all.
+const MERGE_FLAG: u8 = 0x10;
+
+impl Header {
+    /// Returns the size of the header as stored in the record, extra fields included.
+    fn len(&self) -> usize {
+        HEADER_SIZE + 4 * (self.flags & HEADER_WORDS_MASK) as usize
+    }
+
+    fn is_tombstone(&self) -> bool {
+        self.value_size == TOMBSTONE
+    }
+
+    /// Returns the number of value bytes following the key of the record.
+    fn value_len(&self) -> usize {
+        if self.is_tombstone() {
+            0
+        } else {
+            self.value_size as usize
+        }
+    }
+
+    /// Returns the total size of the record, header included.
+    ///
+    /// Fails with [`Error::DecodeError`] if the size doesn't fit in a `usize`, which can happen
+    /// with corrupted or malicious headers.
+    fn record_size(&self) -> Result<usize> {
+        self.len()
+            .checked_add(self.key_size as usize)
+            .and_then(|size| size.checked_add(self.value_len()))
+            .ok_or_else(|| {
+                Error::DecodeError(format!(
+                    "record too large: {} bytes of key and {} bytes of value",
+                    self.key_size,
+                    self.value_len()
+                ))
+            })
+    }
 }
 
-const HEADER_SIZE: usize = 12; // 12 bytes to encode three u32
+impl Header {
+    /// Encodes the fields known to this version, which make the whole header unless
+    /// [`Header::len`] says otherwise.
+    fn encode_known(&self) -> [u8; HEADER_SIZE] {
+        let key_size = self.key_size | (self.flags as u32) << 24;
+        let mut buf = [0; HEADER_SIZE];
+        buf[..4].copy_from_slice(&self.timestamp.to_le_bytes());
+        buf[4..8].copy_from_slice(&key_size.to_le_bytes());
+        buf[8..].copy_from_slice(&self.value_size.to_le_bytes());
+        buf
+    }
+}
 
 impl Encodable for Header {
     fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(HEADER_SIZE);
-        buf.extend_from_slice(&self.timestamp.to_le_bytes());
-        buf.extend_from_slice(&self.key_size.to_le_bytes());
-        buf.extend_from_slice(&self.value_size.to_le_bytes());
+        let mut buf = Vec::with_capacity(self.len());
+        buf.extend_from_slice(&self.encode_known());
+        // Fields unknown to this version are left zeroed.
+        buf.resize(self.len(), 0);
         buf
     }
 
+    /// Decodes the header at the start of `buf`, which must hold at least the known fields.
+    ///
+    /// Extra fields, as written by future versions, are skipped: the header is [`Header::len`]
+    /// bytes long, which can be more than the known fields.
     fn decode(buf: &[u8]) -> Result<Self> {
-        if buf.len() != HEADER_SIZE {
+        if buf.len() < HEADER_SIZE {
             return Err(Error::DecodeError(format!(
-                "wrong header buffer size: got {} bytes, expected {} bytes",
+                "header buffer too small: got {} bytes, expected at least {} bytes",
                 buf.len(),
                 HEADER_SIZE
             )));
         }
 
+        let key_size = u32::from_le_bytes(buf[4..8].try_into().unwrap());
         Ok(Self {
             timestamp: u32::from_le_bytes(buf[..4].try_into().unwrap()),
-            key_size: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
-            value_size: u32::from_le_bytes(buf[8..].try_into().unwrap()),
+            key_size: key_size & MAX_KEY_SIZE,
+            value_size: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            flags: (key_size >> 24) as u8,
         })
     }
 }
@@ -77,35 +195,84 @@ impl Encodable for Header {
 struct KeyValue {
     timestamp: u32,
     key: String,
-    value: String,
+    value: Option<String>, // `None` for tombstones
+    flags: u8,
 }
 
+/// Smallest timestamp of the records written by this version.
+///
+/// Timestamps are clamped to it so that no record starts with a header made of zeros (an empty
+/// key with an empty value written at the Unix epoch otherwise would), which is how space
+/// reserved by [`MyDB::reserve`] is told apart from records.
+const MIN_TIMESTAMP: u32 = 1;
+
 impl KeyValue {
     fn new(timestamp: u32, key: String, value: String) -> Result<Self> {
-        if key.len() > u32::MAX as usize {
-            return Err(Error::KeyTooLong);
-        }
-        if value.len() > u32::MAX as usize {
-            return Err(Error::ValueTooLong);
-        }
+        check_key_len(key.len())?;
+        check_value_len(value.len())?;
         Ok(KeyValue {
-            timestamp,
+            timestamp: std::cmp::max(timestamp, MIN_TIMESTAMP),
             key,
-            value,
+            value: Some(value),
+            flags: 0,
         })
     }
-}
 
-impl Encodable for KeyValue {
-    fn encode(&self) -> Vec<u8> {
-        let header = Header {
+    fn tombstone(timestamp: u32, key: String) -> Result<Self> {
+        check_key_len(key.len())?;
+        Ok(KeyValue {
+            timestamp: std::cmp::max(timestamp, MIN_TIMESTAMP),
+            key,
+            value: None,
+            flags: 0,
+        })
+    }
+
+    fn header(&self) -> Header {
+        Header {
             timestamp: self.timestamp,
             key_size: u32::try_from(self.key.len()).unwrap(), // cannot overflow u32 if we use `KeyValue::new`
-            value_size: u32::try_from(self.value.len()).unwrap(), // idem
-        };
-        let mut buf = header.encode();
+            value_size: match &self.value {
+                Some(value) => u32::try_from(value.len()).unwrap(), // idem
+                None => TOMBSTONE,
+            },
+            flags: self.flags,
+        }
+    }
+}
+
+fn check_key_len(len: usize) -> Result<()> {
+    if len > MAX_KEY_SIZE as usize {
+        return Err(Error::KeyTooLong);
+    }
+    Ok(())
+}
+
+fn check_value_len(len: usize) -> Result<()> {
+    // The largest size is reserved for tombstones.
+    if len >= TOMBSTONE as usize {
+        return Err(Error::ValueTooLong);
+    }
+    Ok(())
+}
+
+impl KeyValue {
+    /// Appends the encoded record to `buf`, see [`Encodable::encode`].
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        let header = self.header();
+        buf.extend_from_slice(&header.encode_known());
+        buf.resize(buf.len() + header.len() - HEADER_SIZE, 0);
         buf.extend_from_slice(self.key.as_bytes());
-        buf.extend_from_slice(self.value.as_bytes());
+        if let Some(value) = &self.value {
+            buf.extend_from_slice(value.as_bytes());
+        }
+    }
+}
+
+impl Encodable for KeyValue {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
         buf
     }
 
@@ -116,10 +283,10 @@ impl Encodable for KeyValue {
             ));
         }
 
-        let header = Header::decode(&buf[..HEADER_SIZE])?;
+        let header = Header::decode(buf)?;
         let key_size = header.key_size as usize;
-        let value_size = header.value_size as usize;
-        let total_size = HEADER_SIZE + key_size + value_size;
+        let value_size = header.value_len();
+        let total_size = header.record_size()?;
 
         if buf.len() != total_size {
             return Err(Error::DecodeError(format!(
@@ -129,223 +296,5078 @@ impl Encodable for KeyValue {
             )));
         }
 
-        let offset_key = HEADER_SIZE;
+        let offset_key = header.len();
         let offset_value = offset_key + key_size;
 
         let key = &buf[offset_key..offset_value];
         let key = std::str::from_utf8(key)?.to_owned();
 
-        let value = &buf[offset_value..offset_value + value_size];
-        let value = std::str::from_utf8(value)?.to_owned();
+        let value = if header.is_tombstone() {
+            None
+        } else {
+            let value = &buf[offset_value..offset_value + value_size];
+            Some(std::str::from_utf8(value)?.to_owned())
+        };
 
         Ok(KeyValue {
             timestamp: header.timestamp,
             key,
             value,
+            flags: header.flags,
         })
     }
 }
 
+pub mod format {
+    //! Codec of the records of database files, for external tools.
+    //!
+    //! A database file is a sequence of records, each made of a 12-byte header followed by the
+    //! key and the value. The header holds, as little-endian `u32`:
+    //!
+    //! - the timestamp of the record, in seconds since the Unix epoch, which is at least 1;
+    //! - the size of the key in bytes, whose most significant byte is reserved for flags;
+    //! - the size of the value in bytes, or `u32::MAX` for tombstones (written when deleting a
+    //!   key) which don't have any value.
+    //!
+    //! The 4 least significant bits of the flags give a number of 4-byte words of extra header
+    //! fields between the value size and the key. They're reserved for future versions and
+    //! skipped when reading.
+    //! The next bit (`0x10`) marks merge operands, which are combined with the previous value
+    //! of the key rather than replacing it (see [`MyDB::merge`](crate::MyDB::merge)).
+    //!
+    //! Keys and values are UTF-8 strings. The latest record of a key gives its current value.
+    //! This format is stable and compatible with CaskDB as long as no flag is set: CaskDB reads
+    //! the flags as part of the key size, so it can't read merge operands or records with extra
+    //! header fields. Split databases have a different format which isn't covered here.
+    //!
+    //! ```
+    //! use mydb::format::{decode_record, encode_record, Record};
+    //!
+    //! let record = Record {
+    //!     timestamp: 10,
+    //!     key: "hello".to_string(),
+    //!     value: Some("world".to_string()),
+    //! };
+    //! let bytes = encode_record(&record).unwrap();
+    //! assert_eq!(
+    //!     bytes,
+    //!     b"\x0a\0\0\0\x05\0\0\0\x05\0\0\0helloworld",
+    //! );
+    //! assert_eq!(decode_record(&bytes).unwrap(), (record, bytes.len()));
+    //! ```
+
+    use crate::{Encodable, Error, Header, KeyValue, Result};
+
+    /// Size in bytes of the header of each record.
+    pub const HEADER_SIZE: usize = crate::HEADER_SIZE;
+
+    /// Record of a database file.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Record {
+        /// Time at which the record was written, in seconds since the Unix epoch.
+        pub timestamp: u32,
+        /// Key of the record.
+        pub key: String,
+        /// Value of the record, `None` for tombstones.
+        pub value: Option<String>,
+    }
+
+    /// Encodes `record` as it's written to database files.
+    ///
+    /// A zero timestamp is encoded as 1, so that the header of the record is never made of
+    /// zeros.
+    ///
+    /// Fails with [`Error::KeyTooLong`] or [`Error::ValueTooLong`] if the key or value can't be
+    /// encoded.
+    pub fn encode_record(record: &Record) -> Result<Vec<u8>> {
+        let (timestamp, key) = (record.timestamp, record.key.clone());
+        let kv = match &record.value {
+            Some(value) => KeyValue::new(timestamp, key, value.clone())?,
+            None => KeyValue::tombstone(timestamp, key)?,
+        };
+        Ok(kv.encode())
+    }
+
+    /// Decodes the record at the start of `buf`.
+    ///
+    /// Returns the record along with its size in bytes, which is the offset of the next record
+    /// when decoding a whole database file. Fails with [`Error::DecodeError`] if `buf` doesn't
+    /// start with a complete and valid record.
+    pub fn decode_record(buf: &[u8]) -> Result<(Record, usize)> {
+        if buf.len() < HEADER_SIZE {
+            return Err(Error::DecodeError(
+                "not enough data to decode header".to_string(),
+            ));
+        }
+
+        let header = Header::decode(buf)?;
+        let size = header.record_size()?;
+        if buf.len() < size {
+            return Err(Error::DecodeError(format!(
+                "incomplete record: got {} bytes, expected {} bytes",
+                buf.len(),
+                size
+            )));
+        }
+
+        let kv = KeyValue::decode(&buf[..size])?;
+        let record = Record {
+            timestamp: kv.timestamp,
+            key: kv.key,
+            value: kv.value,
+        };
+        Ok((record, size))
+    }
+}
+
+pub mod fuzz {
+    //! Entry points for fuzzing the parsing of database files.
+    //!
+    //! These never panic, whatever the input: malformed records are reported as errors.
+
+    use crate::{format, Result};
+
+    /// Decodes the record at the start of `bytes`, see [`format::decode_record`].
+    pub fn decode_record(bytes: &[u8]) -> Result<(format::Record, usize)> {
+        format::decode_record(bytes)
+    }
+}
+
+/// Magic at the start of key directory snapshots, see [`MyDB::save_keydir`].
+const KEYDIR_MAGIC: &[u8; 8] = b"MYDBKDIR";
+
+/// Size of the value offset that follows the key of each record in the keys file of a split
+/// database.
+const VALUE_OFFSET_SIZE: usize = 8;
+
+// In split mode, `size` and `offset` locate the value within the values file instead.
+#[derive(Debug, PartialEq, Eq)]
 struct KeyDirEntry {
-    size: u32,     // total size of the record (in bytes)
-    offset: usize, // offset within the file where the record's header starts
+    timestamp: u32,          // timestamp of the record
+    key_size: u32,           // size of the key (in bytes)
+    header_size: u8,         // size of the header of the record (in bytes)
+    size: u32,               // total size of the record (in bytes)
+    offset: usize,           // offset within the file where the record's header starts
+    value_hash: Option<u64>, // hash of the value, see `MyDB::value_hash`
+}
+
+impl KeyDirEntry {
+    /// Returns whether both entries locate the same record, whether their value hashes are
+    /// known or not.
+    fn same_record(&self, other: &KeyDirEntry) -> bool {
+        (
+            self.timestamp,
+            self.key_size,
+            self.header_size,
+            self.size,
+            self.offset,
+        ) == (
+            other.timestamp,
+            other.key_size,
+            other.header_size,
+            other.size,
+            other.offset,
+        )
+    }
+}
+
+// The second map holds the merge operands written after the entry of each key, in log order,
+// see `MyDB::merge`.
+#[derive(Default)]
+struct KeyDir(
+    HashMap<String, KeyDirEntry>,
+    HashMap<String, Vec<KeyDirEntry>>,
+);
+
+/// Metadata about the record currently associated with a key.
+///
+/// This is a read-only view of what the database keeps in memory for each key, see
+/// [`MyDB::metadata`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryMeta {
+    /// Timestamp (in seconds since the Unix epoch) at which the record was written.
+    pub timestamp: u32,
+    /// Total size of the record (in bytes). In split mode, this is the size of the value.
+    pub size: u32,
+    /// Offset within the file where the record starts. In split mode, this is the offset of the
+    /// value within the values file.
+    pub offset: usize,
 }
 
-struct KeyDir(HashMap<String, KeyDirEntry>);
+impl From<&KeyDirEntry> for EntryMeta {
+    fn from(entry: &KeyDirEntry) -> Self {
+        Self {
+            timestamp: entry.timestamp,
+            size: entry.size,
+            offset: entry.offset,
+        }
+    }
+}
 
 impl KeyDir {
-    fn load<W: io::Read + io::Seek>(w: W) -> Result<Self> {
-        let mut buf = vec![0; 1024];
-        let mut reader = io::BufReader::new(w);
-        let mut keydir = HashMap::new();
-        let mut offset: usize = 0;
+    /// Returns the latest timestamp of the keys, or zero if there isn't any.
+    fn max_timestamp(&self) -> u32 {
+        self.0
+            .values()
+            .map(|entry| entry.timestamp)
+            .max()
+            .unwrap_or(0)
+    }
 
-        loop {
-            if let Err(err) = reader.read_exact(&mut buf[..HEADER_SIZE]) {
-                if err.kind() == io::ErrorKind::UnexpectedEof {
-                    break;
-                }
-                return Err(Error::IoError(err));
+    /// Returns whether both key directories locate the same records and merge operands, see
+    /// [`KeyDirEntry::same_record`].
+    fn same_records(&self, other: &KeyDir) -> bool {
+        self.0.len() == other.0.len()
+            && self.1.len() == other.1.len()
+            && self
+                .0
+                .iter()
+                .all(|(key, entry)| other.0.get(key).is_some_and(|e| entry.same_record(e)))
+            && self.1.iter().all(|(key, entries)| {
+                other.1.get(key).is_some_and(|es| {
+                    entries.len() == es.len()
+                        && entries.iter().zip(es).all(|(a, b)| a.same_record(b))
+                })
+            })
+    }
+
+    /// Writes a snapshot of the key directory of a database file whose records end at `offset`,
+    /// see [`MyDB::save_keydir`].
+    ///
+    /// The snapshot is made of a magic, `offset` and the number of entries as `u64`, followed by
+    /// the entries and a CRC-32 checksum of everything before it. Each entry is made of the key
+    /// size (`u32`), the key, the header size (`u8`), the timestamp and size of the record
+    /// (`u32`) and its offset (`u64`), all little-endian.
+    fn save<W: io::Write>(&self, mut w: W, offset: usize) -> Result<()> {
+        let mut crc = Crc32::new();
+        let mut buf = Vec::with_capacity(24);
+        buf.extend_from_slice(KEYDIR_MAGIC);
+        buf.extend_from_slice(&(offset as u64).to_le_bytes());
+        buf.extend_from_slice(&(self.0.len() as u64).to_le_bytes());
+        crc.update(&buf);
+        w.write_all(&buf)?;
+
+        for (key, entry) in &self.0 {
+            buf.clear();
+            buf.extend_from_slice(&entry.key_size.to_le_bytes());
+            buf.extend_from_slice(key.as_bytes());
+            buf.push(entry.header_size);
+            buf.extend_from_slice(&entry.timestamp.to_le_bytes());
+            buf.extend_from_slice(&entry.size.to_le_bytes());
+            buf.extend_from_slice(&(entry.offset as u64).to_le_bytes());
+            crc.update(&buf);
+            w.write_all(&buf)?;
+        }
+
+        w.write_all(&crc.finish().to_le_bytes())?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Reads a snapshot written by [`KeyDir::save`], returning the key directory and the offset
+    /// at which the records of the database file ended.
+    fn restore(buf: &[u8]) -> Result<(Self, usize)> {
+        let corrupted = || Error::Corruption("invalid key directory snapshot".to_string());
+        let (buf, checksum) = match buf.len().checked_sub(4) {
+            Some(len) if len >= 24 => buf.split_at(len),
+            _ => return Err(corrupted()),
+        };
+        let mut crc = Crc32::new();
+        crc.update(buf);
+        if &buf[..8] != KEYDIR_MAGIC || crc.finish().to_le_bytes() != checksum {
+            return Err(corrupted());
+        }
+
+        let mut rest = &buf[8..];
+        let mut take = |n: usize| -> Result<&[u8]> {
+            if rest.len() < n {
+                return Err(corrupted());
             }
+            let (taken, left) = rest.split_at(n);
+            rest = left;
+            Ok(taken)
+        };
+        let offset = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        let count = u64::from_le_bytes(take(8)?.try_into().unwrap());
+
+        let mut keydir = KeyDir::default();
+        for _ in 0..count {
+            let key_size = u32::from_le_bytes(take(4)?.try_into().unwrap());
+            let key = std::str::from_utf8(take(key_size as usize)?)?.to_owned();
+            let header_size = take(1)?[0];
+            let entry = KeyDirEntry {
+                timestamp: u32::from_le_bytes(take(4)?.try_into().unwrap()),
+                key_size,
+                header_size,
+                size: u32::from_le_bytes(take(4)?.try_into().unwrap()),
+                offset: u64::from_le_bytes(take(8)?.try_into().unwrap()) as usize,
+                value_hash: None,
+            };
+            keydir.0.insert(key, entry);
+        }
+        if !rest.is_empty() {
+            return Err(corrupted());
+        }
+
+        Ok((keydir, offset as usize))
+    }
+
+    /// Loads the key directory by scanning every record of `w`.
+    ///
+    /// When `split` is true, `w` is expected to be the keys file of a split database, where each
+    /// record is followed by the offset of its value in the values file instead of the value.
+    ///
+    /// Returns the key directory along with statistics of the scan. Since the scan stops after
+    /// the last complete record, the number of bytes scanned is the offset right after it.
+    fn load<W: io::Read + io::Seek>(
+        w: W,
+        split: bool,
+        normalizer: Option<KeyNormalizer>,
+    ) -> Result<(Self, LoadStats)> {
+        let mut keydir = KeyDir::default();
+        let stats = keydir.load_from(w, split, 0, normalizer)?;
+        // Deleted keys leave the map larger than needed for the keys which are left.
+        keydir.0.shrink_to_fit();
+        Ok((keydir, stats))
+    }
+
+    /// Updates the key directory by scanning the records of `w` starting at offset `start`, see
+    /// [`KeyDir::load`]. Keys read are normalized with `normalizer` if set.
+    ///
+    /// Returns statistics of the scan, which stops after the last complete record.
+    fn load_from<W: io::Read + io::Seek>(
+        &mut self,
+        w: W,
+        split: bool,
+        start: usize,
+        normalizer: Option<KeyNormalizer>,
+    ) -> Result<LoadStats> {
+        let started = time::Instant::now();
+        let mut records = 0;
+        let mut header = [0; HEADER_SIZE];
+        let mut reader = io::BufReader::new(w);
+        let (keydir, operands) = (&mut self.0, &mut self.1);
+        let mut offset = start;
+
+        let len = seek(&mut reader, io::SeekFrom::End(0))? as usize;
+        seek(&mut reader, io::SeekFrom::Start(start as u64))?;
 
+        // The last record might be incomplete if a crash happened while it was written, in which
+        // case we stop right before it.
+        while offset + HEADER_SIZE <= len {
             // Read header.
-            let header = Header::decode(&buf[..HEADER_SIZE])?;
+            reader.read_exact(&mut header)?;
+            if is_reserved(&header) {
+                break;
+            }
+            let header = Header::decode(&header)?;
 
             let key_size = header.key_size as usize;
-            let value_size = header.value_size as usize;
-            let total_size = HEADER_SIZE + key_size + value_size;
+            let value_size = header.value_len();
+            let total_size = if split {
+                header.len() + key_size + VALUE_OFFSET_SIZE
+            } else {
+                header.record_size()?
+            };
+            if offset + total_size > len {
+                break;
+            }
+
+            // Skip the header fields unknown to this version.
+            seek(
+                &mut reader,
+                io::SeekFrom::Current((header.len() - HEADER_SIZE) as i64),
+            )?;
 
-            // Read key.
-            buf.resize(std::cmp::max(key_size, buf.len()), 0);
-            reader.read_exact(&mut buf[..key_size])?;
-            let key = std::str::from_utf8(&buf[..key_size])?.to_owned();
+            // Read key, directly in its own buffer so that a large key doesn't inflate a buffer
+            // reused for the rest of the scan.
+            let mut key = vec![0; key_size];
+            reader.read_exact(&mut key)?;
+            let mut key = String::from_utf8(key).map_err(|err| err.utf8_error())?;
+            if let Some(normalizer) = normalizer {
+                key = normalizer.normalize(&key).into_owned();
+            }
 
-            // Ignore reading value.
-            reader.seek(io::SeekFrom::Current(value_size as i64))?;
+            let entry = if split {
+                // Read value offset within the values file.
+                let mut value_offset = [0; VALUE_OFFSET_SIZE];
+                reader.read_exact(&mut value_offset)?;
+                let value_offset = u64::from_le_bytes(value_offset);
 
-            let entry = KeyDirEntry {
-                size: total_size.try_into().unwrap(),
-                offset,
+                KeyDirEntry {
+                    timestamp: header.timestamp,
+                    key_size: header.key_size,
+                    header_size: header.len() as u8,
+                    size: header.value_size,
+                    offset: value_offset.try_into().unwrap(),
+                    value_hash: None,
+                }
+            } else {
+                // Ignore reading value.
+                seek(&mut reader, io::SeekFrom::Current(value_size as i64))?;
+
+                KeyDirEntry {
+                    timestamp: header.timestamp,
+                    key_size: header.key_size,
+                    header_size: header.len() as u8,
+                    size: total_size.try_into().map_err(|_| {
+                        Error::DecodeError(format!("record too large: {} bytes", total_size))
+                    })?,
+                    offset,
+                    value_hash: None,
+                }
             };
-            keydir.insert(key, entry);
+
+            if header.is_tombstone() {
+                keydir.remove(&key);
+                operands.remove(&key);
+            } else if header.flags & MERGE_FLAG != 0 && keydir.contains_key(&key) {
+                operands.entry(key).or_default().push(entry);
+            } else {
+                // An operand without any previous value is a value on its own.
+                operands.remove(&key);
+                keydir.insert(key, entry);
+            }
 
             offset += total_size;
+            records += 1;
         }
 
-        Ok(KeyDir(keydir))
+        Ok(LoadStats {
+            records_scanned: records,
+            bytes_scanned: offset - start,
+            duration: started.elapsed(),
+        })
     }
 }
 
-/// Handle used to interact with the database.
-pub struct MyDB {
-    file: fs::File,
-    keydir: KeyDir,
-    offset: usize,
-}
+/// Storage backend of a database.
+///
+/// A database only needs to read, write and seek within its storage, plus a way to make writes
+/// durable. This is implemented for [`fs::File`], which is what is used most of the time, and
+/// for in-memory buffers ([`io::Cursor<Vec<u8>>`]).
+pub trait Storage: io::Read + io::Write + io::Seek {
+    /// Makes sure that everything written so far is durably stored.
+    fn sync(&mut self) -> io::Result<()>;
 
-impl MyDB {
-    /// Creates an instance of [`MyDB`] by using database file pointed to by `path`.
+    /// Makes sure that the data written so far is durably stored, without necessarily syncing
+    /// metadata which isn't needed to read the data back.
     ///
-    /// If the file doesn't exist then it's created and a new empty database is returned. If the
-    /// file already exists then database is loaded from this file.
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let file = fs::OpenOptions::new()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(path)?;
-        let keydir = KeyDir::load(&file)?;
-        Ok(MyDB {
-            file,
-            keydir,
-            offset: 0,
-        })
+    /// Defaults to [`Storage::sync`].
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.sync()
     }
 
-    /// Creates an instance of [`MyDB`] by using the `file` handle.
-    pub fn new_from_file(file: fs::File) -> Result<Self> {
-        let keydir = KeyDir::load(&file)?;
-        Ok(MyDB {
-            file,
-            keydir,
-            offset: 0,
-        })
+    /// Truncates or extends the storage so that its size becomes `len` bytes.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Hints that the `len` bytes starting at `offset` are about to be read.
+    ///
+    /// This is best-effort and does nothing by default.
+    fn prefetch(&self, offset: u64, len: u64) {
+        let _ = (offset, len);
     }
 
-    /// Gets the value associated with the given `key`.
+    /// Creates an empty storage at `path`, replacing whatever is there.
     ///
-    /// This operation might fail (mainly because of an I/O error) hence we return a [`Result`].
-    /// Besides, the given `key` might not exist within the database so the wrapped value is an
-    /// [`Option`].
-    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
-        let entry = self.keydir.0.get(key);
-        let entry = match entry {
-            Some(entry) => entry,
-            None => return Ok(None),
-        };
+    /// This is used by compaction to write the compacted database to a new file before
+    /// replacing the database file, for databases opened from a path. This fails with
+    /// [`io::ErrorKind::Unsupported`] by default.
+    fn create(path: &Path) -> io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let _ = path;
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
 
-        self.file.seek(io::SeekFrom::Start(entry.offset as u64))?;
+impl Storage for fs::File {
+    fn create(path: &Path) -> io::Result<Self> {
+        fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+    }
 
-        let mut kv = vec![0; entry.size as usize];
-        self.file.read_exact(&mut kv)?;
-        let kv = KeyValue::decode(&kv)?;
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
 
-        Ok(Some(kv.value))
+    fn sync_data(&mut self) -> io::Result<()> {
+        fs::File::sync_data(self)
     }
 
-    /// Sets the value associated with `key` to `value`.
-    ///
-    /// This operation might fail (mainly because of an I/O error) hence we return a [`Result`].
-    /// If the key already exists in the database it is overwritten with the new `value`. Otherwise,
-    /// the value is simply inserted.
-    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
-        let timestamp = now_timestamp();
-        let kv = KeyValue::new(timestamp, key.to_owned(), value.to_owned())?;
-        let kv = kv.encode();
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        fs::File::set_len(self, len)
+    }
 
-        self.file.write_all(&kv)?;
-        self.file.flush()?;
-        self.file.sync_all()?;
+    fn prefetch(&self, offset: u64, len: u64) {
+        #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+        {
+            use std::os::unix::io::AsRawFd;
 
-        let size = kv.len() as u64;
-        let entry = KeyDirEntry {
-            size: size.try_into().unwrap(),
-            offset: self.offset,
-        };
-        self.keydir.0.insert(key.to_owned(), entry);
-        self.offset += size as usize;
+            extern "C" {
+                fn posix_fadvise(fd: i32, offset: i64, len: i64, advice: i32) -> i32;
+            }
+            const POSIX_FADV_WILLNEED: i32 = 3;
 
-        Ok(())
+            // This is only a hint, so failures don't matter.
+            unsafe {
+                posix_fadvise(
+                    self.as_raw_fd(),
+                    offset as i64,
+                    len as i64,
+                    POSIX_FADV_WILLNEED,
+                );
+            }
+        }
+        #[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+        {
+            let _ = (offset, len);
+        }
     }
 }
 
-fn now_timestamp() -> u32 {
-    let time = time::SystemTime::now()
-        .duration_since(time::UNIX_EPOCH)
-        .unwrap();
-    time.as_secs().try_into().unwrap()
+impl Storage for io::Cursor<Vec<u8>> {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().resize(len as usize, 0);
+        Ok(())
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rand::distributions::{Alphanumeric, DistString};
-    use rand::random;
+/// How writes are made durable, see [`MyDB::with_sync_policy`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync both data and metadata after every write, see [`Storage::sync`].
+    #[default]
+    All,
+    /// Only sync data after every write, see [`Storage::sync_data`].
+    ///
+    /// For files this uses `fdatasync` which is faster than `fsync` since metadata such as the
+    /// modification time isn't flushed. Metadata needed to read the data back, like the file
+    /// size, is still flushed which makes it enough for an append-only database.
+    Data,
+    /// Never flush nor sync after writes.
+    ///
+    /// Writes are only durable once [`MyDB::sync`] is called, or once they are synced in the
+    /// background (see [`MyDB::with_background_sync`]). This is much faster but recent writes
+    /// might be lost on crash.
+    Never,
+}
 
-    fn assert_header_encode(header: Header) {
-        let encoded = header.encode();
-        let decoded = Header::decode(&encoded).unwrap();
-        assert_eq!(header, decoded);
+impl SyncPolicy {
+    /// Flushes and syncs `storage` after a write, as required by the policy.
+    ///
+    /// Storages which buffer writes are only flushed when they're synced, so that writes can be
+    /// batched with [`SyncPolicy::Never`].
+    fn sync<S: Storage>(self, storage: &mut S) -> io::Result<()> {
+        match self {
+            SyncPolicy::All => {
+                storage.flush()?;
+                storage.sync()
+            }
+            SyncPolicy::Data => {
+                storage.flush()?;
+                storage.sync_data()
+            }
+            SyncPolicy::Never => Ok(()),
+        }
     }
+}
 
-    fn assert_keyvalue_encode(kv: KeyValue) {
-        let encoded = kv.encode();
-        let decoded = KeyValue::decode(&encoded).unwrap();
-        assert_eq!(kv, decoded);
+/// How values that aren't valid UTF-8 are decoded, see [`MyDB::with_decode_mode`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeMode {
+    /// Fail with [`Error::DecodeError`].
+    #[default]
+    Strict,
+    /// Replace invalid sequences with [`char::REPLACEMENT_CHARACTER`].
+    Lossy,
+}
+
+/// Where a value was read from, see [`MyDB::get_traced`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadSource {
+    /// The read cache, see [`MyDB::with_read_cache`].
+    Cache,
+    /// The storage.
+    Disk,
+}
+
+/// Source of the timestamps of the records, see [`MyDB::with_clock`].
+///
+/// This is implemented for closures returning a [`time::SystemTime`], which makes it easy to
+/// control timestamps in tests.
+pub trait Clock: Send {
+    /// Returns the current time.
+    fn now(&self) -> time::SystemTime;
+}
+
+impl<F: Fn() -> time::SystemTime + Send> Clock for F {
+    fn now(&self) -> time::SystemTime {
+        self()
     }
+}
 
-    #[test]
-    fn test_header() {
-        let headers = [
-            Header {
-                timestamp: 10,
-                key_size: 10,
-                value_size: 10,
-            },
-            Header {
-                timestamp: 0,
-                key_size: 0,
-                value_size: 0,
-            },
-            Header {
-                timestamp: 10000,
-                key_size: 10000,
-                value_size: 10000,
-            },
-        ];
+/// Operator combining the value of a key with merge operands, see [`MyDB::with_merge_operator`].
+///
+/// This is implemented for closures taking the key, its current value (`None` if it doesn't
+/// have any) and the operand, and returning the new value.
+pub trait MergeOp: Send {
+    /// Returns the value of `key` after merging `operand` into its current value `existing`.
+    fn merge(&self, key: &str, existing: Option<&str>, operand: &str) -> String;
+}
 
-        for header in headers {
-            assert_header_encode(header);
-        }
+impl<F: Fn(&str, Option<&str>, &str) -> String + Send> MergeOp for F {
+    fn merge(&self, key: &str, existing: Option<&str>, operand: &str) -> String {
+        self(key, existing, operand)
     }
+}
 
-    #[test]
-    fn test_header_random() {
-        for _ in 0..100 {
-            let header = Header {
+/// Write-ahead log of the writes made to the database file, see [`OpenOptions::with_wal`].
+///
+/// Each entry is made of the offset of the write within the database file (`u64`), the number
+/// of bytes written (`u32`) and the CRC-32 checksum of these bytes (`u32`), all little-endian,
+/// followed by the bytes themselves.
+struct Wal {
+    file: fs::File,
+    size: u64, // size of the entries written since the last checkpoint
+}
+
+/// Size of the header of write-ahead log entries.
+const WAL_HEADER_SIZE: usize = 16;
+
+/// Size of the write-ahead log beyond which it's checkpointed, see [`MyDB::checkpoint`].
+const WAL_CHECKPOINT_SIZE: u64 = 1024 * 1024;
+
+impl Wal {
+    /// Opens the write-ahead log of the database file at `path`, creating it with the `options`
+    /// of the database file, and applies its entries to `file`, which is then synced so that the
+    /// log starts empty.
+    fn open(path: &Path, file: &mut fs::File, options: &fs::OpenOptions) -> Result<Self> {
+        let mut wal = open_file(options.clone().create(true), &wal_path(path))?;
+
+        let mut entries = Vec::new();
+        wal.read_to_end(&mut entries)?;
+        let mut applied = false;
+        let mut rest = &entries[..];
+        while rest.len() >= WAL_HEADER_SIZE {
+            let offset = u64::from_le_bytes(rest[..8].try_into().unwrap());
+            let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+            let checksum = u32::from_le_bytes(rest[12..16].try_into().unwrap());
+            // An incomplete entry was being written when the crash occurred, so the database file
+            // wasn't written yet.
+            let Some(buf) = rest[WAL_HEADER_SIZE..].get(..len) else {
+                break;
+            };
+            let mut crc = Crc32::new();
+            crc.update(buf);
+            if crc.finish() != checksum {
+                break;
+            }
+
+            seek(file, io::SeekFrom::Start(offset))?;
+            file.write_all(buf)?;
+            applied = true;
+            rest = &rest[WAL_HEADER_SIZE + len..];
+        }
+
+        if applied {
+            file.sync_all()?;
+        }
+        if !entries.is_empty() {
+            wal.set_len(0)?;
+            wal.sync_all()?;
+        }
+        Ok(Wal { file: wal, size: 0 })
+    }
+
+    /// Applies the entries left in the write-ahead log of the database file at `path` to `file`
+    /// and removes the log, if there's one, for databases opened without a log.
+    ///
+    /// Entries left by a crash must be applied before the database file changes again, since
+    /// opening the database with a log later on would otherwise write them over newer data.
+    fn recover(path: &Path, file: &mut fs::File) -> Result<()> {
+        let wal_path = wal_path(path);
+        if !wal_path.exists() {
+            return Ok(());
+        }
+        let mut options = fs::OpenOptions::new();
+        options.read(true).write(true);
+        drop(Wal::open(path, file, &options)?);
+        fs::remove_file(&wal_path)?;
+        sync_dir(path)?;
+        Ok(())
+    }
+
+    /// Appends an entry logging that `buf` is about to be written at `offset`, and syncs it
+    /// according to `policy`. Returns the size of the entry.
+    fn append(&mut self, offset: usize, buf: &[u8], policy: SyncPolicy) -> Result<usize> {
+        let mut crc = Crc32::new();
+        crc.update(buf);
+        let mut entry = Vec::with_capacity(WAL_HEADER_SIZE + buf.len());
+        entry.extend_from_slice(&(offset as u64).to_le_bytes());
+        entry.extend_from_slice(&u32::try_from(buf.len()).unwrap().to_le_bytes());
+        entry.extend_from_slice(&crc.finish().to_le_bytes());
+        entry.extend_from_slice(buf);
+
+        seek(&mut self.file, io::SeekFrom::Start(self.size))?;
+        self.file.write_all(&entry)?;
+        policy.sync(&mut self.file)?;
+        self.size += entry.len() as u64;
+        Ok(entry.len())
+    }
+
+    /// Empties the log, once every logged write is durably stored in the database file.
+    fn clear(&mut self) -> Result<()> {
+        if self.size > 0 {
+            self.file.set_len(0)?;
+            self.file.sync_all()?;
+            self.size = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Thread periodically syncing database files, see [`MyDB::with_background_sync`].
+struct BackgroundSync {
+    commands: mpsc::Sender<BackgroundSyncCommand>,
+    handle: thread::JoinHandle<()>,
+}
+
+enum BackgroundSyncCommand {
+    Stop,
+    Reopen(Vec<PathBuf>), // files were replaced, e.g. by compaction
+}
+
+impl BackgroundSync {
+    fn spawn(mut files: Vec<fs::File>, interval: time::Duration) -> Self {
+        let (commands, received) = mpsc::channel();
+        let handle = thread::spawn(move || loop {
+            match received.recv_timeout(interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    for file in &files {
+                        // Errors can't be reported from here, syncing is retried at the next
+                        // tick and the database is synced again when dropped anyway.
+                        let _ = file.sync_data();
+                    }
+                }
+                Ok(BackgroundSyncCommand::Reopen(paths)) => {
+                    let reopened: io::Result<Vec<_>> = paths
+                        .iter()
+                        .map(|path| fs::OpenOptions::new().write(true).open(path))
+                        .collect();
+                    if let Ok(reopened) = reopened {
+                        files = reopened;
+                    }
+                }
+                Ok(BackgroundSyncCommand::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    break
+                }
+            }
+        });
+        Self { commands, handle }
+    }
+
+    /// Makes the thread sync the files at `paths` from now on.
+    fn reopen(&self, paths: Vec<PathBuf>) {
+        let _ = self.commands.send(BackgroundSyncCommand::Reopen(paths));
+    }
+
+    fn stop(self) {
+        // The thread also stops if the channel is disconnected so sending can't fail in a way
+        // that matters.
+        let _ = self.commands.send(BackgroundSyncCommand::Stop);
+        let _ = self.handle.join();
+    }
+}
+
+/// Summary of the sizes (in bytes) of values, see [`MyDB::size_histogram`].
+///
+/// Every field is zero for an empty database.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SizeHistogram {
+    /// Number of values.
+    pub count: usize,
+    /// Size of the smallest value.
+    pub min: usize,
+    /// Size of the largest value.
+    pub max: usize,
+    /// Average size of values.
+    pub mean: f64,
+    /// Median size of values.
+    pub p50: usize,
+    /// 90th percentile of the sizes of values.
+    pub p90: usize,
+    /// 99th percentile of the sizes of values.
+    pub p99: usize,
+}
+
+/// Statistics of the scan made to load a database when it's opened, see [`MyDB::load_stats`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LoadStats {
+    /// Number of records scanned, including overwritten records and tombstones.
+    pub records_scanned: usize,
+    /// Number of bytes scanned (of the keys file in split mode).
+    pub bytes_scanned: usize,
+    /// Time spent scanning.
+    pub duration: time::Duration,
+}
+
+/// What was discarded to recover a database when it was opened, see [`MyDB::new_with_report`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Number of bytes truncated from the end of the database file (of the keys file in split
+    /// mode), including space reserved by [`MyDB::reserve`].
+    pub bytes_truncated: u64,
+    /// Number of incomplete records discarded, as left by a crash in the middle of a write.
+    pub records_skipped: usize,
+}
+
+/// Progress of a compaction, reported by [`MyDB::compact_with_progress`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactProgress {
+    /// Number of records rewritten so far.
+    pub records_processed: usize,
+    /// Number of bytes written so far.
+    pub bytes_written: usize,
+}
+
+/// Reads and writes made by a database handle since it was opened, see [`MyDB::io_counters`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IoCounters {
+    /// Number of bytes read.
+    pub bytes_read: u64,
+    /// Number of bytes written.
+    pub bytes_written: u64,
+    /// Number of reads.
+    pub reads: u64,
+    /// Number of writes.
+    pub writes: u64,
+}
+
+impl IoCounters {
+    fn read(&mut self, len: usize) {
+        self.reads += 1;
+        self.bytes_read += len as u64;
+    }
+
+    fn write(&mut self, len: usize) {
+        self.writes += 1;
+        self.bytes_written += len as u64;
+    }
+
+    fn add(&mut self, other: IoCounters) {
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+        self.reads += other.reads;
+        self.writes += other.writes;
+    }
+}
+
+/// Outcome of a batch of writes, reported by [`MyDB::try_set_batch`].
+#[derive(Debug)]
+pub struct BatchReport {
+    /// Number of pairs written.
+    pub written: usize,
+    /// Index within the batch and reason of every pair which was rejected.
+    pub rejected: Vec<(usize, Error)>,
+}
+
+/// Least recently used cache of values.
+struct Cache {
+    capacity: usize,
+    tick: u64,                              // incremented on every access
+    values: HashMap<String, (String, u64)>, // value and tick of last access of each key
+    recency: BTreeMap<u64, String>,         // keys ordered by tick of last access
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            values: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<String> {
+        let (value, tick) = self.values.get_mut(key)?;
+        self.recency.remove(tick);
+        self.tick += 1;
+        *tick = self.tick;
+        self.recency.insert(self.tick, key.to_owned());
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: String, value: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        if self.values.len() >= self.capacity {
+            if let Some((_, lru)) = self.recency.pop_first() {
+                self.values.remove(&lru);
+            }
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.values.insert(key, (value, self.tick));
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((_, tick)) = self.values.remove(key) {
+            self.recency.remove(&tick);
+        }
+    }
+}
+
+/// Runtime configuration of a database, see the `with_*` methods of [`MyDB`].
+#[derive(Default)]
+struct Config {
+    skip_redundant_writes: bool,
+    strict_keys: bool,                     // reject keys with control characters
+    verify_keys: bool,                     // check the key of records read by `get`
+    skip_drop_sync: bool,                  // see `with_sync_on_drop`
+    key_normalizer: Option<KeyNormalizer>, // set when opening, see `OpenOptions`
+    sync_policy: SyncPolicy,
+    in_place_updates: bool,
+    decode_mode: DecodeMode,
+    clock: Option<Box<dyn Clock>>, // system clock if not set
+    merge_op: Option<Box<dyn MergeOp>>,
+    max_file_size: Option<u64>,
+    preallocated_segment_size: Option<usize>,
+    monotonic_timestamps: bool,
+    temp_dir: Option<PathBuf>, // where compaction writes, next to the database file if not set
+    max_unsynced_bytes: Option<usize>,
+    cache_max_value_size: Option<usize>, // values larger than this aren't cached
+    bloom_bits_per_key: Option<usize>,   // see `with_bloom_filters`
+    #[cfg(feature = "encryption")]
+    cipher: Option<Aes256Gcm>, // values aren't encrypted if not set
+}
+
+impl Config {
+    /// Fails with [`Error::FileSizeLimitExceeded`] if the database isn't allowed to grow to
+    /// `size` bytes, see [`MyDB::with_max_file_size`].
+    fn check_file_size(&self, size: usize) -> Result<()> {
+        match self.max_file_size {
+            Some(max) if size as u64 > max => Err(Error::FileSizeLimitExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    /// Copies the settings of `other` which matter to read values, see
+    /// [`MyDB::configure_sealed`].
+    fn copy_read_settings(&mut self, other: &Config) {
+        self.verify_keys = other.verify_keys;
+        self.key_normalizer = other.key_normalizer;
+        self.decode_mode = other.decode_mode;
+        #[cfg(feature = "encryption")]
+        {
+            self.cipher = other.cipher.clone();
+        }
+    }
+}
+
+/// Record read by a [`LogReader`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    /// Offset of the record within the database file.
+    pub offset: u64,
+    /// Time at which the record was written, in seconds since the Unix epoch.
+    pub timestamp: u32,
+    /// Key of the record.
+    pub key: String,
+    /// Value of the record, `None` for tombstones.
+    pub value: Option<String>,
+}
+
+/// Sequential reader of every record of a database file, in the order in which they were
+/// written.
+///
+/// Unlike [`MyDB`], this doesn't keep track of the latest record of each key: overwritten
+/// records and tombstones are returned as well. This is meant for tools processing the log as
+/// a stream, see [`format`](mod@format) to decode records from bytes instead. Split databases
+/// aren't supported.
+pub struct LogReader<R: io::Read = io::BufReader<fs::File>> {
+    reader: R,
+    offset: u64,
+}
+
+impl LogReader {
+    /// Opens the database file pointed to by `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = fs::File::open(path)?;
+        Ok(Self::new(io::BufReader::new(file)))
+    }
+}
+
+impl<R: io::Read> LogReader<R> {
+    /// Creates a reader of the records read from `reader`.
+    pub fn new(reader: R) -> Self {
+        LogReader { reader, offset: 0 }
+    }
+
+    /// Reads the next record, or returns `None` at the end of the log.
+    ///
+    /// An incomplete record at the end of the log, as left by a crash in the middle of a write,
+    /// is considered to be past the end of the log.
+    pub fn next_record(&mut self) -> Result<Option<LogRecord>> {
+        let mut buf = vec![0; HEADER_SIZE];
+        if !read_record_part(&mut self.reader, &mut buf)? || is_reserved(&buf) {
+            return Ok(None);
+        }
+
+        // The rest of the record is read through `take` instead of resizing the buffer to the
+        // size given by the header, so that a corrupted size doesn't allocate more than what's
+        // left of the log.
+        let header = Header::decode(&buf)?;
+        let rest = (header.record_size()? - HEADER_SIZE) as u64;
+        if (&mut self.reader).take(rest).read_to_end(&mut buf)? as u64 != rest {
+            return Ok(None);
+        }
+
+        let kv = KeyValue::decode(&buf)?;
+        let record = LogRecord {
+            offset: self.offset,
+            timestamp: kv.timestamp,
+            key: kv.key,
+            value: kv.value,
+        };
+        self.offset += buf.len() as u64;
+        Ok(Some(record))
+    }
+}
+
+impl<R: io::Read> Iterator for LogReader<R> {
+    type Item = Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/// Fills `buf` from `reader`, returning whether it could be filled before the end of `reader`.
+fn read_record_part<R: io::Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(Error::IoError(err)),
+    }
+}
+
+/// Counts the records of the database file pointed to by `path`, without loading it.
+///
+/// This is a lighter scan than opening the database since only headers are read. Every record
+/// is counted, including overwritten records and tombstones, so this is an upper bound of the
+/// number of keys. A truncated record at the end of the file isn't counted. This doesn't
+/// support the files of split databases.
+pub fn estimate_records<P: AsRef<Path>>(path: P) -> Result<usize> {
+    let mut file = fs::File::open(path)?;
+    let len = seek(&mut file, io::SeekFrom::End(0))?;
+    seek(&mut file, io::SeekFrom::Start(0))?;
+
+    let mut reader = io::BufReader::new(file);
+    let mut buf = [0; HEADER_SIZE];
+    let mut offset = 0;
+    let mut count = 0;
+
+    loop {
+        if let Err(err) = reader.read_exact(&mut buf) {
+            if err.kind() == io::ErrorKind::UnexpectedEof {
+                break;
+            }
+            return Err(Error::IoError(err));
+        }
+        if is_reserved(&buf) {
+            break;
+        }
+
+        let header = Header::decode(&buf)?;
+        let size = (header.len() - HEADER_SIZE) as u64
+            + (header.key_size as u64)
+            + (header.value_len() as u64);
+        offset += (HEADER_SIZE as u64) + size;
+        if offset > len {
+            break;
+        }
+
+        count += 1;
+        reader.seek_relative(size as i64)?;
+    }
+
+    Ok(count)
+}
+
+/// Normalization of keys, see [`OpenOptions::with_key_normalizer`].
+#[derive(Debug, Clone, Copy)]
+pub struct KeyNormalizer(fn(&str) -> String);
+
+impl KeyNormalizer {
+    /// Returns a normalizer mapping every key to `normalize(key)`.
+    pub fn new(normalize: fn(&str) -> String) -> Self {
+        KeyNormalizer(normalize)
+    }
+
+    /// Returns a normalizer which lowercases keys, to make them case-insensitive.
+    pub fn lowercase() -> Self {
+        KeyNormalizer(str::to_lowercase)
+    }
+
+    /// Normalizes `key`, borrowing it if it's already normalized so that callers don't need to
+    /// copy it.
+    fn normalize<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        let normalized = (self.0)(key);
+        match normalized == key {
+            true => Cow::Borrowed(key),
+            false => Cow::Owned(normalized),
+        }
+    }
+}
+
+/// Options to open a database file, for the settings which matter before the database is loaded.
+///
+/// Settings which can be changed on an open database are set with the `with_*` methods of
+/// [`MyDB`] instead.
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    create: bool,
+    wal: bool,
+    key_normalizer: Option<KeyNormalizer>,
+    #[cfg(unix)]
+    mode: Option<u32>,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions {
+            create: true,
+            wal: false,
+            key_normalizer: None,
+            #[cfg(unix)]
+            mode: None,
+        }
+    }
+}
+
+impl OpenOptions {
+    /// Returns the default options, which are the options used by [`MyDB::new`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether the database file is created if it doesn't exist.
+    ///
+    /// When disabled, opening a database file which doesn't exist fails with
+    /// [`Error::IoError`] of kind [`io::ErrorKind::NotFound`] instead of returning an empty
+    /// database, which protects against typos in paths. It's enabled by default.
+    pub fn with_create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// Enables or disables the write-ahead log.
+    ///
+    /// With a write-ahead log, writes are first appended to a small log next to the database
+    /// file (with a `.wal` suffix) which is synced according to the sync policy (see
+    /// [`MyDB::with_sync_policy`]), and only then written to the database file, which isn't
+    /// synced. Writes are thus as durable as without the log, but only the log is synced for
+    /// each write, until the database file is synced by [`MyDB::checkpoint`]. This happens
+    /// automatically once the log grows past 1 MiB, before compaction and when the database is
+    /// dropped. When opening the database, writes of the log which didn't make it to the
+    /// database file, because of a crash, are applied again, even when opening it without the
+    /// log, which is then removed. It's disabled by default.
+    pub fn with_wal(mut self, enabled: bool) -> Self {
+        self.wal = enabled;
+        self
+    }
+
+    /// Sets the normalizer applied to every key, e.g. to make keys case-insensitive with
+    /// [`KeyNormalizer::lowercase`].
+    ///
+    /// Keys are normalized by [`MyDB::get`], [`MyDB::set`], [`MyDB::delete`] and
+    /// [`MyDB::contains_key`], so that keys with the same normalized form refer to the same
+    /// entry, as well as when the database is loaded (and reloaded), so that records written
+    /// before the normalizer was used collide too. The database must then always be opened with
+    /// the same normalizer. Keys aren't normalized by default.
+    pub fn with_key_normalizer(mut self, normalizer: KeyNormalizer) -> Self {
+        self.key_normalizer = Some(normalizer);
+        self
+    }
+
+    /// Sets the permissions of the database file when it's created, such as `0o600` to make it
+    /// only accessible to its owner.
+    ///
+    /// The permissions are filtered by the umask of the process, like with
+    /// [`std::os::unix::fs::OpenOptionsExt::mode`]. They also apply to the write-ahead log, and
+    /// compaction gives the compacted file the permissions of the database file it replaces.
+    /// Defaults to `0o666` (before the umask). Only available on Unix.
+    #[cfg(unix)]
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Opens the database file pointed to by `path` with these options.
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<MyDB> {
+        let path = path.as_ref();
+        let mut options = fs::OpenOptions::new();
+        options
+            .read(true)
+            .write(true)
+            .create(self.create)
+            .truncate(false);
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+        }
+        let mut file = open_file(&options, path)?;
+        let wal = match self.wal {
+            true => Some(Wal::open(path, &mut file, &options)?),
+            false => {
+                Wal::recover(path, &mut file)?;
+                None
+            }
+        };
+        let mut db = MyDB::load_storage(file, self.key_normalizer)?;
+        db.path = Some(path.to_owned());
+        db.wal = wal;
+        Ok(db)
+    }
+}
+
+/// Handle used to interact with the database.
+///
+/// A database is stored within a [`Storage`] which defaults to [`fs::File`], so that `MyDB` is
+/// the type of file-backed databases.
+///
+/// `MyDB` is [`Send`] whenever its storage is, so it can be moved to another thread or held
+/// across `.await` points. It isn't [`Sync`] since even reads need a mutable reference, e.g. to
+/// update the read cache: wrap it in a [`SharedDB`] to share it between threads.
+pub struct MyDB<S: Storage = fs::File> {
+    file: S,
+    values: Option<S>, // values file when the database is in split mode
+    keydir: KeyDir,
+    offset: usize,
+    config: Config,
+    cache: Option<Cache>,
+    path: Option<PathBuf>,
+    temporary: bool, // file removed on drop
+    background_sync: Option<BackgroundSync>,
+    base: Option<Box<MyDB<S>>>, // read-only database below this one, see `open_with_base`
+    read_only: bool,
+    load_stats: LoadStats,
+    recovery: RecoveryReport,
+    last_timestamp: u32, // latest timestamp written, see `with_monotonic_timestamps`
+    unsynced_bytes: usize, // written since the last sync, see `with_max_unsynced_bytes`
+    wal: Option<Wal>,
+    sealed: Vec<MyDB<S>>,                // read-only segments, see `open_dir`
+    sealed_keys: HashMap<String, usize>, // keys whose latest value is in a sealed segment
+    segments_dir: Option<PathBuf>,       // set by `open_dir`
+    allocated: usize, // known physical size of the file, see `with_preallocated_segment_size`
+    io: IoCounters,
+    rewrites: u64, // incremented when records are moved or segments rolled, see `SharedDB::compact`
+    bloom: Option<BloomFilter>, // keys of a sealed segment, see `with_bloom_filters`
+    sealed_lookups: Cell<u64>, // see `segment_lookups`
+}
+
+impl MyDB<fs::File> {
+    /// Creates an instance of [`MyDB`] by using database file pointed to by `path`.
+    ///
+    /// If the file doesn't exist then it's created and a new empty database is returned. If the
+    /// file already exists then database is loaded from this file. See [`OpenOptions`] to
+    /// change how the file is opened.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        OpenOptions::new().open(path)
+    }
+
+    /// Creates an instance of [`MyDB`] like [`MyDB::new`], also reporting what was discarded to
+    /// recover the database.
+    ///
+    /// An incomplete record at the end of the file, as left by a crash in the middle of a write,
+    /// is silently discarded when opening a database. The report tells whether this happened,
+    /// since it means that the last write was lost.
+    pub fn new_with_report<P: AsRef<Path>>(path: P) -> Result<(Self, RecoveryReport)> {
+        let db = Self::new(path)?;
+        let recovery = db.recovery;
+        Ok((db, recovery))
+    }
+
+    /// Creates an empty temporary database.
+    ///
+    /// The database is backed by a new file within the temporary directory of the system (see
+    /// [`std::env::temp_dir`]) which is removed when the database is dropped. This is handy for
+    /// tests and scratch workloads.
+    pub fn open_temp() -> Result<Self> {
+        static COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+        loop {
+            let name = format!(
+                "mydb-{}-{}-{}.db",
+                process::id(),
+                now_timestamp()?,
+                COUNTER.fetch_add(1, atomic::Ordering::Relaxed)
+            );
+            let path = std::env::temp_dir().join(name);
+
+            let file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path);
+            let file = match file {
+                Ok(file) => file,
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(Error::IoError(err)),
+            };
+
+            let mut db = Self::new_from_storage(file)?;
+            db.path = Some(path);
+            db.temporary = true;
+            return Ok(db);
+        }
+    }
+
+    /// Creates an instance of [`MyDB`] by using the database file pointed to by `path` (see
+    /// [`MyDB::new`]) and sets every key/value pair of `iter`.
+    ///
+    /// Pairs are written with a single write, like with [`MyDB::extend`], so that the storage is
+    /// only synced once. Collecting pairs into a [`MyDB`] does the same with a temporary database
+    /// (see [`MyDB::open_temp`]), but panics on errors.
+    pub fn try_from_iter<P, I, K, V>(path: P, iter: I) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let mut db = Self::new(path)?;
+        db.extend(iter)?;
+        Ok(db)
+    }
+
+    /// Creates an instance of [`MyDB`] by using the database file pointed to by `overlay_path`,
+    /// on top of the `base` database.
+    ///
+    /// Writes only go to the overlay database, while [`MyDB::get`] falls back to `base` for keys
+    /// which aren't in the overlay. This makes it possible to share an immutable base between
+    /// several databases. Deleting a key only deletes it from the overlay, so its value in the
+    /// base is visible again. Other methods such as [`MyDB::len`] only look at the overlay.
+    pub fn open_with_base<P: AsRef<Path>>(overlay_path: P, base: MyDB) -> Result<Self> {
+        let mut db = Self::new(overlay_path)?;
+        db.base = Some(Box::new(base));
+        Ok(db)
+    }
+
+    /// Syncs the database in the background every `interval`, instead of after every write.
+    ///
+    /// This sets the sync policy to [`SyncPolicy::Never`] and spawns a thread which periodically
+    /// syncs the database files. This bounds the amount of data which might be lost on crash to
+    /// what was written during the last `interval`, without paying the cost of syncing on every
+    /// write. The thread is stopped when the database is dropped, after which the database is
+    /// synced one last time.
+    pub fn with_background_sync(mut self, interval: time::Duration) -> Result<Self> {
+        let mut files = vec![self.file.try_clone()?];
+        if let Some(values) = &self.values {
+            files.push(values.try_clone()?);
+        }
+
+        if let Some(background_sync) = self.background_sync.take() {
+            background_sync.stop();
+        }
+        self.background_sync = Some(BackgroundSync::spawn(files, interval));
+        self.config.sync_policy = SyncPolicy::Never;
+
+        Ok(self)
+    }
+
+    /// Creates an instance of [`MyDB`] in split mode by using the database files derived from
+    /// `path`.
+    ///
+    /// In split mode keys and metadata are written to a `.keys` file while values are written to
+    /// a `.values` file, both named after `path`. Loading the database then only needs to scan
+    /// the keys file, which is much smaller than a regular database file when values are large.
+    /// Files are created if they don't exist.
+    pub fn new_split<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut options = fs::OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(false);
+
+        let mut file = open_file(&options, &path.with_extension("keys"))?;
+        let mut values = open_file(&options, &path.with_extension("values"))?;
+
+        let (keydir, stats) = KeyDir::load(&mut file, true, None)?;
+        let recovery = truncate_torn_tail(&mut file, stats.bytes_scanned)?;
+        let offset = seek(&mut values, io::SeekFrom::End(0))?.try_into().unwrap();
+
+        let mut db = Self::from_parts(file, Some(values), keydir, offset);
+        db.path = Some(path.to_owned());
+        db.load_stats = stats;
+        db.recovery = recovery;
+        Ok(db)
+    }
+
+    /// Opens the database made of the segment files (with a `.db` extension) of directory `dir`.
+    ///
+    /// Segments are ordered by file name, numerically for names which are numbers (so that
+    /// `10.db` comes after `9.db`), which come after other names. New writes go to the last
+    /// segment, which is created (as `0.db`) if the directory doesn't contain any segment, while
+    /// the other segments are only read. When several segments have a record for the same key,
+    /// the record with the newest timestamp wins, and the record of the last segment wins ties.
+    /// Deleting a key of another segment writes a tombstone to the last segment.
+    ///
+    /// Only [`MyDB::get`], [`MyDB::contains_key`], [`MyDB::metadata`], [`MyDB::value_len`],
+    /// [`MyDB::delete`], [`MyDB::delete_many`], [`MyDB::len`], [`MyDB::is_empty`] and
+    /// [`MyDB::count_prefix`] see the keys of the other segments; other methods, such as
+    /// iteration and compaction, only see the last segment. See [`MyDB::roll_segment`] to start a
+    /// new segment, and [`MyDB::merge_segments`] to compact the other segments.
+    pub fn open_dir<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().is_some_and(|ext| ext == "db") {
+                paths.push(path);
+            }
+        }
+        paths.sort_by_cached_key(|path| (segment_number(path), path.clone()));
+        let active_path = paths.pop().unwrap_or_else(|| dir.join("0.db"));
+
+        // Latest record of each key, as its timestamp, segment and whether it's a tombstone.
+        let mut latest: HashMap<String, (u32, usize, bool)> = HashMap::new();
+        for (i, path) in paths.iter().chain([&active_path]).enumerate() {
+            if !path.exists() {
+                continue;
+            }
+            let mut records = HashMap::new();
+            for record in LogReader::open(path)? {
+                let record = record?;
+                records.insert(record.key, (record.timestamp, record.value.is_none()));
+            }
+            for (key, (timestamp, tombstone)) in records {
+                match latest.get(&key) {
+                    Some(&(newest, _, _)) if newest > timestamp => {}
+                    _ => {
+                        latest.insert(key, (timestamp, i, tombstone));
+                    }
+                }
+            }
+        }
+
+        let mut db = Self::new(&active_path)?;
+        for path in &paths {
+            db.sealed.push(Self::open_read_only(path)?);
+        }
+        for (key, (_, i, tombstone)) in latest {
+            if i < paths.len() {
+                db.keydir.0.remove(&key);
+                if !tombstone {
+                    db.sealed_keys.insert(key, i);
+                }
+            }
+        }
+        db.segments_dir = Some(dir.to_owned());
+        Ok(db)
+    }
+
+    /// Seals the active segment of a database opened with [`MyDB::open_dir`] and starts a new
+    /// one, numbered after it (e.g. `4.db` after `3.db`).
+    ///
+    /// The active segment is synced and truncated right after its last record, which releases
+    /// space preallocated by [`MyDB::with_preallocated_segment_size`], then it's only read like
+    /// the other sealed segments. Fails with [`Error::IoError`] of kind
+    /// [`io::ErrorKind::Unsupported`] if the database wasn't opened with [`MyDB::open_dir`], or
+    /// of kind [`io::ErrorKind::InvalidInput`] if the name of the active segment isn't a number.
+    pub fn roll_segment(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let dir = match &self.segments_dir {
+            Some(dir) => dir.clone(),
+            None => return Err(Error::IoError(io::ErrorKind::Unsupported.into())),
+        };
+        let active_path = self.path.clone().unwrap();
+        let number = match segment_number(&active_path) {
+            Some(number) => number,
+            None => return Err(Error::IoError(io::ErrorKind::InvalidInput.into())),
+        };
+
+        // Sealed segments are read without a merge operator.
+        self.collapse_operands()?;
+        self.truncate_to_fit()?;
+        self.sync()?;
+
+        // Swap the active segment with the new one, then keep the former as a sealed segment.
+        let mut next = Self::new(dir.join(format!("{}.db", number + 1)))?;
+        std::mem::swap(&mut self.file, &mut next.file);
+        std::mem::swap(&mut self.keydir, &mut next.keydir);
+        std::mem::swap(&mut self.offset, &mut next.offset);
+        std::mem::swap(&mut self.path, &mut next.path);
+        self.allocated = 0;
+        self.rewrites += 1;
+        if let Some(background_sync) = &self.background_sync {
+            background_sync.reopen(vec![self.path.clone().unwrap()]);
+        }
+        drop(next);
+
+        let i = self.sealed.len();
+        self.sealed.push(Self::open_read_only(&active_path)?);
+        self.configure_sealed();
+        for key in self.sealed[i].keydir.0.keys() {
+            self.sealed_keys.insert(key.clone(), i);
+        }
+        Ok(())
+    }
+
+    /// Merges the sealed segments numbered `ids` (see [`MyDB::open_dir`]) into a single segment,
+    /// leaving the last segment alone.
+    ///
+    /// Only the latest record of each key within these segments is kept, tombstones included
+    /// since they might delete records of older segments. This compacts a few segments at a
+    /// time, e.g. in the background, instead of rewriting the whole database. The merged segment
+    /// durably replaces the newest of these segments before the other ones are deleted, so a
+    /// crash in between only leaves records which are older than the merged ones. Fails with
+    /// [`Error::IoError`] of kind [`io::ErrorKind::Unsupported`] if the database wasn't opened
+    /// with [`MyDB::open_dir`], or of kind [`io::ErrorKind::InvalidInput`] if an id isn't the
+    /// number of a sealed segment or if the segments aren't next to each other. Merging
+    /// segments around another one would change which record wins when records of a key have
+    /// the same timestamp.
+    pub fn merge_segments(&mut self, ids: &[u32]) -> Result<()> {
+        self.check_writable()?;
+        if self.segments_dir.is_none() {
+            return Err(Error::IoError(io::ErrorKind::Unsupported.into()));
+        }
+        let mut merged = Vec::with_capacity(ids.len());
+        for &id in ids {
+            match self.sealed.iter().position(|segment| {
+                segment.path.as_deref().and_then(segment_number) == Some(u64::from(id))
+            }) {
+                Some(i) => merged.push(i),
+                None => return Err(Error::IoError(io::ErrorKind::InvalidInput.into())),
+            }
+        }
+        merged.sort_unstable();
+        merged.dedup();
+        if merged.windows(2).any(|pair| pair[1] != pair[0] + 1) {
+            return Err(Error::IoError(io::ErrorKind::InvalidInput.into()));
+        }
+        let target = match merged.last() {
+            Some(&target) => target,
+            None => return Ok(()),
+        };
+
+        // Latest record of each key, picked like when opening the segments.
+        let mut latest: HashMap<String, LogRecord> = HashMap::new();
+        for &i in &merged {
+            let mut records = HashMap::new();
+            for record in LogReader::open(self.sealed[i].path.as_ref().unwrap())? {
+                let record = record?;
+                records.insert(record.key.clone(), record);
+            }
+            for (key, record) in records {
+                match latest.get(&key) {
+                    Some(newest) if newest.timestamp > record.timestamp => {}
+                    _ => {
+                        latest.insert(key, record);
+                    }
+                }
+            }
+        }
+        let mut records: Vec<_> = latest.into_values().collect();
+        records.sort_unstable_by(|a, b| (a.timestamp, &a.key).cmp(&(b.timestamp, &b.key)));
+
+        let path = self.sealed[target].path.clone().unwrap();
+        let temp_path = compaction_path(&path, self.config.temp_dir.as_deref());
+        let mut file = io::BufWriter::new(<fs::File as Storage>::create(&temp_path)?);
+        for record in records {
+            let kv = match record.value {
+                Some(value) => KeyValue::new(record.timestamp, record.key, value)?,
+                None => KeyValue::tombstone(record.timestamp, record.key)?,
+            };
+            let buf = kv.encode();
+            file.write_all(&buf)?;
+            self.io.write(buf.len());
+        }
+        let file = file.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temp_path, &path)?;
+        sync_dir(&path)?;
+        let mut segment = Some(Self::open_read_only(&path)?);
+
+        // Keys of the merged segments are now in the merged one, which takes the place of the
+        // newest of them.
+        let mut index = Vec::with_capacity(self.sealed.len());
+        let mut removed = Vec::new();
+        let mut sealed = Vec::with_capacity(self.sealed.len() - merged.len() + 1);
+        for (i, old) in std::mem::take(&mut self.sealed).into_iter().enumerate() {
+            index.push(sealed.len());
+            if i == target {
+                sealed.push(segment.take().unwrap());
+            } else if merged.contains(&i) {
+                removed.extend(old.path.clone());
+            } else {
+                sealed.push(old);
+            }
+        }
+        for &i in &merged {
+            index[i] = index[target];
+        }
+        for i in self.sealed_keys.values_mut() {
+            *i = index[*i];
+        }
+        self.sealed = sealed;
+        self.configure_sealed();
+
+        for path in &removed {
+            fs::remove_file(path)?;
+        }
+        if !removed.is_empty() {
+            sync_dir(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Creates a read-only instance of [`MyDB`] by using the database file pointed to by `path`.
+    ///
+    /// This is meant for readers of a database which is written by another handle, possibly in
+    /// another process. Records appended by the writer aren't visible until [`MyDB::reload`] is
+    /// called. Every write fails with [`Error::ReadOnly`].
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut file = fs::File::open(&path)?;
+        // The last record might still be being written, so it's skipped but not truncated.
+        let (keydir, stats) = KeyDir::load(&mut file, false, None)?;
+
+        let mut db = Self::from_parts(file, None, keydir, stats.bytes_scanned);
+        db.path = Some(path.as_ref().to_owned());
+        db.load_stats = stats;
+        db.read_only = true;
+        Ok(db)
+    }
+
+    /// Creates a read-only database over `data`, the content of a database file.
+    ///
+    /// This serves reads straight from memory without any file, e.g. for a database image
+    /// embedded in a binary with [`include_bytes!`]. Like when opening a file, an incomplete
+    /// record at the end of `data` is ignored. Split databases aren't supported.
+    pub fn from_bytes(data: &[u8]) -> Result<ReadOnlyDB<'_>> {
+        let (keydir, _) = KeyDir::load(io::Cursor::new(data), false, None)?;
+        Ok(ReadOnlyDB { data, keydir })
+    }
+
+    /// Opens the database file pointed to by `path` with the key directory saved to `snapshot`
+    /// by [`MyDB::save_keydir`], instead of scanning the whole file.
+    ///
+    /// This makes restarting a process holding a large database much faster. The file must be
+    /// the one the snapshot was saved from, possibly with records appended since then, which are
+    /// scanned like when opening the database. Fails with [`Error::Corruption`] if the snapshot
+    /// is invalid or if the file is shorter than when the snapshot was saved, in which case the
+    /// database should be opened with [`MyDB::new`] instead. Split databases aren't supported.
+    pub fn new_with_keydir<P: AsRef<Path>, R: io::Read>(path: P, mut snapshot: R) -> Result<Self> {
+        let mut buf = Vec::new();
+        snapshot.read_to_end(&mut buf)?;
+        let (mut keydir, offset) = KeyDir::restore(&buf)?;
+
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(&path)?;
+        Wal::recover(path.as_ref(), &mut file)?;
+        let len = seek(&mut file, io::SeekFrom::End(0))?;
+        if len < offset as u64 {
+            return Err(Error::Corruption(format!(
+                "database file has {} bytes but the key directory snapshot expects at least {} bytes",
+                len, offset
+            )));
+        }
+
+        let stats = keydir.load_from(&mut file, false, offset, None)?;
+        let offset = offset + stats.bytes_scanned;
+        let recovery = truncate_torn_tail(&mut file, offset)?;
+        let mut db = Self::from_parts(file, None, keydir, offset);
+        db.path = Some(path.as_ref().to_owned());
+        db.load_stats = stats;
+        db.recovery = recovery;
+        Ok(db)
+    }
+
+    /// Opens the database file pointed to by `path` with its hint file written by
+    /// [`MyDB::compact_with_hint`], instead of scanning the whole file.
+    ///
+    /// Records appended since the hint file was written are scanned, like with
+    /// [`MyDB::new_with_keydir`]. Without a hint file, or if it doesn't match the database file,
+    /// the database is opened with [`MyDB::new`].
+    pub fn new_with_hint<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        match fs::File::open(hint_path(path)) {
+            Ok(hint) => match Self::new_with_keydir(path, io::BufReader::new(hint)) {
+                Err(Error::Corruption(_)) => Self::new(path),
+                result => result,
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Self::new(path),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Closes the database (see [`MyDB::close`]) and opens it again from the same path.
+    ///
+    /// The key directory is loaded again from the database file, which makes it an easy way to
+    /// check what was persisted. The database is opened the same way (e.g. in split mode,
+    /// read-only or from the segments of a directory) and keeps the settings made with the
+    /// `with_*` methods, except for background syncing which is stopped, while the read cache
+    /// starts empty. Fails with [`Error::IoError`] of kind [`io::ErrorKind::Unsupported`] if the
+    /// database wasn't opened from a path.
+    pub fn reopen(mut self) -> Result<Self> {
+        let path = match self.path.clone() {
+            Some(path) => path,
+            None => return Err(Error::IoError(io::ErrorKind::Unsupported.into())),
+        };
+        let (split, read_only, wal) = (self.values.is_some(), self.read_only, self.wal.is_some());
+        let mut config = std::mem::take(&mut self.config);
+        if self.background_sync.is_some() {
+            config.sync_policy = SyncPolicy::default();
+        }
+        let cache = self.cache.as_ref().map(|cache| cache.capacity);
+        // The file of a temporary database must survive until it's opened again.
+        let temporary = std::mem::replace(&mut self.temporary, false);
+        let base = self.base.take();
+        let segments_dir = self.segments_dir.take();
+        self.close()?;
+
+        let mut db = if let Some(dir) = segments_dir {
+            Self::open_dir(dir)?
+        } else if read_only {
+            Self::open_read_only(&path)?
+        } else if split {
+            Self::new_split(&path)?
+        } else {
+            let mut options = OpenOptions::new().with_wal(wal);
+            options.key_normalizer = config.key_normalizer;
+            options.open(&path)?
+        };
+        db.config = config;
+        db.configure_sealed();
+        db.cache = cache.map(Cache::new);
+        db.temporary = temporary;
+        db.base = base;
+        Ok(db)
+    }
+}
+
+impl<S: Storage> MyDB<S> {
+    /// Creates an instance of [`MyDB`] by using the given `storage`.
+    ///
+    /// The database is loaded from whatever the storage already contains. If the last record is
+    /// incomplete, which happens when a crash occurs in the middle of a write, it's discarded and
+    /// the storage is truncated right before it.
+    pub fn new_from_storage(storage: S) -> Result<Self> {
+        Self::load_storage(storage, None)
+    }
+
+    /// Same as [`MyDB::new_from_storage`] with keys normalized by `normalizer` if set, see
+    /// [`OpenOptions::with_key_normalizer`].
+    fn load_storage(mut storage: S, normalizer: Option<KeyNormalizer>) -> Result<Self> {
+        let (keydir, stats) = KeyDir::load(&mut storage, false, normalizer)?;
+        let recovery = truncate_torn_tail(&mut storage, stats.bytes_scanned)?;
+        let mut db = Self::from_parts(storage, None, keydir, stats.bytes_scanned);
+        db.load_stats = stats;
+        db.recovery = recovery;
+        db.config.key_normalizer = normalizer;
+        Ok(db)
+    }
+
+    fn from_parts(file: S, values: Option<S>, keydir: KeyDir, offset: usize) -> Self {
+        let last_timestamp = keydir.max_timestamp();
+        MyDB {
+            file,
+            values,
+            keydir,
+            offset,
+            config: Config::default(),
+            cache: None,
+            path: None,
+            temporary: false,
+            background_sync: None,
+            base: None,
+            read_only: false,
+            load_stats: LoadStats::default(),
+            recovery: RecoveryReport::default(),
+            last_timestamp,
+            unsynced_bytes: 0,
+            wal: None,
+            sealed: Vec::new(),
+            sealed_keys: HashMap::new(),
+            segments_dir: None,
+            allocated: 0,
+            io: IoCounters::default(),
+            rewrites: 0,
+            bloom: None,
+            sealed_lookups: Cell::new(0),
+        }
+    }
+
+    /// Loads the records written to the storage by other handles since the database was opened
+    /// or last reloaded.
+    ///
+    /// This is how read-only handles (see [`MyDB::open_read_only`]) observe the writes of the
+    /// handle writing the database. An incomplete record at the end of the storage, which might
+    /// be in the middle of being written, is skipped until the next reload. Returns whether new
+    /// records were found.
+    pub fn reload(&mut self) -> Result<bool> {
+        let changed = if let Some(values) = &mut self.values {
+            // Keys and values files grow independently so the keys file is loaded again.
+            let (keydir, _) = KeyDir::load(&mut self.file, true, self.config.key_normalizer)?;
+            let offset: usize = seek(values, io::SeekFrom::End(0))?.try_into().unwrap();
+            let changed = offset != self.offset || !keydir.same_records(&self.keydir);
+            if changed {
+                self.keydir = keydir;
+                self.offset = offset;
+            }
+            changed
+        } else {
+            let stats = self.keydir.load_from(
+                &mut self.file,
+                false,
+                self.offset,
+                self.config.key_normalizer,
+            )?;
+            let offset = self.offset + stats.bytes_scanned;
+            let changed = offset != self.offset;
+            self.offset = offset;
+            changed
+        };
+
+        if changed {
+            if let Some(cache) = &mut self.cache {
+                *cache = Cache::new(cache.capacity);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Returns the timestamp of records written now, according to the clock of the database.
+    ///
+    /// Fails with [`Error::ClockError`] if the clock can't be represented as a timestamp.
+    fn timestamp(&mut self) -> Result<u32> {
+        let mut timestamp = match &self.config.clock {
+            Some(clock) => to_timestamp(clock.now())?,
+            None => now_timestamp()?,
+        };
+        timestamp = std::cmp::max(timestamp, MIN_TIMESTAMP);
+        if self.config.monotonic_timestamps && timestamp <= self.last_timestamp {
+            timestamp = self.last_timestamp.saturating_add(1);
+        }
+        self.last_timestamp = std::cmp::max(self.last_timestamp, timestamp);
+        Ok(timestamp)
+    }
+
+    /// Accounts for `len` bytes that were just written, syncing the database if too many bytes
+    /// aren't synced yet (see [`MyDB::with_max_unsynced_bytes`]).
+    fn track_unsynced(&mut self, len: usize) -> Result<()> {
+        if self.config.sync_policy != SyncPolicy::Never {
+            return Ok(());
+        }
+        self.unsynced_bytes += len;
+        match self.config.max_unsynced_bytes {
+            Some(max) if self.unsynced_bytes >= max => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with [`Error::ReadOnly`] if the database is read-only.
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Enables an in-memory cache of up to `capacity` values.
+    ///
+    /// Values are cached when read by [`MyDB::get`] and invalidated when their key is written.
+    /// When the cache is full, the least recently used value is evicted. This trades memory for
+    /// read latency on frequently read keys.
+    pub fn with_read_cache(mut self, capacity: usize) -> Self {
+        self.cache = Some(Cache::new(capacity));
+        self
+    }
+
+    /// Prevents values larger than `size` bytes from being cached by the read cache (see
+    /// [`MyDB::with_read_cache`]).
+    ///
+    /// Since the cache holds a number of values, a few large values could take a lot of memory.
+    /// Large values are still read, they're just not retained. Every value can be cached by
+    /// default.
+    pub fn with_cache_max_value_size(mut self, size: usize) -> Self {
+        self.config.cache_max_value_size = Some(size);
+        self
+    }
+
+    /// Encrypts values with AES-256-GCM and the given `key` before writing them, and decrypts
+    /// them when reading them.
+    ///
+    /// Each value is encrypted with a random nonce, which is stored in the record along with the
+    /// encrypted value, both hex-encoded so that records keep the same format. Encrypted values
+    /// thus take a bit more than twice their size. Keys aren't encrypted since they're needed to
+    /// look values up. Reading a value which wasn't encrypted with `key` fails with
+    /// [`Error::DecryptionFailed`], so the key must be set again every time the database is
+    /// opened. [`MyDB::get_value_range`] still returns bytes as stored, i.e. encrypted. Only
+    /// available with the `encryption` feature.
+    #[cfg(feature = "encryption")]
+    pub fn with_cipher(mut self, key: &[u8; 32]) -> Self {
+        self.config.cipher = Some(Aes256Gcm::new(key.into()));
+        if let Some(cache) = &mut self.cache {
+            *cache = Cache::new(cache.capacity);
+        }
+        self.configure_sealed();
+        self
+    }
+
+    /// Creates the record setting `key` to `value`, whose key is normalized if a normalizer is
+    /// set (see [`OpenOptions::with_key_normalizer`]) and whose value is encrypted if a cipher is
+    /// set (see `MyDB::with_cipher`).
+    ///
+    /// Fails with [`Error::InvalidKey`] if the key is rejected by [`MyDB::with_strict_keys`].
+    fn new_record(&self, timestamp: u32, key: String, value: String) -> Result<KeyValue> {
+        if self.config.strict_keys && key.chars().any(char::is_control) {
+            return Err(Error::InvalidKey);
+        }
+        let key = match self.normalize_key(&key) {
+            Cow::Borrowed(_) => key,
+            Cow::Owned(normalized) => normalized,
+        };
+        KeyValue::new(timestamp, key, self.seal_value(value)?)
+    }
+
+    /// Normalizes `key` if a normalizer is set, see [`OpenOptions::with_key_normalizer`].
+    fn normalize_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match self.config.key_normalizer {
+            Some(normalizer) => normalizer.normalize(key),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    /// Makes the sealed segments (see [`MyDB::open_dir`]) read values with the same settings as
+    /// the database, e.g. its decode mode and cipher, and builds their missing bloom filters.
+    fn configure_sealed(&mut self) {
+        let bits_per_key = self.config.bloom_bits_per_key;
+        for segment in &mut self.sealed {
+            segment.config.copy_read_settings(&self.config);
+            if segment.bloom.as_ref().map(|bloom| bloom.bits_per_key) != bits_per_key {
+                segment.bloom =
+                    bits_per_key.map(|bits| BloomFilter::new(segment.keydir.0.keys(), bits));
+            }
+        }
+    }
+
+    /// Encrypts `value` as stored in a record if a cipher is set (see `MyDB::with_cipher`).
+    fn seal_value(&self, value: String) -> Result<String> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.config.cipher {
+            return encrypt_value(cipher, &value);
+        }
+        Ok(value)
+    }
+
+    /// Decrypts `value` as stored in a record if a cipher is set (see `MyDB::with_cipher`).
+    fn open_value(&self, value: String) -> Result<String> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.config.cipher {
+            return decrypt_value(cipher, &value);
+        }
+        Ok(value)
+    }
+
+    /// Returns whether values are encrypted (see `MyDB::with_cipher`), in which case value
+    /// hashes are hashes of the encrypted values.
+    fn has_cipher(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        if self.config.cipher.is_some() {
+            return true;
+        }
+        false
+    }
+
+    /// Makes sure that everything written so far is durably stored, whatever the sync policy.
+    ///
+    /// When the database was opened from a path, its directory is synced too so that the entries
+    /// of the files created or renamed by the database, e.g. by compaction, are durable as well.
+    pub fn sync(&mut self) -> Result<()> {
+        if let Some(values) = &mut self.values {
+            values.flush()?;
+            values.sync()?;
+        }
+        self.file.flush()?;
+        self.file.sync()?;
+        if let Some(wal) = &mut self.wal {
+            wal.file.sync_all()?;
+        }
+        if let Some(path) = &self.path {
+            sync_dir(path)?;
+        }
+        self.unsynced_bytes = 0;
+        Ok(())
+    }
+
+    /// Durably stores every write and closes the database.
+    ///
+    /// Dropping the database doesn't sync the writes which aren't synced yet (see
+    /// [`SyncPolicy::Never`]) and can't report errors, so closing the database explicitly is the
+    /// way to make sure nothing is lost. With the `log` feature, a warning is logged when a
+    /// database is dropped with unsynced writes.
+    #[must_use = "errors syncing the last writes are only reported by the returned result"]
+    pub fn close(mut self) -> Result<()> {
+        if let Some(background_sync) = self.background_sync.take() {
+            background_sync.stop();
+        }
+        if !self.read_only {
+            self.sync()?;
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the database file and empties the write-ahead log, see [`OpenOptions::with_wal`].
+    ///
+    /// This does nothing without a write-ahead log.
+    pub fn checkpoint(&mut self) -> Result<()> {
+        if self.wal.is_none() {
+            return Ok(());
+        }
+        self.file.flush()?;
+        self.file.sync()?;
+        if let Some(wal) = &mut self.wal {
+            wal.clear()?;
+        }
+        Ok(())
+    }
+
+    /// Sets how writes are made durable, defaults to [`SyncPolicy::All`].
+    pub fn with_sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.config.sync_policy = policy;
+        self
+    }
+
+    /// Sets how values that aren't valid UTF-8 are decoded, defaults to [`DecodeMode::Strict`].
+    ///
+    /// Values written by [`MyDB::set`] are always valid but files might get corrupted or written
+    /// by other tools. [`DecodeMode::Lossy`] allows to recover the valid parts of such values.
+    pub fn with_decode_mode(mut self, mode: DecodeMode) -> Self {
+        self.config.decode_mode = mode;
+        self.configure_sealed();
+        self
+    }
+
+    /// Makes compaction write the compacted database to a file within `dir`, instead of next to
+    /// the database file.
+    ///
+    /// This is useful when there isn't enough space left for a compacted copy of the database
+    /// next to it. Since the compacted file then replaces the database file by renaming it, which
+    /// is atomic only within a filesystem, `dir` must be on the same filesystem as the database.
+    /// Fails with [`Error::IoError`] if it isn't (of kind [`io::ErrorKind::InvalidInput`]) or if
+    /// `dir` doesn't exist. This only applies to databases opened from a path (see
+    /// [`MyDB::compact`]).
+    pub fn with_temp_dir<P: AsRef<Path>>(mut self, dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        if let Some(path) = &self.path {
+            if !same_filesystem(dir, path)? {
+                return Err(Error::IoError(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "temporary directory {:?} is on another filesystem than the database",
+                        dir
+                    ),
+                )));
+            }
+        } else if !dir.is_dir() {
+            return Err(Error::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("temporary directory {:?} doesn't exist", dir),
+            )));
+        }
+
+        self.config.temp_dir = Some(dir.to_owned());
+        Ok(self)
+    }
+
+    /// Syncs the database as soon as `max` bytes were written since the last sync.
+    ///
+    /// This only matters when writes aren't synced when they happen, i.e. with
+    /// [`SyncPolicy::Never`] (which is also used by [`MyDB::with_background_sync`]). This bounds
+    /// the amount of data which might be lost on crash by a number of bytes, in addition to any
+    /// delay. The write which reaches `max` bytes syncs the database before returning.
+    pub fn with_max_unsynced_bytes(mut self, max: usize) -> Self {
+        self.config.max_unsynced_bytes = Some(max);
+        self
+    }
+
+    /// Limits the size of the database to `max` bytes.
+    ///
+    /// Writes which would make the database larger fail with [`Error::FileSizeLimitExceeded`]
+    /// and leave it unchanged, which protects disks with little space. Unlike a cache, nothing is
+    /// evicted to make room: space is only reclaimed by deleting keys and compacting the
+    /// database. The size is the one returned by [`MyDB::disk_size`], so only values count in split
+    /// mode.
+    pub fn with_max_file_size(mut self, max: u64) -> Self {
+        self.config.max_file_size = Some(max);
+        self
+    }
+
+    /// Preallocates the active segment (see [`MyDB::open_dir`]) to `size` bytes.
+    ///
+    /// The first write to a segment extends its file with zeros up to `size` bytes, like
+    /// [`MyDB::reserve`], so that the filesystem allocates the whole segment up front instead of
+    /// in the middle of later writes, which also reduces fragmentation. Records then overwrite
+    /// the preallocated space: [`MyDB::disk_size`] keeps returning the size of the records while
+    /// the file is larger. The file is truncated right after its last record when the segment is
+    /// rolled with [`MyDB::roll_segment`], or when it's opened again. Writes go past `size` once
+    /// the segment is full. Split mode databases don't support preallocation, so this does
+    /// nothing for them.
+    pub fn with_preallocated_segment_size(mut self, size: usize) -> Self {
+        self.config.preallocated_segment_size = Some(size);
+        self
+    }
+
+    /// Keeps a bloom filter of the keys of each sealed segment (see [`MyDB::open_dir`]), with
+    /// `bits_per_key` bits per key.
+    ///
+    /// Filters are built for the current sealed segments, then for each segment sealed by
+    /// [`MyDB::roll_segment`] or written by [`MyDB::merge_segments`]. Lookups of keys which
+    /// aren't in the last segment check the filters first: when every filter excludes the key,
+    /// the keys of the sealed segments aren't looked up at all (see
+    /// [`MyDB::segment_lookups`]), which makes negative lookups cheaper when there are many of
+    /// them. Filters are only kept in memory. About 10 bits per key give 1% of false positives.
+    pub fn with_bloom_filters(mut self, bits_per_key: usize) -> Self {
+        self.config.bloom_bits_per_key = Some(bits_per_key);
+        self.configure_sealed();
+        self
+    }
+
+    /// Enables or disables monotonic timestamps.
+    ///
+    /// When enabled, every write gets a timestamp strictly greater than the previous one, which
+    /// is bumped by one second if the clock went backwards (or didn't move). This keeps
+    /// timestamps meaningful for ordering writes, even when the system clock is adjusted. On
+    /// open, the previous timestamp is the latest timestamp of the keys of the database. It's
+    /// disabled by default.
+    pub fn with_monotonic_timestamps(mut self, enabled: bool) -> Self {
+        self.config.monotonic_timestamps = enabled;
+        self
+    }
+
+    /// Uses `clock` instead of the system clock to timestamp the records written from now on.
+    ///
+    /// Writes fail with [`Error::ClockError`] while the clock is before the Unix epoch. Records
+    /// written during the first second of the epoch get a timestamp of 1, see
+    /// [`format::encode_record`].
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.config.clock = Some(Box::new(clock));
+        self
+    }
+
+    /// Uses `op` to combine the merge operands written by [`MyDB::merge`] with the values they
+    /// apply to.
+    ///
+    /// Merge operands are only kept as records, so the operator must be set again every time the
+    /// database is opened, before reading keys which were merged.
+    pub fn with_merge_operator<M: MergeOp + 'static>(mut self, op: M) -> Self {
+        self.config.merge_op = Some(Box::new(op));
+        self
+    }
+
+    /// Enables or disables syncing the database when it's dropped.
+    ///
+    /// Dropping a database syncs it one last time with [`MyDB::with_background_sync`], and
+    /// syncs the database file to empty the write-ahead log (see [`OpenOptions::with_wal`]).
+    /// When disabled, dropping only flushes the storage, which makes dropping many short-lived
+    /// databases (e.g. in tests) much faster. Writes which weren't synced yet might then be lost
+    /// on crash, but logged writes are still applied again when the database is opened. It's
+    /// enabled by default. [`MyDB::close`] always syncs the database.
+    pub fn with_sync_on_drop(mut self, enabled: bool) -> Self {
+        self.config.skip_drop_sync = !enabled;
+        self
+    }
+
+    /// Enables or disables the verification of keys when reading values.
+    ///
+    /// When enabled, [`MyDB::get`] reads the key of the record along with its value and fails with
+    /// [`Error::Corruption`] if it isn't the requested key, instead of returning the value of
+    /// another key. This catches a key directory which doesn't match the database file, e.g.
+    /// because of a bug computing offsets, at the cost of reading keys. It's disabled by
+    /// default, and has no effect in split mode where values are stored without their keys.
+    pub fn with_key_verification(mut self, enabled: bool) -> Self {
+        self.config.verify_keys = enabled;
+        self.configure_sealed();
+        self
+    }
+
+    /// Enables or disables in-place updates.
+    ///
+    /// When enabled, [`MyDB::set`] overwrites the existing record of a key instead of appending a
+    /// new one if the new record has exactly the same size, i.e. the new value has the same length
+    /// as the current one. This saves space for workloads overwriting fixed-size values but
+    /// gives up on the crash safety of appending: a crash in the middle of an overwrite might
+    /// leave a record mixing old and new bytes, which can't be detected. It's thus disabled by
+    /// default. It has no effect in split mode, and the storage must not be opened in append mode
+    /// (like files opened with [`fs::OpenOptions::append`]) since writes wouldn't happen in place.
+    /// Overwriting a record removes the hint file of the database, see
+    /// [`MyDB::compact_with_hint`].
+    pub fn with_in_place_updates(mut self, enabled: bool) -> Self {
+        self.config.in_place_updates = enabled;
+        self
+    }
+
+    /// Enables or disables strict keys.
+    ///
+    /// When enabled, writing a key which contains control characters, such as NUL or line
+    /// breaks, fails with [`Error::InvalidKey`]. This is useful when keys end up in systems
+    /// which can't handle them. It's disabled by default since the file format supports any
+    /// UTF-8 key.
+    pub fn with_strict_keys(mut self, enabled: bool) -> Self {
+        self.config.strict_keys = enabled;
+        self
+    }
+
+    /// Enables or disables skipping redundant writes.
+    ///
+    /// When enabled, [`MyDB::set`] first reads the current value of the key and doesn't write
+    /// anything if it's already equal to the new value. It's disabled by default because it
+    /// costs a read for every write.
+    pub fn with_skip_redundant_writes(mut self, enabled: bool) -> Self {
+        self.config.skip_redundant_writes = enabled;
+        self
+    }
+
+    /// Gets the value associated with the given `key`.
+    ///
+    /// This operation might fail (mainly because of an I/O error) hence we return a [`Result`].
+    /// Besides, the given `key` might not exist within the database so the wrapped value is an
+    /// [`Option`].
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        Ok(self.get_traced(key)?.map(|(value, _)| value))
+    }
+
+    /// Gets the value associated with the given `key`, along with where it was read from.
+    ///
+    /// This helps checking the effectiveness of the read cache. Without a read cache, values are
+    /// always read from [`ReadSource::Disk`].
+    pub fn get_traced(&mut self, key: &str) -> Result<Option<(String, ReadSource)>> {
+        let key = &*self.normalize_key(key);
+        if let Some(value) = self.cache.as_mut().and_then(|cache| cache.get(key)) {
+            return Ok(Some((value, ReadSource::Cache)));
+        }
+
+        let mut value = self.read_value(key)?;
+        if let (None, Some(i)) = (&value, self.sealed_index(key)) {
+            value = self.sealed[i].get(key)?;
+        }
+        if let (None, Some(base)) = (&value, &mut self.base) {
+            value = base.get(key)?;
+        }
+        if let (Some(cache), Some(value)) = (&mut self.cache, &value) {
+            if self
+                .config
+                .cache_max_value_size
+                .is_none_or(|size| value.len() <= size)
+            {
+                cache.insert(key.to_owned(), value.clone());
+            }
+        }
+        Ok(value.map(|value| (value, ReadSource::Disk)))
+    }
+
+    /// Hints that the values of the given `keys` are about to be read.
+    ///
+    /// On Linux, this asks the kernel to load them in the page cache ahead of time, which speeds up
+    /// a burst of [`MyDB::get`] calls, e.g. during a scan. This is best-effort: it does nothing
+    /// when the storage doesn't support it (see [`Storage::prefetch`]) and unknown keys are
+    /// ignored.
+    pub fn prefetch(&self, keys: &[&str]) {
+        let storage = self.values.as_ref().unwrap_or(&self.file);
+        for key in keys {
+            let key = &*self.normalize_key(key);
+            match self.keydir.0.get(key) {
+                Some(entry) => {
+                    let (offset, size) = self.value_span(entry);
+                    storage.prefetch(offset as u64, size as u64);
+                }
+                None => {
+                    if let Some(base) = &self.base {
+                        base.prefetch(&[key]);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads the value associated with the given `key` from the storage.
+    ///
+    /// Errors mention the key and the offset of its record, to point at the faulty record. Fails
+    /// with [`Error::StaleEntry`] if the record is past the end of the storage, and with
+    /// [`Error::Corruption`] if keys are verified and the record is for another key.
+    fn read_value(&mut self, key: &str) -> Result<Option<String>> {
+        let location = match self.keydir.0.get(key) {
+            Some(entry) => self.locate(entry),
+            None => return Ok(None),
+        };
+        let record_offset = location.0;
+        let value = self.read_span(key, location)?;
+        if let Some(entry) = self.keydir.0.get_mut(key) {
+            entry.value_hash.get_or_insert_with(|| hash_value(&value));
+        }
+        let value = self.decode_value(key, record_offset, value)?;
+        Ok(Some(self.merge_operands(key, value)?))
+    }
+
+    /// Returns where the record of `entry` is, as the offset of the record, the size of the key
+    /// to read before the value and the span of the value (see [`MyDB::value_span`]).
+    ///
+    /// The key is only read to verify it (see [`MyDB::with_key_verification`]), which isn't
+    /// possible in split mode since values are stored without their keys.
+    fn locate(&self, entry: &KeyDirEntry) -> (usize, usize, (usize, usize)) {
+        let key_size = match self.values {
+            None if self.config.verify_keys => entry.key_size as usize,
+            _ => 0,
+        };
+        (entry.offset, key_size, self.value_span(entry))
+    }
+
+    /// Reads the value of the record of `key` located by [`MyDB::locate`], see
+    /// [`MyDB::read_value`].
+    ///
+    /// When the key stored right before the value is read along with it, it must be `key`:
+    /// otherwise the key directory doesn't match the storage.
+    fn read_span(
+        &mut self,
+        key: &str,
+        (record_offset, key_size, (offset, size)): (usize, usize, (usize, usize)),
+    ) -> Result<Vec<u8>> {
+        let context = |err: &dyn std::fmt::Display| read_context(key, record_offset, err);
+
+        let start = offset - key_size;
+        let storage = self.values.as_mut().unwrap_or(&mut self.file);
+        seek(storage, io::SeekFrom::Start(start as u64))?;
+
+        let mut value = vec![0; key_size + size];
+        if let Err(err) = storage.read_exact(&mut value) {
+            // The file was truncated by someone else since the record was loaded.
+            if err.kind() == io::ErrorKind::UnexpectedEof {
+                let len = seek(storage, io::SeekFrom::End(0))?;
+                if len < (offset + size) as u64 {
+                    return Err(Error::StaleEntry(context(&format!(
+                        "the file is only {} bytes long, it was probably truncated since it was \
+                         loaded (see `MyDB::reload`)",
+                        len
+                    ))));
+                }
+            }
+            return Err(io::Error::new(err.kind(), context(&err)).into());
+        }
+        self.io.read(key_size + size);
+
+        let stored_key = &value[..key_size];
+        if key_size > 0 && !self.is_stored_key(key, stored_key) {
+            return Err(Error::Corruption(context(&format!(
+                "the record is for key {:?}, the key directory doesn't match the file",
+                String::from_utf8_lossy(stored_key)
+            ))));
+        }
+        value.drain(..key_size);
+        Ok(value)
+    }
+
+    /// Returns whether `stored`, the key of a record, is `key` once normalized (see
+    /// [`OpenOptions::with_key_normalizer`]).
+    fn is_stored_key(&self, key: &str, stored: &[u8]) -> bool {
+        if stored == key.as_bytes() {
+            return true;
+        }
+        match (self.config.key_normalizer, std::str::from_utf8(stored)) {
+            (Some(normalizer), Ok(stored)) => normalizer.normalize(stored) == key,
+            _ => false,
+        }
+    }
+
+    /// Decodes `value` as read from the record of `key` at `record_offset`, according to the
+    /// decode mode, and decrypts it.
+    fn decode_value(&self, key: &str, record_offset: usize, value: Vec<u8>) -> Result<String> {
+        let value = match self.config.decode_mode {
+            DecodeMode::Strict => String::from_utf8(value).map_err(|err| {
+                Error::DecodeError(read_context(key, record_offset, &err.utf8_error()))
+            })?,
+            DecodeMode::Lossy => String::from_utf8_lossy(&value).into_owned(),
+        };
+        self.open_value(value)
+    }
+
+    /// Merges the operands of `key` (see [`MyDB::merge`]) into `value`, its latest full value.
+    fn merge_operands(&mut self, key: &str, mut value: String) -> Result<String> {
+        let locations: Vec<_> = match self.keydir.1.get(key) {
+            Some(operands) => operands.iter().map(|entry| self.locate(entry)).collect(),
+            None => return Ok(value),
+        };
+        for location in locations {
+            let operand = self.read_span(key, location)?;
+            let operand = self.decode_value(key, location.0, operand)?;
+            value = self.merge_value(key, Some(&value), &operand)?;
+        }
+        Ok(value)
+    }
+
+    /// Merges `operand` into `existing` with the merge operator, see [`MyDB::merge`].
+    fn merge_value(&self, key: &str, existing: Option<&str>, operand: &str) -> Result<String> {
+        match &self.config.merge_op {
+            Some(op) => Ok(op.merge(key, existing, operand)),
+            None => Err(Error::IoError(io::ErrorKind::Unsupported.into())),
+        }
+    }
+
+    /// Rewrites every merged key with its merged value, so that it doesn't have operands anymore.
+    fn collapse_operands(&mut self) -> Result<()> {
+        if self.keydir.1.is_empty() {
+            return Ok(());
+        }
+        let keys: Vec<_> = self.keydir.1.keys().cloned().collect();
+        let mut merged = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.read_value(&key)? {
+                merged.push((key, value));
+            }
+        }
+        self.extend(merged)
+    }
+
+    /// Gets `len` bytes of the value associated with the given `key`, starting at byte `start`.
+    ///
+    /// Only the requested bytes are read from the storage, which makes it much cheaper than
+    /// [`MyDB::get`] when only a small part of a large value is needed. Since the range might not
+    /// fall on UTF-8 character boundaries, raw bytes are returned. Fails with
+    /// [`Error::InvalidRange`] if the range goes past the end of the value.
+    ///
+    /// Values with merge operands (see [`MyDB::merge`]) are read and merged in full, and the
+    /// range is taken from the merged value.
+    pub fn get_value_range(
+        &mut self,
+        key: &str,
+        start: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>> {
+        let key = &*self.normalize_key(key);
+        if self.keydir.1.contains_key(key) {
+            let value = match self.read_value(key)? {
+                Some(value) => self.seal_value(value)?,
+                None => return Ok(None),
+            };
+            return match start.checked_add(len) {
+                Some(end) if end <= value.len() => Ok(Some(value.as_bytes()[start..end].to_vec())),
+                _ => Err(Error::InvalidRange),
+            };
+        }
+
+        let (offset, size) = match self.keydir.0.get(key) {
+            Some(entry) => self.value_span(entry),
+            None => return Ok(None),
+        };
+
+        match start.checked_add(len) {
+            Some(end) if end <= size => (),
+            _ => return Err(Error::InvalidRange),
+        }
+
+        let storage = self.values.as_mut().unwrap_or(&mut self.file);
+        seek(storage, io::SeekFrom::Start((offset + start) as u64))?;
+
+        let mut buf = vec![0; len];
+        storage.read_exact(&mut buf)?;
+        self.io.read(len);
+
+        Ok(Some(buf))
+    }
+
+    /// Returns the values of `key` that are still in the database, from the oldest to the latest.
+    ///
+    /// Every write appends a new record so previous values stay around until compaction (see
+    /// [`MyDB::compact_keep_versions`] to keep some of them). Only values written since the last
+    /// deletion of `key` are returned. Each merge operand (see [`MyDB::merge`]) gives the value
+    /// merged into the previous one. This reads the whole database.
+    pub fn history(&mut self, key: &str) -> Result<Vec<String>> {
+        let key = &*self.normalize_key(key);
+        let mut values: Vec<String> = Vec::new();
+        for kv in self.read_log()? {
+            if self.normalize_key(&kv.key) != key {
+                continue;
+            }
+            match kv.value {
+                Some(value) if kv.flags & MERGE_FLAG != 0 && !values.is_empty() => {
+                    let operand = self.open_value(value)?;
+                    let merged =
+                        self.merge_value(key, values.last().map(String::as_str), &operand)?;
+                    values.push(merged);
+                }
+                Some(value) => values.push(self.open_value(value)?),
+                None => values.clear(),
+            }
+        }
+        Ok(values)
+    }
+
+    /// Folds every record of the database into a state, in the order in which they were written.
+    ///
+    /// Starting from `init`, `f` is called with the state and the key and value of each record,
+    /// `None` being the value of tombstones. Unlike reads, this includes records which were
+    /// overwritten since, as long as they weren't reclaimed by compaction, which helps rebuilding
+    /// any state from the history of the writes, e.g. with an event sourcing design. Values are
+    /// passed as stored, i.e. encrypted with `MyDB::with_cipher`. This reads the whole database.
+    pub fn replay<T, F>(&mut self, init: T, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut T, &str, Option<&[u8]>),
+    {
+        let mut state = init;
+        for kv in self.read_log()? {
+            f(&mut state, &kv.key, kv.value.as_deref().map(str::as_bytes));
+        }
+        Ok(state)
+    }
+
+    /// Returns the key, value and timestamp of the `n` latest records written to the database,
+    /// from the oldest to the latest.
+    ///
+    /// This is meant to debug recent activity: records which were overwritten since are returned
+    /// too, as long as they weren't reclaimed by compaction. Tombstones are skipped since they
+    /// don't have any value. This reads the whole database.
+    pub fn tail(&mut self, n: usize) -> Result<Vec<(String, String, u32)>> {
+        let mut records = self
+            .read_log()?
+            .into_iter()
+            .rev()
+            .filter_map(|kv| Some((kv.key, kv.value?, kv.timestamp)))
+            .take(n)
+            .map(|(key, value, timestamp)| Ok((key, self.open_value(value)?, timestamp)))
+            .collect::<Result<Vec<_>>>()?;
+        records.reverse();
+        Ok(records)
+    }
+
+    /// Gets the values associated with `keys`, ordered by key.
+    ///
+    /// Keys which aren't set are omitted from the returned map.
+    pub fn get_sorted(&mut self, keys: &[&str]) -> Result<BTreeMap<String, String>> {
+        let mut values = BTreeMap::new();
+        for &key in keys {
+            if let Some(value) = self.get(key)? {
+                values.insert(key.to_owned(), value);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Gets the value associated with `key`, or `default` if it isn't set.
+    ///
+    /// Nothing is written to the database when `key` isn't set.
+    pub fn get_or(&mut self, key: &str, default: &str) -> Result<String> {
+        Ok(self.get(key)?.unwrap_or_else(|| default.to_owned()))
+    }
+
+    /// Gets the value associated with `key`, failing with [`Error::KeyNotFound`] if it isn't
+    /// set.
+    ///
+    /// This is the same as [`MyDB::get`] for callers which expect the key to be set.
+    pub fn get_required(&mut self, key: &str) -> Result<String> {
+        self.get(key)?
+            .ok_or_else(|| Error::KeyNotFound(key.to_owned()))
+    }
+
+    /// Sets the value associated with `key` to `value`.
+    ///
+    /// This operation might fail (mainly because of an I/O error) hence we return a [`Result`].
+    /// If the key already exists in the database it is overwritten with the new `value`. Otherwise,
+    /// the value is simply inserted.
+    ///
+    /// Returns whether a write occurred, which is always the case unless redundant writes are
+    /// skipped (see [`MyDB::with_skip_redundant_writes`]) and `key` is already set to `value`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool> {
+        self.set_inner(key, value, self.config.skip_redundant_writes)
+    }
+
+    /// Sets the value associated with `key` to `value` unless it's already the current value.
+    ///
+    /// This behaves like [`MyDB::set`] with redundant writes skipped (see
+    /// [`MyDB::with_skip_redundant_writes`]) whatever the configuration, which makes retrying a
+    /// write safe without growing the database. The current value isn't read when its hash is
+    /// known (see [`MyDB::value_hash`]). Returns whether a write occurred.
+    pub fn set_idempotent(&mut self, key: &str, value: &str) -> Result<bool> {
+        self.set_inner(key, value, true)
+    }
+
+    /// Merges `operand` into the value of `key` with the merge operator of the database (see
+    /// [`MyDB::with_merge_operator`]), e.g. to increment a counter or append to a list.
+    ///
+    /// Instead of reading the current value, merging it and writing the result, this only appends
+    /// the operand, so merging is as cheap as setting a value. Operands are merged when the value
+    /// is read, in the order in which they were written, starting from the latest value set by
+    /// [`MyDB::set`]. Compaction collapses the operands into a value. Until then, reads see
+    /// merged values, except for [`MyDB::metadata`] and [`MyDB::value_len`] which describe the
+    /// value before merging, and [`ReadOnlyDB::get`] which fails. Fails with
+    /// [`Error::IoError`] of kind [`io::ErrorKind::Unsupported`] if the database doesn't have a
+    /// merge operator.
+    pub fn merge(&mut self, key: &str, operand: &str) -> Result<()> {
+        if self.config.merge_op.is_none() {
+            return Err(Error::IoError(io::ErrorKind::Unsupported.into()));
+        }
+        let key = &*self.normalize_key(key);
+        if !self.keydir.0.contains_key(key) {
+            // Operands are only kept on top of a value of this segment, so the merged value is
+            // written instead (it doesn't need any read if the key doesn't exist).
+            let existing = if self.contains_key(key) {
+                self.get(key)?
+            } else {
+                None
+            };
+            let value = self.merge_value(key, existing.as_deref(), operand)?;
+            self.set_inner(key, &value, false)?;
+            return Ok(());
+        }
+
+        let timestamp = self.timestamp()?;
+        let mut kv = self.new_record(timestamp, key.to_owned(), operand.to_owned())?;
+        kv.flags = MERGE_FLAG;
+        let entry = self.append(&kv)?;
+        self.keydir.1.entry(kv.key).or_default().push(entry);
+        Ok(())
+    }
+
+    fn set_inner(&mut self, key: &str, value: &str, skip_redundant: bool) -> Result<bool> {
+        let key = &*self.normalize_key(key);
+        if skip_redundant && self.is_current_value(key, value)? {
+            return Ok(false);
+        }
+
+        let timestamp = self.timestamp()?;
+        let kv = self.new_record(timestamp, key.to_owned(), value.to_owned())?;
+        if self.config.in_place_updates && self.overwrite(&kv)? {
+            return Ok(true);
+        }
+
+        let entry = self.append(&kv)?;
+        self.keydir.0.insert(kv.key, entry);
+
+        Ok(true)
+    }
+
+    /// Returns whether `value` is the current value of `key`.
+    ///
+    /// When the hash of the current value is known (see [`MyDB::value_hash`]), hashes are
+    /// compared instead of reading the current value.
+    fn is_current_value(&mut self, key: &str, value: &str) -> Result<bool> {
+        match self.value_hash(key) {
+            Some(hash) if !self.has_cipher() => Ok(hash == hash_value(value.as_bytes())),
+            _ => Ok(self.get(key)?.as_deref() == Some(value)),
+        }
+    }
+
+    /// Durably overwrites the current record of the key of `kv` with `kv` if they have the same
+    /// size, see [`MyDB::with_in_place_updates`].
+    ///
+    /// Returns whether the record was overwritten.
+    fn overwrite(&mut self, kv: &KeyValue) -> Result<bool> {
+        self.check_writable()?;
+        let (offset, size) = match self.keydir.0.get(&kv.key) {
+            Some(entry) if self.values.is_none() && !self.keydir.1.contains_key(&kv.key) => {
+                (entry.offset, entry.size as usize)
+            }
+            _ => return Ok(false),
+        };
+
+        let record = kv.encode();
+        if record.len() != size {
+            return Ok(false);
+        }
+
+        if let Some(cache) = &mut self.cache {
+            cache.remove(&kv.key);
+        }
+
+        // The hint file would otherwise keep the timestamp of the overwritten record.
+        if let Some(path) = &self.path {
+            remove_hint(path)?;
+        }
+        self.write_at(offset, &record)?;
+        if let Some(entry) = self.keydir.0.get_mut(&kv.key) {
+            entry.timestamp = kv.timestamp;
+            entry.header_size = HEADER_SIZE as u8;
+            entry.value_hash = kv
+                .value
+                .as_deref()
+                .map(|value| hash_value(value.as_bytes()));
+        }
+        self.track_unsynced(record.len())?;
+
+        Ok(true)
+    }
+
+    /// Writes the current value of `key` again, with a fresh timestamp.
+    ///
+    /// This is useful to mark a key as recently used without having to provide its value.
+    /// Returns whether the key existed, nothing is written otherwise.
+    pub fn touch(&mut self, key: &str) -> Result<bool> {
+        match self.get(key)? {
+            Some(value) => self.set_inner(key, &value, false),
+            None => Ok(false),
+        }
+    }
+
+    /// Deletes the given `key` from the database.
+    ///
+    /// This operation might fail (mainly because of an I/O error) hence we return a [`Result`].
+    /// Deleting a key appends a tombstone record, space is only reclaimed by compaction (see
+    /// [`MyDB::compact`]). Returns whether the key existed, nothing is written otherwise.
+    pub fn delete(&mut self, key: &str) -> Result<bool> {
+        let key = &*self.normalize_key(key);
+        if !self.contains(key) {
+            return Ok(false);
+        }
+
+        let kv = KeyValue::tombstone(self.timestamp()?, key.to_owned())?;
+        self.append(&kv)?;
+        self.keydir.0.remove(key);
+
+        Ok(true)
+    }
+
+    /// Sets every key/value pair of `iter`.
+    ///
+    /// Records of every pair are appended with a single write, which is much cheaper than calling
+    /// [`MyDB::set`] for each pair since the storage is only synced once. If a key appears
+    /// several times, its last value wins. Nothing is written if any pair is invalid (e.g. its
+    /// key is too long).
+    pub fn extend<I, K, V>(&mut self, iter: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let timestamp = self.timestamp()?;
+        let kvs = iter
+            .into_iter()
+            .map(|(key, value)| {
+                let (key, value) = (key.as_ref().to_owned(), value.as_ref().to_owned());
+                self.new_record(timestamp, key, value)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.set_all(kvs)
+    }
+
+    /// Sets the key/value pairs of `entries` which are valid, reporting the invalid ones.
+    ///
+    /// Every pair is validated before anything is written, and the report tells which pairs were
+    /// rejected and why (e.g. their key is too long). When some pairs are rejected, the valid
+    /// ones are only written if `write_valid` is true, otherwise nothing is written. Valid pairs
+    /// are written with a single write, like with [`MyDB::extend`]. I/O errors still fail the
+    /// whole batch.
+    pub fn try_set_batch<I, K, V>(&mut self, entries: I, write_valid: bool) -> Result<BatchReport>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let timestamp = self.timestamp()?;
+        let mut kvs = Vec::new();
+        let mut rejected = Vec::new();
+        for (i, (key, value)) in entries.into_iter().enumerate() {
+            let (key, value) = (key.as_ref().to_owned(), value.as_ref().to_owned());
+            match self.new_record(timestamp, key, value) {
+                Ok(kv) => kvs.push(kv),
+                Err(err) => rejected.push((i, err)),
+            }
+        }
+
+        if !rejected.is_empty() && !write_valid {
+            return Ok(BatchReport {
+                written: 0,
+                rejected,
+            });
+        }
+
+        let written = kvs.len();
+        self.set_all(kvs)?;
+        Ok(BatchReport { written, rejected })
+    }
+
+    /// Sets every key/value pair of `map`, e.g. a [`HashMap`] or a [`BTreeMap`].
+    ///
+    /// This is like [`MyDB::extend`], except that only the last value of a key is written when
+    /// it appears several times, so that no space is wasted on records which are immediately
+    /// overwritten. Records are appended with a single write and the storage is synced once.
+    ///
+    /// [`BTreeMap`]: std::collections::BTreeMap
+    pub fn set_map<M: IntoIterator<Item = (String, String)>>(&mut self, map: M) -> Result<()> {
+        let timestamp = self.timestamp()?;
+        let kvs = last_values(map, |key, value| self.new_record(timestamp, key, value))?;
+        self.set_all(kvs)
+    }
+
+    /// Appends the records `kvs` with a single write and adds them to the key directory.
+    fn set_all(&mut self, kvs: Vec<KeyValue>) -> Result<()> {
+        if kvs.is_empty() {
+            return Ok(());
+        }
+
+        let entries = self.append_all(&kvs)?;
+        for (kv, entry) in kvs.into_iter().zip(entries) {
+            self.keydir.0.insert(kv.key, entry);
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the whole content of the database by the key/value pairs of `iter`.
+    ///
+    /// This is meant for restoring snapshots: it's like deleting every key and then calling
+    /// [`MyDB::extend`], except that the database is left compacted. If a key appears several
+    /// times, its last value wins. Nothing is written if any pair is invalid (e.g. its key is too
+    /// long).
+    ///
+    /// When the database was opened from a path (e.g. with [`MyDB::new`]), the new content is
+    /// written to a new file which atomically replaces the database file once durably written,
+    /// so a crash leaves either the previous or the new content. Otherwise, and in split mode,
+    /// the storage is rewritten in place, so a crash loses data. The sealed segments of a
+    /// database opened with [`MyDB::open_dir`] are deleted once the last segment is replaced, so
+    /// a crash in between leaves their keys which aren't part of the new content.
+    pub fn replace_contents<I, K, V>(&mut self, iter: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.check_writable()?;
+        self.checkpoint()?;
+        let timestamp = self.timestamp()?;
+        let kvs = last_values(iter, |key, value| self.new_record(timestamp, key, value))?;
+
+        if let Some(cache) = &mut self.cache {
+            *cache = Cache::new(cache.capacity);
+        }
+
+        match (&self.values, self.path.clone()) {
+            (None, Some(path)) => {
+                let mut buf = Vec::new();
+                let mut keydir = KeyDir::default();
+                for kv in kvs {
+                    let record = kv.encode();
+                    let entry = KeyDirEntry {
+                        timestamp,
+                        key_size: kv.key.len().try_into().unwrap(),
+                        header_size: HEADER_SIZE as u8,
+                        size: record.len().try_into().unwrap(),
+                        offset: buf.len(),
+                        value_hash: kv
+                            .value
+                            .as_deref()
+                            .map(|value| hash_value(value.as_bytes())),
+                    };
+                    keydir.0.insert(kv.key, entry);
+                    buf.extend(record);
+                }
+                self.config.check_file_size(buf.len())?;
+
+                let temp_path = compaction_path(&path, self.config.temp_dir.as_deref());
+                let mut file = S::create(&temp_path)?;
+                file.write_all(&buf)?;
+                self.io.write(buf.len());
+                file.flush()?;
+                file.sync()?;
+                self.replace_file(&temp_path, &path, file)?;
+
+                self.keydir = keydir;
+                self.offset = buf.len();
+            }
+            _ => {
+                self.rewrites += 1;
+                self.allocated = 0;
+                self.file.set_len(0)?;
+                if let Some(values) = &mut self.values {
+                    values.set_len(0)?;
+                }
+                self.keydir = KeyDir::default();
+                self.offset = 0;
+
+                let entries = self.append_all(&kvs)?;
+                for (kv, entry) in kvs.into_iter().zip(entries) {
+                    self.keydir.0.insert(kv.key, entry);
+                }
+            }
+        }
+
+        // The content of sealed segments is replaced too.
+        self.sealed_keys.clear();
+        for segment in std::mem::take(&mut self.sealed) {
+            let path = segment.path.clone();
+            drop(segment);
+            if let Some(path) = path {
+                fs::remove_file(&path)?;
+                sync_dir(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the given `keys` from the database.
+    ///
+    /// Tombstones of every key are appended with a single write, which is much cheaper than
+    /// calling [`MyDB::delete`] for each key since the storage is only synced once. Keys which
+    /// aren't in the database are skipped. Returns the number of keys actually deleted.
+    pub fn delete_many(&mut self, keys: &[&str]) -> Result<usize> {
+        let timestamp = self.timestamp()?;
+        let mut kvs = Vec::new();
+        let mut deleted = HashSet::new();
+        for &key in keys {
+            let key = self.normalize_key(key);
+            if self.contains(&key) && deleted.insert(key.clone()) {
+                kvs.push(KeyValue::tombstone(timestamp, key.into_owned())?);
+            }
+        }
+        if kvs.is_empty() {
+            return Ok(0);
+        }
+
+        self.append_all(&kvs)?;
+        for kv in &kvs {
+            self.keydir.0.remove(&kv.key);
+        }
+
+        Ok(kvs.len())
+    }
+
+    /// Deletes the smallest key of the database and returns it along with its value, or `None` if
+    /// the database is empty.
+    ///
+    /// Keys aren't kept in order so finding the smallest one takes time linear in the number of
+    /// keys.
+    pub fn pop_first(&mut self) -> Result<Option<(String, String)>> {
+        let key = self.keydir.0.keys().min().cloned();
+        self.pop(key)
+    }
+
+    /// Deletes the largest key of the database and returns it along with its value, or `None` if
+    /// the database is empty.
+    ///
+    /// Keys aren't kept in order so finding the largest one takes time linear in the number of
+    /// keys.
+    pub fn pop_last(&mut self) -> Result<Option<(String, String)>> {
+        let key = self.keydir.0.keys().max().cloned();
+        self.pop(key)
+    }
+
+    fn pop(&mut self, key: Option<String>) -> Result<Option<(String, String)>> {
+        let key = match key {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let value = self.get_required(&key)?;
+        self.delete(&key)?;
+        Ok(Some((key, value)))
+    }
+
+    /// Renames the key `from` to `to`, replacing the value of `to` if it's already set.
+    ///
+    /// The value of `from` is written under `to` along with a tombstone for `from` in a single
+    /// write, so that a crash can't lose the value while it's being renamed. Returns
+    /// whether `from` existed, nothing is written otherwise. Renaming a key to itself writes
+    /// nothing either.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<bool> {
+        let (from, to) = (&*self.normalize_key(from), &*self.normalize_key(to));
+        let value = match self.get(from)? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        if from == to {
+            return Ok(true);
+        }
+
+        let timestamp = self.timestamp()?;
+        let kvs = [
+            self.new_record(timestamp, to.to_owned(), value)?,
+            KeyValue::tombstone(timestamp, from.to_owned())?,
+        ];
+        let entry = self.append_all(&kvs)?.remove(0);
+        self.keydir.0.remove(from);
+        self.keydir.0.insert(to.to_owned(), entry);
+
+        Ok(true)
+    }
+
+    /// Durably appends the record `kv` to the storage and invalidates the cached value of its key.
+    ///
+    /// Returns the key directory entry locating the written record.
+    fn append(&mut self, kv: &KeyValue) -> Result<KeyDirEntry> {
+        Ok(self.append_all(std::slice::from_ref(kv))?.remove(0))
+    }
+
+    /// Durably appends the records `kvs` to the storage, with a single write to each file, and
+    /// invalidates the cached values of their keys.
+    ///
+    /// Returns the key directory entries locating the written records, in the same order.
+    fn append_all(&mut self, kvs: &[KeyValue]) -> Result<Vec<KeyDirEntry>> {
+        let entries = self.append_records(kvs)?;
+        // Values and tombstones replace the merge operands of their keys.
+        for kv in kvs.iter().filter(|kv| kv.flags & MERGE_FLAG == 0) {
+            self.keydir.1.remove(&kv.key);
+        }
+        Ok(entries)
+    }
+
+    /// Same as [`MyDB::append_all`] but leaves merge operands as they are.
+    fn append_records(&mut self, kvs: &[KeyValue]) -> Result<Vec<KeyDirEntry>> {
+        self.check_writable()?;
+        if let Some(cache) = &mut self.cache {
+            for kv in kvs {
+                cache.remove(&kv.key);
+            }
+        }
+        // The latest records of these keys are about to be in the active segment.
+        for kv in kvs {
+            self.sealed_keys.remove(&kv.key);
+        }
+
+        let mut entries = Vec::with_capacity(kvs.len());
+
+        if let Some(values) = &mut self.values {
+            let mut value_buf = Vec::new();
+            let mut key_buf = Vec::new();
+            for kv in kvs {
+                let value = kv.value.as_deref().unwrap_or_default();
+                let offset = self.offset + value_buf.len();
+                value_buf.extend_from_slice(value.as_bytes());
+                key_buf.extend(encode_key_record(&kv.header(), &kv.key, offset));
+
+                entries.push(KeyDirEntry {
+                    timestamp: kv.timestamp,
+                    key_size: kv.key.len().try_into().unwrap(),
+                    header_size: HEADER_SIZE as u8,
+                    size: value.len().try_into().unwrap(),
+                    offset,
+                    value_hash: Some(hash_value(value.as_bytes())),
+                });
+            }
+
+            self.config.check_file_size(self.offset + value_buf.len())?;
+
+            // Write the values before their keys so that a key never points to a missing value.
+            if !value_buf.is_empty() {
+                seek(values, io::SeekFrom::Start(self.offset as u64))?;
+                values.write_all(&value_buf)?;
+                self.io.write(value_buf.len());
+                self.config.sync_policy.sync(values)?;
+            }
+
+            seek(&mut self.file, io::SeekFrom::End(0))?;
+            self.file.write_all(&key_buf)?;
+            self.io.write(key_buf.len());
+            self.config.sync_policy.sync(&mut self.file)?;
+
+            self.offset += value_buf.len();
+            self.track_unsynced(value_buf.len() + key_buf.len())?;
+
+            return Ok(entries);
+        }
+
+        // A single record is written straight from its parts, without copying them to a buffer,
+        // unless the write is logged.
+        if let ([kv], None) = (kvs, &self.wal) {
+            return Ok(vec![self.append_parts(kv)?]);
+        }
+
+        let mut buf = Vec::new();
+        for kv in kvs {
+            let start = buf.len();
+            kv.encode_into(&mut buf);
+            entries.push(KeyDirEntry {
+                timestamp: kv.timestamp,
+                key_size: kv.key.len().try_into().unwrap(),
+                header_size: HEADER_SIZE as u8,
+                size: (buf.len() - start).try_into().unwrap(),
+                offset: self.offset + start,
+                value_hash: kv
+                    .value
+                    .as_deref()
+                    .map(|value| hash_value(value.as_bytes())),
+            });
+        }
+        self.config.check_file_size(self.offset + buf.len())?;
+        self.preallocate(buf.len())?;
+
+        self.write_at(self.offset, &buf)?;
+
+        self.offset += buf.len();
+        self.track_unsynced(buf.len())?;
+
+        Ok(entries)
+    }
+
+    /// Durably appends the record `kv` to the database file, which mustn't have a write-ahead
+    /// log, see [`MyDB::append_records`].
+    ///
+    /// The header, key and value are written with a single vectored write (most of the time)
+    /// instead of being copied to a buffer first, which makes writing a record cheaper.
+    fn append_parts(&mut self, kv: &KeyValue) -> Result<KeyDirEntry> {
+        let header = kv.header().encode_known();
+        let value = kv.value.as_deref().unwrap_or_default().as_bytes();
+        let size = HEADER_SIZE + kv.key.len() + value.len();
+        self.config.check_file_size(self.offset + size)?;
+        self.preallocate(size)?;
+
+        seek(&mut self.file, io::SeekFrom::Start(self.offset as u64))?;
+        let mut parts = [
+            io::IoSlice::new(&header),
+            io::IoSlice::new(kv.key.as_bytes()),
+            io::IoSlice::new(value),
+        ];
+        write_all_vectored(&mut self.file, &mut parts)?;
+        self.io.write(size);
+        self.config.sync_policy.sync(&mut self.file)?;
+
+        let entry = KeyDirEntry {
+            timestamp: kv.timestamp,
+            key_size: kv.key.len().try_into().unwrap(),
+            header_size: HEADER_SIZE as u8,
+            size: size.try_into().unwrap(),
+            offset: self.offset,
+            value_hash: kv.value.as_ref().map(|_| hash_value(value)),
+        };
+        self.offset += size;
+        self.track_unsynced(size)?;
+        Ok(entry)
+    }
+
+    /// Durably writes `buf` at `offset` within the database file.
+    ///
+    /// With a write-ahead log, the write is logged and synced there first instead of syncing the
+    /// database file.
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<()> {
+        if let Some(wal) = &mut self.wal {
+            let n = wal.append(offset, buf, self.config.sync_policy)?;
+            self.io.write(n);
+        }
+
+        seek(&mut self.file, io::SeekFrom::Start(offset as u64))?;
+        self.file.write_all(buf)?;
+        self.io.write(buf.len());
+
+        match &self.wal {
+            Some(wal) if wal.size >= WAL_CHECKPOINT_SIZE => self.checkpoint(),
+            Some(_) => Ok(()),
+            None => Ok(self.config.sync_policy.sync(&mut self.file)?),
+        }
+    }
+
+    /// Compacts the database by rewriting only the latest record of each key.
+    ///
+    /// Since every write appends a new record, the database grows even when keys are
+    /// overwritten. Compaction gets rid of records that were overwritten so that the storage
+    /// only holds what is needed to serve the current content of the database.
+    ///
+    /// Live records are copied one chunk at a time, so memory usage stays bounded whatever the
+    /// size of the database. When the database was opened from a path (e.g. with [`MyDB::new`]),
+    /// they're copied to a new file next to the database file, which atomically replaces it once
+    /// durably written. A crash during compaction thus leaves either the original or the
+    /// compacted database. Otherwise, and in split mode, live records are moved towards the start
+    /// of the storage in place, so a crash during compaction loses data. Records are copied as
+    /// is, so they keep their original timestamps, except that keys with merge operands (see
+    /// [`MyDB::merge`]) are first written again with their merged value.
+    pub fn compact(&mut self) -> Result<()> {
+        self.compact_with_progress(|_| {})
+    }
+
+    /// Compacts the database (see [`MyDB::compact`]) and writes a hint file matching the
+    /// compacted file, to open it quickly with [`MyDB::new_with_hint`].
+    ///
+    /// The hint file is a snapshot of the key directory (see [`MyDB::save_keydir`]) saved next
+    /// to the database file, with a `.hint` suffix. Rewriting the database file, e.g. when
+    /// compacting it, removes the previous hint file before the rewritten file replaces the
+    /// database file, and the new hint file is only written afterwards, atomically. A crash thus
+    /// never leaves a hint file which doesn't match the database file, at worst no hint file.
+    /// Fails with [`Error::IoError`] of kind [`io::ErrorKind::Unsupported`] if the database
+    /// wasn't opened from a path or is in split mode.
+    pub fn compact_with_hint(&mut self) -> Result<()> {
+        let path = match (&self.path, &self.values) {
+            (Some(path), None) => path.clone(),
+            _ => return Err(Error::IoError(io::ErrorKind::Unsupported.into())),
+        };
+        self.compact()?;
+
+        let hint = hint_path(&path);
+        let mut temp_path = hint.clone().into_os_string();
+        temp_path.push(".tmp");
+        let mut file = fs::File::create(&temp_path)?;
+        fs::set_permissions(&temp_path, fs::metadata(&path)?.permissions())?;
+        let mut w = io::BufWriter::new(&mut file);
+        self.save_keydir(&mut w)?;
+        w.flush()?;
+        drop(w);
+        file.sync_all()?;
+        fs::rename(&temp_path, &hint)?;
+        sync_dir(&hint)?;
+        Ok(())
+    }
+
+    /// Compacts the database (see [`MyDB::compact`]) if the ratio of its dead bytes, which
+    /// compaction would reclaim, exceeds `dead_ratio`.
+    ///
+    /// The ratio is computed from [`MyDB::disk_size`] and [`MyDB::live_bytes`] without any I/O,
+    /// so this is a cheap check to call periodically. Returns whether the database was compacted.
+    pub fn compact_if_needed(&mut self, dead_ratio: f64) -> Result<bool> {
+        let disk_size = self.disk_size();
+        if disk_size == 0 {
+            return Ok(false);
+        }
+        let dead_bytes = disk_size - self.live_bytes();
+        if dead_bytes as f64 / disk_size as f64 <= dead_ratio {
+            return Ok(false);
+        }
+        self.compact()?;
+        Ok(true)
+    }
+
+    /// Same as [`MyDB::compact`] but calls `progress` after each rewritten record.
+    ///
+    /// This is useful to report progress when compacting large databases.
+    pub fn compact_with_progress<F: FnMut(CompactProgress)>(
+        &mut self,
+        mut progress: F,
+    ) -> Result<()> {
+        self.check_writable()?;
+        self.collapse_operands()?;
+        // Logged writes refer to offsets within the file which is about to be rewritten.
+        self.checkpoint()?;
+        if self.values.is_none() {
+            if let Some(path) = self.path.clone() {
+                return self.compact_to_file(&path, &mut progress);
+            }
+        }
+
+        self.rewrites += 1;
+        self.allocated = 0;
+        let mut entries: Vec<_> = self.keydir.0.iter_mut().collect();
+        entries.sort_by_key(|(_, entry)| entry.offset);
+
+        // Keys records are rebuilt from memory in split mode.
+        if self.values.is_some() {
+            self.file.set_len(0)?;
+            seek(&mut self.file, io::SeekFrom::Start(0))?;
+        }
+
+        let mut buf = vec![0; COMPACT_CHUNK_SIZE];
+        let mut offset = 0;
+        let mut stats = CompactProgress {
+            records_processed: 0,
+            bytes_written: 0,
+        };
+        for (key, entry) in entries {
+            // Records only move towards the start of the storage, so a chunk never overwrites
+            // data which is still to be moved.
+            let storage = self.values.as_mut().unwrap_or(&mut self.file);
+            let size = entry.size as usize;
+            let mut copied = 0;
+            while copied < size {
+                let n = std::cmp::min(buf.len(), size - copied);
+                seek(storage, io::SeekFrom::Start((entry.offset + copied) as u64))?;
+                storage.read_exact(&mut buf[..n])?;
+                self.io.read(n);
+                seek(storage, io::SeekFrom::Start((offset + copied) as u64))?;
+                storage.write_all(&buf[..n])?;
+                self.io.write(n);
+                copied += n;
+            }
+
+            if self.values.is_some() {
+                let header = Header {
+                    timestamp: entry.timestamp,
+                    key_size: key.len() as u32,
+                    value_size: entry.size,
+                    flags: 0,
+                };
+                let key_record = encode_key_record(&header, key, offset);
+                self.file.write_all(&key_record)?;
+                self.io.write(key_record.len());
+                stats.bytes_written += key_record.len();
+            }
+
+            entry.offset = offset;
+            offset += size;
+
+            stats.records_processed += 1;
+            stats.bytes_written += size;
+            progress(stats);
+        }
+
+        let storage = self.values.as_mut().unwrap_or(&mut self.file);
+        storage.set_len(offset as u64)?;
+        if let Some(values) = &mut self.values {
+            self.config.sync_policy.sync(values)?;
+        }
+        self.config.sync_policy.sync(&mut self.file)?;
+        self.offset = offset;
+
+        Ok(())
+    }
+
+    /// Compacts the database by writing the live records to a new file which then replaces the
+    /// file at `path`, see [`MyDB::compact`].
+    fn compact_to_file<F: FnMut(CompactProgress)>(
+        &mut self,
+        path: &Path,
+        progress: &mut F,
+    ) -> Result<()> {
+        let temp_path = compaction_path(path, self.config.temp_dir.as_deref());
+        let (compacted, len) = self.write_compacted(&temp_path, progress)?;
+        self.replace_file(&temp_path, path, compacted)?;
+
+        // Records were written in the order of their offsets.
+        let mut entries: Vec<_> = self.keydir.0.values_mut().collect();
+        entries.sort_by_key(|entry| entry.offset);
+        let mut offset = 0;
+        for entry in entries {
+            entry.offset = offset;
+            offset += entry.size as usize;
+        }
+        self.offset = len;
+
+        Ok(())
+    }
+
+    /// Atomically replaces the database file at `path` by `file`, which was durably written at
+    /// `temp_path`.
+    fn replace_file(&mut self, temp_path: &Path, path: &Path, file: S) -> Result<()> {
+        // The compacted file is created with the default permissions.
+        fs::set_permissions(temp_path, fs::metadata(path)?.permissions())?;
+        remove_hint(path)?;
+        // Renaming is atomic, syncing the directory makes it durable.
+        fs::rename(temp_path, path)?;
+        sync_dir(path)?;
+        self.file = file;
+        self.rewrites += 1;
+        self.allocated = 0;
+        if let Some(background_sync) = &self.background_sync {
+            background_sync.reopen(vec![path.to_owned()]);
+        }
+        Ok(())
+    }
+
+    /// Durably writes the live records to a new storage at `temp_path`, in the order of their
+    /// offsets, followed by the tombstones hiding records of sealed segments (see
+    /// [`MyDB::sealed_tombstones`]), and returns it along with its length.
+    fn write_compacted<F: FnMut(CompactProgress)>(
+        &mut self,
+        temp_path: &Path,
+        progress: &mut F,
+    ) -> Result<(S, usize)> {
+        let mut compacted = S::create(temp_path)?;
+
+        let mut entries: Vec<_> = self.keydir.0.values().collect();
+        entries.sort_by_key(|entry| entry.offset);
+
+        let mut buf = vec![0; COMPACT_CHUNK_SIZE];
+        let mut stats = CompactProgress {
+            records_processed: 0,
+            bytes_written: 0,
+        };
+        for entry in entries {
+            seek(&mut self.file, io::SeekFrom::Start(entry.offset as u64))?;
+            let size = entry.size as usize;
+            let mut copied = 0;
+            while copied < size {
+                let n = std::cmp::min(buf.len(), size - copied);
+                self.file.read_exact(&mut buf[..n])?;
+                self.io.read(n);
+                compacted.write_all(&buf[..n])?;
+                self.io.write(n);
+                copied += n;
+            }
+
+            stats.records_processed += 1;
+            stats.bytes_written += size;
+            progress(stats);
+        }
+
+        for kv in self.sealed_tombstones()? {
+            let record = kv.encode();
+            compacted.write_all(&record)?;
+            self.io.write(record.len());
+            stats.records_processed += 1;
+            stats.bytes_written += record.len();
+            progress(stats);
+        }
+
+        // The compacted file must be durable before it replaces the database file, whatever the
+        // sync policy.
+        compacted.flush()?;
+        compacted.sync()?;
+
+        Ok((compacted, stats.bytes_written))
+    }
+
+    /// Returns tombstones for the keys of sealed segments (see [`MyDB::open_dir`]) which were
+    /// deleted, so that compaction doesn't bring them back.
+    ///
+    /// Each tombstone has the timestamp of the newest record of its key in sealed segments, so
+    /// that it still wins over them once the database is opened again.
+    fn sealed_tombstones(&self) -> Result<Vec<KeyValue>> {
+        let mut deleted: HashMap<&str, u32> = HashMap::new();
+        for segment in &self.sealed {
+            for (key, entry) in &segment.keydir.0 {
+                if self.keydir.0.contains_key(key) || self.sealed_keys.contains_key(key) {
+                    continue;
+                }
+                let timestamp = deleted.entry(key).or_default();
+                *timestamp = std::cmp::max(*timestamp, entry.timestamp);
+            }
+        }
+        deleted
+            .into_iter()
+            .map(|(key, timestamp)| KeyValue::tombstone(timestamp, key.to_owned()))
+            .collect()
+    }
+
+    /// Compacts the database by rewriting only the `n` latest records of each key.
+    ///
+    /// Unlike [`MyDB::compact`], this keeps previous values around so that they can still be
+    /// retrieved with [`MyDB::history`]. Deleting a key still gets rid of all its records. The
+    /// latest record of each key is always kept, so `n == 0` behaves like `n == 1`. Timestamps of
+    /// rewritten records are preserved.
+    ///
+    /// The whole log is read in memory before the storage is rewritten in place, which means a
+    /// crash during compaction loses data.
+    pub fn compact_keep_versions(&mut self, n: usize) -> Result<()> {
+        self.check_writable()?;
+        self.collapse_operands()?;
+        self.checkpoint()?;
+        let records = self.read_log()?;
+
+        // Group records of each key since its last deletion, in log order. Merge operands on top
+        // of a value were collapsed into a later value, so they're dropped.
+        let mut versions: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (i, kv) in records.iter().enumerate() {
+            if kv.value.is_none() {
+                versions.remove(kv.key.as_str());
+            } else if kv.flags & MERGE_FLAG == 0 || !versions.contains_key(kv.key.as_str()) {
+                versions.entry(&kv.key).or_default().push(i);
+            }
+        }
+
+        let mut keep = vec![false; records.len()];
+        for indices in versions.into_values() {
+            let skip = indices.len().saturating_sub(n.max(1));
+            for i in indices.into_iter().skip(skip) {
+                keep[i] = true;
+            }
+        }
+        let mut kept: Vec<_> = records
+            .into_iter()
+            .zip(keep)
+            .filter_map(|(mut kv, keep)| {
+                // An operand without any previous value is a value on its own.
+                kv.flags &= !MERGE_FLAG;
+                keep.then_some(kv)
+            })
+            .collect();
+        kept.extend(self.sealed_tombstones()?);
+
+        // Rewrite them from the start of the storage.
+        if let Some(path) = &self.path {
+            remove_hint(path)?;
+        }
+        self.rewrites += 1;
+        self.allocated = 0;
+        self.file.set_len(0)?;
+        if let Some(values) = &mut self.values {
+            values.set_len(0)?;
+        }
+        self.offset = 0;
+        self.keydir.0.clear();
+
+        let entries = self.append_all(&kept)?;
+        for (kv, entry) in kept.into_iter().zip(entries) {
+            if kv.value.is_some() {
+                self.keydir.0.insert(kv.key, entry);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the in-memory key directory is consistent with the storage.
+    ///
+    /// Every key must point to a record that can be decoded, that is written for this key and
+    /// that has the same timestamp as the one kept in memory. In split mode, the key directory
+    /// must match the keys file and every value must be readable from the values file. This
+    /// reads the whole database so it's mostly useful in tests and for diagnosing issues. Fails
+    /// with [`Error::Corruption`] describing the first inconsistency found.
+    pub fn check_invariants(&mut self) -> Result<()> {
+        let normalizer = self.config.key_normalizer;
+        if let Some(values) = &mut self.values {
+            let (keydir, _) = KeyDir::load(&mut self.file, true, normalizer)?;
+            if keydir.0.len() != self.keydir.0.len() {
+                return Err(Error::Corruption(format!(
+                    "keys file has {} keys but {} keys are in memory",
+                    keydir.0.len(),
+                    self.keydir.0.len()
+                )));
+            }
+
+            let len = seek(values, io::SeekFrom::End(0))?;
+            for (key, entry) in &self.keydir.0 {
+                match keydir.0.get(key) {
+                    Some(stored) if stored.same_record(entry) => (),
+                    _ => {
+                        return Err(Error::Corruption(format!(
+                            "entry of key {:?} doesn't match the keys file",
+                            key
+                        )))
+                    }
+                }
+
+                if (entry.offset + entry.size as usize) as u64 > len {
+                    return Err(Error::Corruption(format!(
+                        "value of key {:?} at offset {} goes past the end of the values file",
+                        key, entry.offset
+                    )));
+                }
+                seek(values, io::SeekFrom::Start(entry.offset as u64))?;
+                let mut value = vec![0; entry.size as usize];
+                values.read_exact(&mut value)?;
+                self.io.read(value.len());
+                if std::str::from_utf8(&value).is_err() {
+                    return Err(Error::Corruption(format!(
+                        "value of key {:?} at offset {} isn't valid utf-8",
+                        key, entry.offset
+                    )));
+                }
+            }
+
+            return Ok(());
+        }
+
+        for (key, entry) in &self.keydir.0 {
+            seek(&mut self.file, io::SeekFrom::Start(entry.offset as u64))?;
+            let mut kv = vec![0; entry.size as usize];
+            if let Err(err) = self.file.read_exact(&mut kv) {
+                return Err(Error::Corruption(format!(
+                    "unable to read record of key {:?} at offset {}: {}",
+                    key, entry.offset, err
+                )));
+            }
+            self.io.read(kv.len());
+
+            let kv = match KeyValue::decode(&kv) {
+                Ok(kv) => kv,
+                Err(err) => {
+                    return Err(Error::Corruption(format!(
+                        "unable to decode record of key {:?} at offset {}: {:?}",
+                        key, entry.offset, err
+                    )))
+                }
+            };
+
+            let stored_key = match normalizer {
+                Some(normalizer) => normalizer.normalize(&kv.key),
+                None => Cow::Borrowed(kv.key.as_str()),
+            };
+            if stored_key != key.as_str() {
+                return Err(Error::Corruption(format!(
+                    "record at offset {} is for key {:?} instead of {:?}",
+                    entry.offset, kv.key, key
+                )));
+            }
+            if kv.timestamp != entry.timestamp {
+                return Err(Error::Corruption(format!(
+                    "record of key {:?} at offset {} has timestamp {} instead of {}",
+                    key, entry.offset, kv.timestamp, entry.timestamp
+                )));
+            }
+            if kv.value.is_none() {
+                return Err(Error::Corruption(format!(
+                    "record of key {:?} at offset {} is a tombstone",
+                    key, entry.offset
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads every record of the database in the order in which they were written.
+    fn read_log(&mut self) -> Result<Vec<KeyValue>> {
+        let mut buf = Vec::new();
+        seek(&mut self.file, io::SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut buf)?;
+        self.io.read(buf.len());
+        if self.values.is_none() {
+            // Ignore space reserved after the last record.
+            buf.truncate(self.offset);
+        }
+
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while offset < buf.len() {
+            let header = Header::decode(&buf[offset..])?;
+            let key_size = header.key_size as usize;
+
+            if let Some(values) = &mut self.values {
+                let key_start = offset + header.len();
+                let key_end = key_start + key_size;
+                let key = std::str::from_utf8(&buf[key_start..key_end])?.to_owned();
+
+                let value = if header.is_tombstone() {
+                    None
+                } else {
+                    let value_offset = &buf[key_end..key_end + VALUE_OFFSET_SIZE];
+                    let value_offset = u64::from_le_bytes(value_offset.try_into().unwrap());
+                    let mut value = vec![0; header.value_len()];
+                    seek(values, io::SeekFrom::Start(value_offset))?;
+                    values.read_exact(&mut value)?;
+                    self.io.read(value.len());
+                    Some(std::str::from_utf8(&value)?.to_owned())
+                };
+
+                records.push(KeyValue {
+                    timestamp: header.timestamp,
+                    key,
+                    value,
+                    flags: header.flags,
+                });
+                offset = key_end + VALUE_OFFSET_SIZE;
+            } else {
+                let total_size = header.record_size()?;
+                records.push(KeyValue::decode(&buf[offset..offset + total_size])?);
+                offset += total_size;
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Reserves space for at least `additional` more bytes of records.
+    ///
+    /// The database file is extended with zeros so that the filesystem can allocate space ahead
+    /// of writes, which helps avoid fragmentation when the amount of data to write is known in
+    /// advance. Records then overwrite the reserved space. Space which is still reserved is
+    /// released by [`MyDB::truncate_to_fit`], compaction, or when the database is opened again.
+    /// Split mode databases don't support reserving space, so this does nothing for them.
+    pub fn reserve(&mut self, additional: usize) -> Result<()> {
+        self.check_writable()?;
+        self.config.check_file_size(self.offset + additional)?;
+        if self.values.is_some() {
+            return Ok(());
+        }
+
+        let len = (self.offset + additional) as u64;
+        if seek(&mut self.file, io::SeekFrom::End(0))? < len {
+            self.file.set_len(len)?;
+            self.config.sync_policy.sync(&mut self.file)?;
+        }
+        Ok(())
+    }
+
+    /// Truncates the database file right after the last record, releasing space which is still
+    /// reserved (see [`MyDB::reserve`]).
+    pub fn truncate_to_fit(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let storage = self.values.as_mut().unwrap_or(&mut self.file);
+        if seek(storage, io::SeekFrom::End(0))? > self.offset as u64 {
+            storage.set_len(self.offset as u64)?;
+            self.config.sync_policy.sync(storage)?;
+        }
+        self.allocated = self.offset;
+        Ok(())
+    }
+
+    /// Extends the database file to the preallocated segment size before `len` bytes are
+    /// appended, if the file isn't already large enough, see
+    /// [`MyDB::with_preallocated_segment_size`].
+    fn preallocate(&mut self, len: usize) -> Result<()> {
+        let size = match self.config.preallocated_segment_size {
+            Some(size) => size,
+            None => return Ok(()),
+        };
+        let end = self.offset + len;
+        if end <= self.allocated {
+            return Ok(());
+        }
+
+        let mut target = std::cmp::max(size, end);
+        if let Some(max) = self.config.max_file_size {
+            target = std::cmp::max(std::cmp::min(target, max as usize), end);
+        }
+        let physical = seek(&mut self.file, io::SeekFrom::End(0))? as usize;
+        if physical < target {
+            self.file.set_len(target as u64)?;
+        }
+        self.allocated = std::cmp::max(physical, target);
+        Ok(())
+    }
+
+    /// Computes the CRC-32 checksum of the whole database file.
+    ///
+    /// This is meant to verify that a copy of the database, such as a backup, matches the
+    /// original: both files have the same checksum if they have the same content, which can be
+    /// checked with any CRC-32 tool. In split mode, the checksum covers the keys file followed by
+    /// the values file. This reads the whole database.
+    pub fn file_checksum(&mut self) -> Result<u32> {
+        let mut crc = Crc32::new();
+        let mut buf = vec![0; 64 * 1024];
+
+        let files = std::iter::once(&mut self.file).chain(self.values.as_mut());
+        for file in files {
+            file.flush()?;
+            seek(file, io::SeekFrom::Start(0))?;
+            loop {
+                let n = match file.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(Error::IoError(err)),
+                };
+                self.io.read(n);
+                crc.update(&buf[..n]);
+            }
+        }
+
+        Ok(crc.finish())
+    }
+
+    /// Returns the offset and size of the value described by `entry`, within the file where
+    /// values live.
+    fn value_span(&self, entry: &KeyDirEntry) -> (usize, usize) {
+        if self.values.is_some() {
+            return (entry.offset, entry.size as usize);
+        }
+        let prefix = entry.header_size as usize + entry.key_size as usize;
+        (entry.offset + prefix, entry.size as usize - prefix)
+    }
+
+    /// Returns the size in bytes of the records written to the database, whether they're still
+    /// live or not.
+    ///
+    /// In split mode, this is the size of the values file. Together with [`MyDB::live_bytes`],
+    /// this tells how much space compaction would reclaim. This doesn't perform any I/O.
+    pub fn disk_size(&self) -> u64 {
+        self.offset as u64
+    }
+
+    /// Returns the size in bytes of the live records, that is the latest record of each key.
+    ///
+    /// In split mode, only values are accounted for, like in [`MyDB::disk_size`]. This doesn't
+    /// perform any I/O.
+    pub fn live_bytes(&self) -> u64 {
+        self.keydir.0.values().map(|entry| entry.size as u64).sum()
+    }
+
+    /// Estimates the memory used by the keydir, in bytes.
+    ///
+    /// This accounts for the bytes of every key and for the slots of the hash map holding the
+    /// entries. It ignores allocator overhead and the read cache, so it's only meant to give an
+    /// idea of the memory needed to hold a database, which grows with the number and length of
+    /// keys but not with values.
+    pub fn memory_usage(&self) -> usize {
+        // Every slot holds an entry and a control byte.
+        let slot_size = std::mem::size_of::<(String, KeyDirEntry)>() + 1;
+        let keys: usize = self.keydir.0.keys().map(String::capacity).sum();
+        self.keydir.0.capacity() * slot_size + keys
+    }
+
+    /// Summarizes the sizes of the values of every key.
+    ///
+    /// This only looks at metadata kept in memory so it doesn't perform any I/O.
+    pub fn size_histogram(&self) -> SizeHistogram {
+        let mut sizes: Vec<_> = self
+            .keydir
+            .0
+            .values()
+            .map(|entry| self.value_span(entry).1)
+            .collect();
+        sizes.sort_unstable();
+
+        if sizes.is_empty() {
+            return SizeHistogram::default();
+        }
+
+        // Nearest-rank percentile.
+        let percentile = |p: usize| sizes[(p * sizes.len()).div_ceil(100) - 1];
+
+        SizeHistogram {
+            count: sizes.len(),
+            min: sizes[0],
+            max: sizes[sizes.len() - 1],
+            mean: sizes.iter().sum::<usize>() as f64 / sizes.len() as f64,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+        }
+    }
+
+    /// Gets the metadata of the record associated with the given `key`.
+    ///
+    /// Unlike [`MyDB::get`], this doesn't perform any I/O since metadata is kept in memory, which
+    /// makes it a cheap way to check whether a key exists. Returns [`None`] if the `key` doesn't
+    /// exist within the database.
+    pub fn metadata(&self, key: &str) -> Option<EntryMeta> {
+        let key = &*self.normalize_key(key);
+        match self.keydir.0.get(key) {
+            Some(entry) => Some(EntryMeta::from(entry)),
+            None => self.sealed_segment(key)?.metadata(key),
+        }
+    }
+
+    /// Returns the size in bytes of the value associated with the given `key`, without reading it.
+    ///
+    /// Like [`MyDB::metadata`], this doesn't perform any I/O. Returns [`None`] if the `key`
+    /// doesn't exist within the database. Values encrypted with `MyDB::with_cipher` are larger
+    /// than they were before being encrypted.
+    pub fn value_len(&self, key: &str) -> Option<usize> {
+        let key = &*self.normalize_key(key);
+        match self.keydir.0.get(key) {
+            Some(entry) => Some(self.value_span(entry).1),
+            None => self.sealed_segment(key)?.value_len(key),
+        }
+    }
+
+    /// Returns the sealed segment holding the latest value of `key`, see [`MyDB::open_dir`].
+    fn sealed_segment(&self, key: &str) -> Option<&MyDB<S>> {
+        self.sealed_index(key).map(|i| &self.sealed[i])
+    }
+
+    /// Returns the index of the sealed segment holding the latest value of `key`.
+    ///
+    /// The keys of sealed segments aren't looked up if the bloom filters of every sealed
+    /// segment exclude `key`, see [`MyDB::with_bloom_filters`].
+    fn sealed_index(&self, key: &str) -> Option<usize> {
+        let excluded = |segment: &MyDB<S>| {
+            segment
+                .bloom
+                .as_ref()
+                .is_some_and(|bloom| !bloom.may_contain(key))
+        };
+        if self.sealed.iter().all(excluded) {
+            return None;
+        }
+        self.sealed_lookups.set(self.sealed_lookups.get() + 1);
+        self.sealed_keys.get(key).copied()
+    }
+
+    /// Returns how many times the keys of sealed segments (see [`MyDB::open_dir`]) were looked
+    /// up by this handle.
+    ///
+    /// Lookups which the bloom filters of sealed segments answer (see
+    /// [`MyDB::with_bloom_filters`]) aren't counted.
+    pub fn segment_lookups(&self) -> u64 {
+        self.sealed_lookups.get()
+    }
+
+    /// Returns the number of reads and writes made by this handle, and how many bytes they
+    /// transferred.
+    ///
+    /// Counters accumulate for the lifetime of the handle and include compaction, but not the
+    /// scan made when opening the database (see [`MyDB::load_stats`]). Values served by the read
+    /// cache aren't counted since they don't cause any read.
+    pub fn io_counters(&self) -> IoCounters {
+        self.io
+    }
+
+    /// Returns the hash of the value associated with `key`, if known.
+    ///
+    /// Hashes are 64-bit FNV-1a hashes of the bytes of values, which are kept in memory for
+    /// values written or read by this handle. They aren't stored in the database file: records
+    /// would need two extra header words (see the [`format`](mod@format) module), which CaskDB
+    /// can't read since it doesn't know about header flags. Hashes are thus lost when the
+    /// database is closed: `None` is returned for keys whose value wasn't written or read since
+    /// the database was opened, for keys with merge operands (see [`MyDB::merge`]), as well as
+    /// for unknown keys. Equal values always have equal hashes, different values almost never
+    /// do, which lets [`MyDB::set_idempotent`] find out whether a value changed without reading
+    /// it.
+    pub fn value_hash(&self, key: &str) -> Option<u64> {
+        let key = &*self.normalize_key(key);
+        if self.keydir.1.contains_key(key) {
+            return None;
+        }
+        self.keydir.0.get(key).and_then(|entry| entry.value_hash)
+    }
+
+    /// Returns statistics of the scan made to load the database when it was opened.
+    ///
+    /// Opening a database scans all of its records, which helps understand why opening a
+    /// large database is slow. Reloading the database (see [`MyDB::reload`]) doesn't change
+    /// these statistics.
+    pub fn load_stats(&self) -> LoadStats {
+        self.load_stats
+    }
+
+    /// Returns the path of the file backing the database, if it was opened from a path.
+    ///
+    /// In split mode, this is the path from which the names of the keys and values files are
+    /// derived.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns the keys whose latest record was written at timestamp `ts` or later, in no
+    /// particular order.
+    ///
+    /// This makes it possible to only pull recent changes, for instance to replicate the
+    /// database incrementally. Deleted keys aren't returned. This only looks at metadata kept in
+    /// memory so it doesn't perform any I/O.
+    pub fn keys_changed_since(&self, ts: u32) -> impl Iterator<Item = &str> {
+        self.keydir
+            .0
+            .iter()
+            .filter(move |(_, entry)| entry.timestamp >= ts)
+            .map(|(key, _)| key.as_str())
+    }
+
+    /// Returns an iterator over the keys and values of the database, in no particular order.
+    ///
+    /// Values are read from the storage as the iterator advances, reading errors are returned by
+    /// the iterator.
+    pub fn iter(&mut self) -> Iter<'_, S> {
+        let keys: Vec<_> = self.keydir.0.keys().cloned().collect();
+        Iter {
+            db: self,
+            keys: keys.into_iter(),
+        }
+    }
+
+    /// Returns an iterator over the keys and values of the database, in the order of their
+    /// records.
+    ///
+    /// This is the same as [`MyDB::iter`], except that values are read in ascending offsets,
+    /// which turns a full scan into a mostly sequential read of the storage instead of random
+    /// reads. This is much faster on spinning disks.
+    pub fn iter_sequential(&mut self) -> Iter<'_, S> {
+        let mut entries: Vec<_> = self.keydir.0.iter().collect();
+        entries.sort_unstable_by_key(|(_, entry)| entry.offset);
+        let keys: Vec<_> = entries.into_iter().map(|(key, _)| key.clone()).collect();
+        Iter {
+            db: self,
+            keys: keys.into_iter(),
+        }
+    }
+
+    /// Calls `f` with every key and value of the database, in the order of their records.
+    ///
+    /// Unlike [`MyDB::iter`], nothing is allocated for each pair: values are read into a single
+    /// buffer which is reused, and `f` borrows the key and the raw bytes of the value (which are
+    /// encrypted with `MyDB::with_cipher`). Keys with merge operands (see [`MyDB::merge`]) come
+    /// last, with their merged value. Stops at the first error, including errors returned by
+    /// `f`, and returns it.
+    pub fn for_each<F: FnMut(&str, &[u8]) -> Result<()>>(&mut self, mut f: F) -> Result<()> {
+        let mut spans = self
+            .keydir
+            .0
+            .iter()
+            .filter(|(key, _)| !self.keydir.1.contains_key(*key))
+            .map(|(key, entry)| (key.as_str(), self.value_span(entry)))
+            .collect::<Vec<_>>();
+        spans.sort_unstable_by_key(|&(_, (offset, _))| offset);
+
+        let storage = self.values.as_mut().unwrap_or(&mut self.file);
+        let mut buf = Vec::new();
+        for (key, (offset, size)) in spans {
+            buf.resize(size, 0);
+            seek(storage, io::SeekFrom::Start(offset as u64))?;
+            storage.read_exact(&mut buf)?;
+            self.io.read(size);
+            f(key, &buf)?;
+        }
+
+        let merged: Vec<_> = self.keydir.1.keys().cloned().collect();
+        for key in merged {
+            if let Some(value) = self.read_value(&key)? {
+                f(&key, self.seal_value(value)?.as_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns an iterator over the values of the database, in no particular order.
+    ///
+    /// Like [`MyDB::iter`], values are read from the storage as the iterator advances and a
+    /// value which can't be read results in an error for this value only.
+    pub fn values(&mut self) -> impl Iterator<Item = Result<String>> + '_ {
+        self.iter().map(|item| item.map(|(_, value)| value))
+    }
+
+    /// Writes every key and value of the database to `w` as CSV, ordered by key.
+    ///
+    /// Each row is made of two fields, the key and the value, without any header row. Fields are
+    /// quoted when they contain commas, quotes or line breaks, quotes being escaped by doubling
+    /// them. See [`MyDB::import_csv`] to read such rows back.
+    pub fn export_csv<W: io::Write>(&mut self, mut w: W) -> Result<()> {
+        let mut keys: Vec<_> = self.keydir.0.keys().cloned().collect();
+        keys.sort_unstable();
+
+        let mut row = String::new();
+        for key in keys {
+            let value = match self.get(&key)? {
+                Some(value) => value,
+                None => continue,
+            };
+            row.clear();
+            push_csv_field(&mut row, &key);
+            row.push(',');
+            push_csv_field(&mut row, &value);
+            row.push('\n');
+            w.write_all(row.as_bytes())?;
+        }
+        w.flush()?;
+
+        Ok(())
+    }
+
+    /// Sets the keys and values read as CSV from `r`, see [`MyDB::export_csv`] for the format.
+    ///
+    /// Rows are written with a single write, like with [`MyDB::extend`]. Fails with
+    /// [`Error::DecodeError`] without writing anything if the CSV is malformed or a row doesn't
+    /// have exactly two fields. Returns the number of rows read.
+    pub fn import_csv<R: io::Read>(&mut self, mut r: R) -> Result<usize> {
+        let mut csv = String::new();
+        r.read_to_string(&mut csv)?;
+
+        let rows = parse_csv(&csv)?;
+        let mut items = Vec::with_capacity(rows.len());
+        for (i, row) in rows.into_iter().enumerate() {
+            match <[String; 2]>::try_from(row) {
+                Ok([key, value]) => items.push((key, value)),
+                Err(row) => {
+                    return Err(Error::DecodeError(format!(
+                        "csv row {} has {} fields instead of 2",
+                        i + 1,
+                        row.len()
+                    )))
+                }
+            }
+        }
+
+        let count = items.len();
+        self.extend(items)?;
+        Ok(count)
+    }
+
+    /// Distributes the keys of the database into `n` databases, according to `shard_fn`.
+    ///
+    /// Each key is written, with its value, to the database at `paths[shard_fn(key) % n]`. These
+    /// databases are opened with [`MyDB::new`] so they're created if needed, and keep their
+    /// current content otherwise. This is useful to reshard a database which grew too large.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is zero or isn't the number of `paths`.
+    pub fn split_by<F, P>(&mut self, n: usize, shard_fn: F, paths: &[P]) -> Result<()>
+    where
+        F: Fn(&str) -> usize,
+        P: AsRef<Path>,
+    {
+        assert!(n > 0, "unable to split a database into zero shards");
+        assert_eq!(n, paths.len(), "there must be one path per shard");
+
+        let mut shards = vec![Vec::new(); n];
+        for item in self.iter() {
+            let (key, value) = item?;
+            shards[shard_fn(&key) % n].push((key, value));
+        }
+
+        for (shard, path) in shards.into_iter().zip(paths) {
+            MyDB::new(path)?.extend(shard)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of keys within the database.
+    pub fn len(&self) -> usize {
+        self.keydir.0.len() + self.sealed_keys.len()
+    }
+
+    /// Returns whether the database doesn't contain any key.
+    pub fn is_empty(&self) -> bool {
+        self.keydir.0.is_empty() && self.sealed_keys.is_empty()
+    }
+
+    /// Returns whether `key` is in the active segment or in a sealed segment (see
+    /// [`MyDB::open_dir`]).
+    fn contains(&self, key: &str) -> bool {
+        self.keydir.0.contains_key(key) || self.sealed_index(key).is_some()
+    }
+
+    /// Returns whether the database contains `key`.
+    ///
+    /// Keys of every segment (see [`MyDB::open_dir`]) and of the base database (see
+    /// [`MyDB::open_with_base`]) are kept in memory, so this doesn't perform any I/O. In
+    /// particular, segments which don't have the key are never read.
+    pub fn contains_key(&self, key: &str) -> bool {
+        let key = &*self.normalize_key(key);
+        self.contains(key)
+            || self
+                .base
+                .as_ref()
+                .is_some_and(|base| base.contains_key(key))
+    }
+
+    /// Returns the number of keys starting with `prefix`, which is normalized like keys (see
+    /// [`OpenOptions::with_key_normalizer`]).
+    ///
+    /// This only looks at keys kept in memory so it doesn't perform any I/O.
+    pub fn count_prefix(&self, prefix: &str) -> usize {
+        let prefix = &*self.normalize_key(prefix);
+        self.keydir
+            .0
+            .keys()
+            .chain(self.sealed_keys.keys())
+            .filter(|key| key.starts_with(prefix))
+            .count()
+    }
+
+    /// Saves a snapshot of the key directory to `w`, to be loaded by [`MyDB::new_with_keydir`].
+    ///
+    /// This is meant to be called when shutting down a process holding a large database, so that
+    /// the next process can open it without scanning the whole file. Fails with
+    /// [`Error::IoError`] of kind [`io::ErrorKind::Unsupported`] in split mode, as well as when
+    /// keys have merge operands (see [`MyDB::merge`]), until the database is compacted.
+    pub fn save_keydir<W: io::Write>(&self, w: W) -> Result<()> {
+        if self.values.is_some() || !self.keydir.1.is_empty() {
+            return Err(Error::IoError(io::ErrorKind::Unsupported.into()));
+        }
+        self.keydir.save(w, self.offset)
+    }
+
+    /// Returns the `(key, size, offset)` of every entry of the key directory, sorted by offset.
+    ///
+    /// This exposes where each live record physically lives within the database file and is
+    /// mostly useful for debugging and for testing the file layout. In split mode, entries
+    /// describe where values live within the values file.
+    pub fn debug_entries(&self) -> Vec<(String, u32, usize)> {
+        let mut entries: Vec<_> = self
+            .keydir
+            .0
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.size, entry.offset))
+            .collect();
+        entries.sort_by_key(|(_, _, offset)| *offset);
+        entries
+    }
+}
+
+/// Returns the path of the file to which the database file at `path` is compacted, within
+/// `temp_dir` if set and next to the database file otherwise.
+fn compaction_path(path: &Path, temp_dir: Option<&Path>) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".compact");
+    let path = PathBuf::from(name);
+    match (temp_dir, path.file_name()) {
+        (Some(dir), Some(name)) => dir.join(name),
+        _ => path,
+    }
+}
+
+/// Returns the path of the hint file of the database file at `path`, see
+/// [`MyDB::compact_with_hint`].
+fn hint_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".hint");
+    PathBuf::from(name)
+}
+
+/// Durably removes the hint file of the database file at `path`, if any, before the file is
+/// rewritten, since the hint wouldn't match the rewritten file.
+fn remove_hint(path: &Path) -> Result<()> {
+    match fs::remove_file(hint_path(path)) {
+        Ok(()) => Ok(sync_dir(path)?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Returns the path of the write-ahead log of the database file at `path`.
+fn wal_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".wal");
+    PathBuf::from(name)
+}
+
+/// Returns whether the files at `a` and `b` are on the same filesystem, which is always assumed
+/// to be the case on platforms other than Unix.
+fn same_filesystem(a: &Path, b: &Path) -> io::Result<bool> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Ok(fs::metadata(a)?.dev() == fs::metadata(b)?.dev())
+    }
+    #[cfg(not(unix))]
+    {
+        fs::metadata(a)?;
+        fs::metadata(b)?;
+        Ok(true)
+    }
+}
+
+/// Durably stores the changes made to the entries of the directory containing `path`, such as
+/// renames.
+///
+/// This is only needed, and possible, on Unix.
+fn sync_dir(path: &Path) -> io::Result<()> {
+    if cfg!(unix) {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Writes every buffer of `bufs` to `w`, in order, like [`io::Write::write_all`] does for a
+/// single buffer.
+fn write_all_vectored<W: io::Write>(w: &mut W, mut bufs: &mut [io::IoSlice<'_>]) -> io::Result<()> {
+    // Skip empty buffers, e.g. the value of tombstones.
+    io::IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match w.write_vectored(bufs) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => io::IoSlice::advance_slices(&mut bufs, n),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Opens the file at `path` with `options`, durably storing its directory entry if it's created.
+fn open_file(options: &fs::OpenOptions, path: &Path) -> io::Result<fs::File> {
+    let existed = path.exists();
+    let file = options.open(path)?;
+    if !existed {
+        sync_dir(path)?;
+    }
+    Ok(file)
+}
+
+/// Size of the chunks in which records are moved during compaction.
+const COMPACT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Returns the context of errors reading the value of `key` from its record at `offset`.
+fn read_context(key: &str, offset: usize, err: &dyn std::fmt::Display) -> String {
+    format!(
+        "unable to read value of key {:?} at offset {}: {}",
+        key, offset, err
+    )
+}
+
+/// Returns the number of the segment file at `path` (see [`MyDB::open_dir`]), if its name is a
+/// number.
+fn segment_number(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Returns whether `header` is made of zeros, which means that it's within space reserved by
+/// [`MyDB::reserve`] rather than an actual record, since records are written with a timestamp of
+/// at least [`MIN_TIMESTAMP`].
+fn is_reserved(header: &[u8]) -> bool {
+    header.iter().all(|&byte| byte == 0)
+}
+
+/// Seeks to `pos` within `seekable`, retrying if the call is interrupted.
+///
+/// Helpers such as [`io::Read::read_exact`] already retry interrupted calls but seeking doesn't,
+/// so a signal received at the wrong time would otherwise surface as an error.
+fn seek<T: io::Seek + ?Sized>(seekable: &mut T, pos: io::SeekFrom) -> io::Result<u64> {
+    loop {
+        match seekable.seek(pos) {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Truncates `storage` to `len` if it's longer, discarding an incomplete record at the end.
+///
+/// Returns what was discarded. Space reserved by [`MyDB::reserve`] is truncated too, but it isn't
+/// counted as a skipped record.
+fn truncate_torn_tail<S: Storage>(storage: &mut S, len: usize) -> Result<RecoveryReport> {
+    let end = seek(storage, io::SeekFrom::End(0))?;
+    if end <= len as u64 {
+        return Ok(RecoveryReport::default());
+    }
+
+    let mut header = [0; HEADER_SIZE];
+    let header = &mut header[..std::cmp::min(HEADER_SIZE as u64, end - len as u64) as usize];
+    seek(storage, io::SeekFrom::Start(len as u64))?;
+    storage.read_exact(header)?;
+    storage.set_len(len as u64)?;
+    storage.sync()?;
+
+    Ok(RecoveryReport {
+        bytes_truncated: end - len as u64,
+        records_skipped: usize::from(!is_reserved(header)),
+    })
+}
+
+/// Encodes the record of the keys file of a split database for the given `header` and `key`,
+/// where the value lives at `value_offset` within the values file.
+fn encode_key_record(header: &Header, key: &str, value_offset: usize) -> Vec<u8> {
+    let mut buf = header.encode();
+    buf.extend_from_slice(key.as_bytes());
+    buf.extend_from_slice(&(value_offset as u64).to_le_bytes());
+    buf
+}
+
+/// Creates the records of the key/value pairs of `iter` with `new_record`, in order, keeping only
+/// the last value of the keys which appear several times.
+fn last_values<I, K, V, F>(iter: I, mut new_record: F) -> Result<Vec<KeyValue>>
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+    F: FnMut(String, String) -> Result<KeyValue>,
+{
+    let mut kvs = Vec::new();
+    let mut indices = HashMap::new();
+    for (key, value) in iter {
+        let (key, value) = (key.as_ref().to_owned(), value.as_ref().to_owned());
+        let kv = new_record(key, value)?;
+        match indices.get(&kv.key) {
+            Some(&i) => kvs[i] = kv,
+            None => {
+                indices.insert(kv.key.clone(), kvs.len());
+                kvs.push(kv);
+            }
+        }
+    }
+    Ok(kvs)
+}
+
+/// Size of the random nonce of encrypted values, see [`MyDB::with_cipher`].
+#[cfg(feature = "encryption")]
+const NONCE_SIZE: usize = 12;
+
+/// Encrypts `value` with `cipher` and a random nonce, returning the hex-encoded nonce and
+/// encrypted value.
+#[cfg(feature = "encryption")]
+fn encrypt_value(cipher: &Aes256Gcm, value: &str) -> Result<String> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let encrypted = cipher
+        .encrypt(&nonce, value.as_bytes())
+        .map_err(|_| Error::ValueTooLong)?;
+
+    let mut hex = String::with_capacity(2 * (NONCE_SIZE + encrypted.len()));
+    for byte in nonce.iter().chain(&encrypted) {
+        hex.push(char::from_digit((byte >> 4) as u32, 16).unwrap());
+        hex.push(char::from_digit((byte & 0xf) as u32, 16).unwrap());
+    }
+    Ok(hex)
+}
+
+/// Decrypts a value encrypted by [`encrypt_value`].
+///
+/// Fails with [`Error::DecryptionFailed`] if `value` wasn't encrypted with `cipher`.
+#[cfg(feature = "encryption")]
+fn decrypt_value(cipher: &Aes256Gcm, value: &str) -> Result<String> {
+    let digits = value.as_bytes();
+    if !digits.len().is_multiple_of(2) || digits.len() < 2 * NONCE_SIZE {
+        return Err(Error::DecryptionFailed);
+    }
+    let bytes = digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).map_err(|_| Error::DecryptionFailed)?;
+            u8::from_str_radix(pair, 16).map_err(|_| Error::DecryptionFailed)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let (nonce, encrypted) = bytes.split_at(NONCE_SIZE);
+    let value = cipher
+        .decrypt(Nonce::from_slice(nonce), encrypted)
+        .map_err(|_| Error::DecryptionFailed)?;
+    Ok(String::from_utf8(value).map_err(|err| err.utf8_error())?)
+}
+
+/// Iterator over the keys and values of a database, see [`MyDB::iter`] and
+/// [`MyDB::iter_sequential`].
+pub struct Iter<'a, S: Storage> {
+    db: &'a mut MyDB<S>,
+    keys: std::vec::IntoIter<String>,
+}
+
+impl<S: Storage> Iterator for Iter<'_, S> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for key in self.keys.by_ref() {
+            match self.db.get(&key) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.keys.len()))
+    }
+}
+
+/// Read-only database borrowing the content of a database file, see [`MyDB::from_bytes`].
+pub struct ReadOnlyDB<'a> {
+    data: &'a [u8],
+    keydir: KeyDir,
+}
+
+impl ReadOnlyDB<'_> {
+    /// Gets the value associated with the given `key`, see [`MyDB::get`].
+    ///
+    /// Fails with [`Error::IoError`] of kind [`io::ErrorKind::Unsupported`] if the key has merge
+    /// operands (see [`MyDB::merge`]), which can't be merged without a merge operator.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        if self.keydir.1.contains_key(key) {
+            return Err(Error::IoError(io::ErrorKind::Unsupported.into()));
+        }
+        let entry = match self.keydir.0.get(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let start = entry.offset + entry.header_size as usize + entry.key_size as usize;
+        let value = &self.data[start..entry.offset + entry.size as usize];
+        Ok(Some(std::str::from_utf8(value)?.to_owned()))
+    }
+
+    /// Returns whether the database contains `key`.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.keydir.0.contains_key(key)
+    }
+
+    /// Returns the number of keys within the database.
+    pub fn len(&self) -> usize {
+        self.keydir.0.len()
+    }
+
+    /// Returns whether the database doesn't contain any key.
+    pub fn is_empty(&self) -> bool {
+        self.keydir.0.is_empty()
+    }
+}
+
+/// Database which can be shared between threads, e.g. within an [`std::sync::Arc`].
+///
+/// Every operation locks the database for its duration, so operations are serialized. If a
+/// thread panics while holding the lock, other threads still get to use the database.
+pub struct SharedDB<S: Storage = fs::File> {
+    db: Mutex<MyDB<S>>,
+    group_commit: Option<GroupCommit>,
+}
+
+/// Writes waiting to be synced together, see [`SharedDB::with_group_commit`].
+struct GroupCommit {
+    max_batch: usize,
+    max_delay: time::Duration,
+    state: Mutex<GroupCommitState>,
+    joined: Condvar,  // notified when a write joins the group
+    durable: Condvar, // notified when a group was synced
+}
+
+struct GroupCommitState {
+    written: u64, // number of writes made so far
+    synced: u64,  // number of writes known to be durable
+    syncing: bool,
+}
+
+impl GroupCommit {
+    fn state(&self) -> MutexGuard<'_, GroupCommitState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Waits until the first `seq` writes are durable, calling `sync` to make them durable if no
+    /// other thread is already doing it.
+    fn wait_durable<F: Fn() -> Result<()>>(&self, seq: u64, sync: F) -> Result<()> {
+        let mut state = self.state();
+        while state.synced < seq {
+            if state.syncing {
+                state = self
+                    .durable
+                    .wait(state)
+                    .unwrap_or_else(|err| err.into_inner());
+                continue;
+            }
+
+            // Lead the group, letting other writes join it for a while.
+            state.syncing = true;
+            let deadline = time::Instant::now() + self.max_delay;
+            while state.written - state.synced < self.max_batch as u64 {
+                let now = time::Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                state = self
+                    .joined
+                    .wait_timeout(state, deadline - now)
+                    .unwrap_or_else(|err| err.into_inner())
+                    .0;
+            }
+
+            let target = state.written;
+            drop(state);
+            let result = sync();
+            state = self.state();
+            state.syncing = false;
+            if result.is_ok() {
+                state.synced = target;
+            }
+            self.durable.notify_all();
+            result?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Storage> SharedDB<S> {
+    /// Wraps `db` to share it between threads.
+    pub fn new(db: MyDB<S>) -> Self {
+        SharedDB {
+            db: Mutex::new(db),
+            group_commit: None,
+        }
+    }
+
+    /// Enables group commit: writes made concurrently by several threads are synced together.
+    ///
+    /// Syncing after every write is costly, and writes made with [`SharedDB::set`] and
+    /// [`SharedDB::delete`] are serialized anyway. With group commit, they're not synced by the
+    /// database (its sync policy becomes [`SyncPolicy::Never`]). Instead, the first writer
+    /// waiting for its write to be durable waits up to `max_delay` for up to `max_batch` writes
+    /// in total, and then syncs all of them at once while the other writers wait. Each write
+    /// still returns only once it's durable. Writes made through [`SharedDB::lock`] aren't
+    /// synced, see [`MyDB::sync`].
+    pub fn with_group_commit(self, max_batch: usize, max_delay: time::Duration) -> Self {
+        self.lock().config.sync_policy = SyncPolicy::Never;
+        SharedDB {
+            group_commit: Some(GroupCommit {
+                max_batch,
+                max_delay,
+                state: Mutex::new(GroupCommitState {
+                    written: 0,
+                    synced: 0,
+                    syncing: false,
+                }),
+                joined: Condvar::new(),
+                durable: Condvar::new(),
+            }),
+            ..self
+        }
+    }
+
+    /// Makes the write `f` and returns once it's durable, within a group if group commit is
+    /// enabled (see [`SharedDB::with_group_commit`]).
+    fn write<T, F: FnOnce(&mut MyDB<S>) -> Result<T>>(&self, f: F) -> Result<T> {
+        let Some(group) = &self.group_commit else {
+            return f(&mut self.lock());
+        };
+
+        let (result, seq) = {
+            let mut db = self.lock();
+            let result = f(&mut db)?;
+            let mut state = group.state();
+            state.written += 1;
+            group.joined.notify_one();
+            (result, state.written)
+        };
+        group.wait_durable(seq, || self.lock().sync())?;
+        Ok(result)
+    }
+
+    /// Locks the database, to make several operations without other threads interleaving theirs.
+    pub fn lock(&self) -> MutexGuard<'_, MyDB<S>> {
+        self.db.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Gets the value associated with the given `key`, see [`MyDB::get`].
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.lock().get(key)
+    }
+
+    /// Sets the given `key` to `value`, see [`MyDB::set`].
+    pub fn set(&self, key: &str, value: &str) -> Result<bool> {
+        self.write(|db| db.set(key, value))
+    }
+
+    /// Deletes the given `key`, see [`MyDB::delete`].
+    pub fn delete(&self, key: &str) -> Result<bool> {
+        self.write(|db| db.delete(key))
+    }
+
+    /// Returns the wrapped database.
+    pub fn into_inner(self) -> MyDB<S> {
+        self.db.into_inner().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl SharedDB {
+    /// Compacts the database (see [`MyDB::compact`]) while letting other threads use it.
+    ///
+    /// Live records are copied to a new file without holding the lock, so that other threads
+    /// keep reading and writing the database file meanwhile. The lock is only held to list the
+    /// live records, and at the end to copy the records written during compaction before
+    /// switching to the new file. These records are copied as is, even if some of them were
+    /// overwritten since, so a later compaction might reclaim more space.
+    ///
+    /// The lock is held during the whole compaction if the database wasn't opened from a path,
+    /// is in split mode, has in-place updates enabled (see [`MyDB::with_in_place_updates`]) or
+    /// has sealed segments (see [`MyDB::open_dir`]), as well as when another thread rewrites the
+    /// database file during compaction.
+    pub fn compact(&self) -> Result<()> {
+        let (path, temp_path, rewrites, end, mut live) = {
+            let mut db = self.lock();
+            db.check_writable()?;
+            match db.path.clone() {
+                Some(path)
+                    if db.values.is_none()
+                        && !db.config.in_place_updates
+                        && db.keydir.1.is_empty()
+                        && db.sealed.is_empty() =>
+                {
+                    // Logged writes must be in the database file to be copied.
+                    db.checkpoint()?;
+                    let live: Vec<_> = db
+                        .keydir
+                        .0
+                        .iter()
+                        .map(|(key, entry)| (key.clone(), entry.offset, entry.size as usize))
+                        .collect();
+                    let temp_path = compaction_path(&path, db.config.temp_dir.as_deref());
+                    (path, temp_path, db.rewrites, db.offset, live)
+                }
+                _ => return db.compact(),
+            }
+        };
+        live.sort_unstable_by_key(|&(_, offset, _)| offset);
+
+        // The database file is read with a handle of our own so that other threads can still
+        // use the database's.
+        let mut io = IoCounters::default();
+        let mut file = fs::File::open(&path)?;
+        let mut compacted = io::BufWriter::new(<fs::File as Storage>::create(&temp_path)?);
+        let mut offsets = HashMap::with_capacity(live.len());
+        let mut buf = Vec::new();
+        let mut len = 0;
+        for (key, offset, size) in live {
+            buf.resize(size, 0);
+            seek(&mut file, io::SeekFrom::Start(offset as u64))?;
+            file.read_exact(&mut buf)?;
+            io.read(size);
+            compacted.write_all(&buf)?;
+            io.write(size);
+            offsets.insert(key, len);
+            len += size;
+        }
+
+        let mut guard = self.lock();
+        let db = &mut *guard;
+        if db.rewrites != rewrites || db.config.in_place_updates || !db.keydir.1.is_empty() {
+            drop(compacted);
+            fs::remove_file(&temp_path)?;
+            return db.compact();
+        }
+        db.checkpoint()?;
+
+        // Copy the records written during compaction.
+        let tail = db.offset - end;
+        seek(&mut file, io::SeekFrom::Start(end as u64))?;
+        let copied = io::copy(&mut (&mut file).take(tail as u64), &mut compacted)?;
+        if copied != tail as u64 {
+            return Err(Error::IoError(io::ErrorKind::UnexpectedEof.into()));
+        }
+        io.read(tail);
+        io.write(tail);
+        let compacted = compacted.into_inner().map_err(|err| err.into_error())?;
+        compacted.sync_all()?;
+        db.replace_file(&temp_path, &path, compacted)?;
+
+        // Records before `end` which are still live weren't written during compaction, so they
+        // are the ones which were copied.
+        for (key, entry) in db.keydir.0.iter_mut() {
+            entry.offset = match entry.offset.checked_sub(end) {
+                Some(offset) => len + offset,
+                None => offsets[key],
+            };
+        }
+        db.offset = len + tail;
+        db.io.add(io);
+
+        Ok(())
+    }
+}
+
+/// Collects key/value pairs into a temporary database, see [`MyDB::try_from_iter`].
+///
+/// # Panics
+///
+/// Panics if the temporary database can't be created or written, or if a pair is invalid (e.g.
+/// its key is too long).
+impl FromIterator<(String, String)> for MyDB {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        let mut db = MyDB::open_temp().expect("unable to create temporary database");
+        db.extend(iter)
+            .expect("unable to write to temporary database");
+        db
+    }
+}
+
+impl<S: Storage> Drop for MyDB<S> {
+    fn drop(&mut self) {
+        if let Some(background_sync) = self.background_sync.take() {
+            background_sync.stop();
+            // Errors can't be reported when dropping.
+            if !self.config.skip_drop_sync {
+                let _ = self.sync();
+            }
+        }
+        if self.config.skip_drop_sync {
+            let _ = self.file.flush();
+            if let Some(values) = &mut self.values {
+                let _ = values.flush();
+            }
+        } else {
+            let _ = self.checkpoint();
+        }
+
+        #[cfg(feature = "log")]
+        if self.unsynced_bytes > 0 && !self.config.skip_drop_sync {
+            log::warn!(
+                "database {:?} dropped with {} unsynced bytes, use MyDB::close to sync them",
+                self.path,
+                self.unsynced_bytes
+            );
+        }
+
+        if let Some(path) = self.path.as_ref().filter(|_| self.temporary) {
+            // Nothing can be done if this fails and it's only a temporary file anyway.
+            let _ = fs::remove_file(path);
+            let _ = fs::remove_file(hint_path(path));
+        }
+    }
+}
+
+/// Appends `field` to `row` as a CSV field, quoting it if needed.
+fn push_csv_field(row: &mut String, field: &str) {
+    if !field.contains([',', '"', '\n', '\r']) {
+        row.push_str(field);
+        return;
+    }
+    row.push('"');
+    row.push_str(&field.replace('"', "\"\""));
+    row.push('"');
+}
+
+/// Parses the rows of `csv`, whose lines might end with either `\n` or `\r\n`.
+fn parse_csv(csv: &str) -> Result<Vec<Vec<String>>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+
+    let mut chars = csv.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => (),
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c => field.push(c),
+        }
+    }
+
+    if quoted {
+        return Err(Error::DecodeError(
+            "unterminated quoted csv field".to_string(),
+        ));
+    }
+    // The last row might not end with a line break.
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+
+    Ok(rows)
+}
+
+/// Lookup table of the CRC-32 (IEEE) checksum, see [`Crc32`].
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Returns the 64-bit FNV-1a hash of `value`, see [`MyDB::value_hash`].
+fn hash_value(value: &[u8]) -> u64 {
+    value.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Bloom filter of the keys of a sealed segment, see [`MyDB::with_bloom_filters`].
+///
+/// Each key sets a few bits picked from its FNV-1a hash by double hashing. A key whose bits
+/// aren't all set certainly isn't in the filter, while other keys probably are.
+struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,         // number of bits set by each key
+    bits_per_key: usize, // as given to `with_bloom_filters`
+}
+
+impl BloomFilter {
+    fn new<'a>(keys: impl ExactSizeIterator<Item = &'a String>, bits_per_key: usize) -> Self {
+        let len = (keys.len() * bits_per_key).max(1);
+        let mut filter = BloomFilter {
+            bits: vec![0; len.div_ceil(64)],
+            // ln(2) bits per key minimize false positives.
+            hashes: ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30),
+            bits_per_key,
+        };
+        for key in keys {
+            for bit in filter.bits_of(key) {
+                filter.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        filter
+    }
+
+    fn may_contain(&self, key: &str) -> bool {
+        self.bits_of(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Returns the bits set by `key`.
+    fn bits_of(&self, key: &str) -> impl Iterator<Item = usize> {
+        let hash = hash_value(key.as_bytes());
+        let (h1, h2) = (hash, hash.rotate_left(32) | 1);
+        let len = self.bits.len() as u64 * 64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+/// Incremental CRC-32 (IEEE) checksum, the one used by zip, gzip and PNG among others.
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Self {
+        Crc32(!0)
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        for &byte in buf {
+            self.0 = CRC32_TABLE[((self.0 ^ byte as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+fn now_timestamp() -> Result<u32> {
+    to_timestamp(time::SystemTime::now())
+}
+
+/// Converts `time` to a record timestamp, i.e. seconds since the Unix epoch.
+///
+/// Fails with [`Error::ClockError`] if `time` is before the epoch or too far in the future.
+fn to_timestamp(time: time::SystemTime) -> Result<u32> {
+    let time = time
+        .duration_since(time::UNIX_EPOCH)
+        .map_err(|err| Error::ClockError(format!("clock is before the unix epoch: {}", err)))?;
+    time.as_secs().try_into().map_err(|_| {
+        Error::ClockError(format!(
+            "clock is too far in the future: {}s",
+            time.as_secs()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::distributions::{Alphanumeric, DistString};
+    use rand::random;
+
+    fn assert_header_encode(header: Header) {
+        let encoded = header.encode();
+        let decoded = Header::decode(&encoded).unwrap();
+        assert_eq!(header, decoded);
+    }
+
+    fn assert_keyvalue_encode(kv: KeyValue) {
+        let encoded = kv.encode();
+        let decoded = KeyValue::decode(&encoded).unwrap();
+        assert_eq!(kv, decoded);
+    }
+
+    #[test]
+    fn test_header() {
+        let headers = [
+            Header {
+                timestamp: 10,
+                key_size: 10,
+                value_size: 10,
+                flags: 0,
+            },
+            Header {
+                timestamp: 0,
+                key_size: 0,
+                value_size: 0,
+                flags: 0,
+            },
+            Header {
+                timestamp: 10000,
+                key_size: 10000,
+                value_size: 10000,
+                flags: 0,
+            },
+        ];
+
+        for header in headers {
+            assert_header_encode(header);
+        }
+    }
+
+    #[test]
+    fn test_header_random() {
+        for _ in 0..100 {
+            let header = Header {
                 timestamp: random(),
-                key_size: random(),
+                key_size: random::<u32>() & MAX_KEY_SIZE,
                 value_size: random(),
+                flags: random(),
             };
             assert_header_encode(header);
         }
     }
 
+    #[test]
+    fn test_header_flags() {
+        let header = Header {
+            timestamp: 10,
+            key_size: MAX_KEY_SIZE,
+            value_size: 10,
+            flags: 0b1000_0101,
+        };
+        let encoded = header.encode();
+        assert_eq!(encoded[4..8], [0xff, 0xff, 0xff, 0b1000_0101]);
+        assert_header_encode(header);
+
+        let mut kv = KeyValue::new(10, "hello".to_string(), "world".to_string()).unwrap();
+        assert_eq!(kv.flags, 0);
+        kv.flags = 0b0100_0010;
+        assert_eq!(kv.header().flags, 0b0100_0010);
+        assert_keyvalue_encode(kv);
+
+        assert!(check_key_len(MAX_KEY_SIZE as usize).is_ok());
+        assert!(matches!(
+            check_key_len(MAX_KEY_SIZE as usize + 1),
+            Err(Error::KeyTooLong)
+        ));
+    }
+
+    #[test]
+    fn test_header_extra_fields() {
+        // A header written by a future version, with two more fields than we know of.
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&10u32.to_le_bytes());
+        buf.extend_from_slice(&(5u32 | 2 << 24).to_le_bytes());
+        buf.extend_from_slice(&5u32.to_le_bytes());
+        buf.extend_from_slice(&[0xab; 8]);
+        buf.extend_from_slice(b"helloworld");
+
+        let header = Header::decode(&buf).unwrap();
+        assert_eq!(
+            (header.timestamp, header.key_size, header.value_size),
+            (10, 5, 5)
+        );
+        assert_eq!(header.len(), HEADER_SIZE + 8);
+        assert_eq!(header.record_size().unwrap(), buf.len());
+
+        let kv = KeyValue::decode(&buf).unwrap();
+        assert_eq!(kv.key, "hello");
+        assert_eq!(kv.value.as_deref(), Some("world"));
+
+        // Opening a database skips the extra fields too.
+        let mut db = MyDB::new_from_storage(io::Cursor::new(buf)).unwrap();
+        assert_eq!(db.get("hello").unwrap(), Some("world".to_string()));
+        db.check_invariants().unwrap();
+
+        assert!(matches!(
+            Header::decode(&[0; HEADER_SIZE - 1]),
+            Err(Error::DecodeError(_))
+        ));
+    }
+
+    #[test]
+    fn test_keyvalue_decode_huge_sizes() {
+        // On 32-bit targets, adding these sizes would overflow `usize`.
+        let header = Header {
+            timestamp: 10,
+            key_size: MAX_KEY_SIZE,
+            value_size: TOMBSTONE - 1,
+            flags: 0,
+        };
+        let expected = HEADER_SIZE as u64 + MAX_KEY_SIZE as u64 + (TOMBSTONE - 1) as u64;
+        match header.record_size() {
+            Ok(size) => assert_eq!(size as u64, expected),
+            Err(err) => assert!(matches!(err, Error::DecodeError(_))),
+        }
+
+        let mut buf = header.encode();
+        buf.extend_from_slice(b"hello");
+        assert!(matches!(KeyValue::decode(&buf), Err(Error::DecodeError(_))));
+    }
+
     #[test]
     fn test_keyvalue() {
         let kvs = [
             KeyValue::new(10, "hello".to_string(), "world".to_string()).unwrap(),
             KeyValue::new(0, "".to_string(), "".to_string()).unwrap(),
+            KeyValue::tombstone(10, "hello".to_string()).unwrap(),
         ];
 
         for kv in kvs {
@@ -353,6 +5375,175 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_tombstone() {
+        let kv = KeyValue::tombstone(10, "hello".to_string()).unwrap();
+        let encoded = kv.encode();
+        assert_eq!(encoded.len(), HEADER_SIZE + "hello".len());
+
+        let header = Header::decode(&encoded[..HEADER_SIZE]).unwrap();
+        assert!(header.is_tombstone());
+        assert_eq!(header.value_len(), 0);
+
+        let decoded = KeyValue::decode(&encoded).unwrap();
+        assert_eq!(decoded.value, None);
+
+        // An empty value isn't a tombstone.
+        let kv = KeyValue::new(10, "hello".to_string(), "".to_string()).unwrap();
+        let encoded = kv.encode();
+        assert!(!Header::decode(&encoded[..HEADER_SIZE])
+            .unwrap()
+            .is_tombstone());
+        assert_eq!(
+            KeyValue::decode(&encoded).unwrap().value,
+            Some("".to_string())
+        );
+    }
+
+    #[test]
+    fn test_tombstone_collision() {
+        // Real values can't have the size reserved for tombstones.
+        assert!(check_value_len(TOMBSTONE as usize - 1).is_ok());
+        assert!(matches!(
+            check_value_len(TOMBSTONE as usize),
+            Err(Error::ValueTooLong)
+        ));
+        assert!(matches!(
+            check_value_len(usize::MAX),
+            Err(Error::ValueTooLong)
+        ));
+    }
+
+    #[test]
+    fn test_open_temp() {
+        let mut db = MyDB::open_temp().unwrap();
+        db.set("hello", "world").unwrap();
+        assert_eq!(db.get("hello").unwrap(), Some("world".to_string()));
+
+        let path = db.path().unwrap().to_owned();
+        assert!(path.exists());
+
+        let mut other = MyDB::open_temp().unwrap();
+        assert_ne!(other.path(), db.path());
+        assert_eq!(other.get("hello").unwrap(), None);
+
+        drop(db);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let storage = io::Cursor::new(Vec::new());
+        let mut db = MyDB::new_from_storage(storage).unwrap();
+        db.check_invariants().unwrap();
+
+        db.set("hello", "world").unwrap();
+        db.set("foo", "bar").unwrap();
+        db.set("hello", "mars").unwrap();
+        db.delete("foo").unwrap();
+        db.check_invariants().unwrap();
+
+        db.keydir.0.get_mut("hello").unwrap().offset = 0;
+        assert!(matches!(db.check_invariants(), Err(Error::Corruption(_))));
+
+        db.keydir.0.get_mut("hello").unwrap().offset = 1;
+        assert!(matches!(db.check_invariants(), Err(Error::Corruption(_))));
+    }
+
+    #[test]
+    fn test_check_invariants_split() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = MyDB::new_split(dir.path().join("split.db")).unwrap();
+
+        db.set("hello", "world").unwrap();
+        db.set("foo", "bar").unwrap();
+        db.check_invariants().unwrap();
+
+        db.keydir.0.get_mut("hello").unwrap().offset = 100;
+        assert!(matches!(db.check_invariants(), Err(Error::Corruption(_))));
+    }
+
+    #[test]
+    fn test_keydir_load_capacity() {
+        let mut storage = io::Cursor::new(Vec::new());
+        for i in 0..1000 {
+            let kv = KeyValue::new(10, format!("key-{}", i % 10), i.to_string()).unwrap();
+            storage.get_mut().extend(kv.encode());
+        }
+        for i in 10..1000 {
+            storage.get_mut().extend(
+                KeyValue::new(10, format!("key-{}", i), String::new())
+                    .unwrap()
+                    .encode(),
+            );
+            storage.get_mut().extend(
+                KeyValue::tombstone(10, format!("key-{}", i))
+                    .unwrap()
+                    .encode(),
+            );
+        }
+
+        let (keydir, stats) = KeyDir::load(&mut storage, false, None).unwrap();
+        assert_eq!(stats.records_scanned, 2980);
+        assert_eq!(keydir.0.len(), 10);
+        assert!(keydir.0.capacity() < 32, "capacity {}", keydir.0.capacity());
+    }
+
+    #[test]
+    fn test_crc32() {
+        let mut crc = Crc32::new();
+        assert_eq!(crc.finish(), 0);
+
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_compact_interrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("compact.db");
+
+        let mut db = MyDB::new(&path).unwrap();
+        db.set("hello", "world").unwrap();
+        db.set("foo", "bar").unwrap();
+        db.set("hello", "mars").unwrap();
+
+        // Crash right before the compacted file replaces the database file.
+        let temp_path = compaction_path(&path, None);
+        drop(db.write_compacted(&temp_path, &mut |_| {}).unwrap());
+        drop(db);
+        assert!(temp_path.exists());
+
+        let mut db = MyDB::new(&path).unwrap();
+        assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+        assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+        assert_eq!(db.history("hello").unwrap(), ["world", "mars"]);
+
+        // Compacting again replaces the leftover file.
+        db.compact().unwrap();
+        assert!(!temp_path.exists());
+        assert_eq!(db.history("hello").unwrap(), ["mars"]);
+        assert_eq!(db.disk_size(), fs::metadata(&path).unwrap().len());
+        db.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let rows = parse_csv("a,b\r\n\"c,\"\"d\"\"\",\"\"\n,\"e\nf\"").unwrap();
+        assert_eq!(
+            rows,
+            [vec!["a", "b"], vec!["c,\"d\"", ""], vec!["", "e\nf"]]
+        );
+        assert!(parse_csv("").unwrap().is_empty());
+        assert!(matches!(parse_csv("\"a,b"), Err(Error::DecodeError(_))));
+
+        let mut row = String::new();
+        push_csv_field(&mut row, "plain");
+        push_csv_field(&mut row, "with \"quotes\", commas");
+        assert_eq!(row, "plain\"with \"\"quotes\"\", commas\"");
+    }
+
     #[test]
     fn test_keyvalue_random() {
         for _ in 0..100 {
@@ -366,4 +5557,41 @@ mod tests {
             assert_keyvalue_encode(kv);
         }
     }
+
+    #[test]
+    fn test_bloom_filter() {
+        let keys: Vec<_> = (0..1000).map(|i| format!("key-{}", i)).collect();
+        let filter = BloomFilter::new(keys.iter(), 10);
+        assert!(keys.iter().all(|key| filter.may_contain(key)));
+
+        let false_positives = (0..1000)
+            .filter(|i| filter.may_contain(&format!("other-{}", i)))
+            .count();
+        assert!(false_positives < 50, "{} false positives", false_positives);
+
+        let empty = BloomFilter::new([].iter(), 10);
+        assert!(!empty.may_contain("key"));
+    }
+
+    #[test]
+    fn test_key_verification() {
+        let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+        db.set("first", "value 1").unwrap();
+        db.set("other", "value 2").unwrap();
+
+        // Make the entry of the second key point to the record of the first one.
+        let offset = db.keydir.0["first"].offset;
+        db.keydir.0.get_mut("other").unwrap().offset = offset;
+        assert_eq!(db.get("other").unwrap(), Some("value 1".to_string()));
+
+        let mut db = db.with_key_verification(true);
+        assert_eq!(db.get("first").unwrap(), Some("value 1".to_string()));
+        match db.get("other") {
+            Err(Error::Corruption(message)) => {
+                assert!(message.contains("\"other\""), "{}", message);
+                assert!(message.contains("\"first\""), "{}", message);
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
 }
@@ -0,0 +1,90 @@
+//! Crash consistency tests.
+//!
+//! A crash in the middle of a write leaves a partially written record at the end of the database
+//! file. We simulate this by truncating the file at every possible offset and checking that the
+//! database always opens to the state it had after one of the writes that completed.
+
+use mydb::MyDB;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+enum Op {
+    Set(&'static str, &'static str),
+    Delete(&'static str),
+}
+
+const OPS: &[Op] = &[
+    Op::Set("hello", "world"),
+    Op::Set("foo", "bar"),
+    Op::Set("hello", "mars"),
+    Op::Set("", ""),
+    Op::Delete("foo"),
+    Op::Set("unicode", "héllo wörld"),
+    Op::Set("foo", "baz"),
+    Op::Delete("hello"),
+    Op::Set("long", "a much longer value than the other ones"),
+];
+
+type State = HashMap<String, String>;
+
+/// Applies `OPS` to a new database and returns the database file content, along with the file
+/// length and the expected state after each write (starting with the empty database).
+fn write_ops() -> (Vec<u8>, Vec<(usize, State)>) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("crash.db");
+    let mut db = MyDB::new(&path).unwrap();
+
+    let mut state = State::new();
+    let mut checkpoints = vec![(0, state.clone())];
+    for op in OPS {
+        match op {
+            Op::Set(key, value) => {
+                db.set(key, value).unwrap();
+                state.insert(key.to_string(), value.to_string());
+            }
+            Op::Delete(key) => {
+                db.delete(key).unwrap();
+                state.remove(*key);
+            }
+        }
+        let len = fs::metadata(&path).unwrap().len() as usize;
+        checkpoints.push((len, state.clone()));
+    }
+
+    (fs::read(&path).unwrap(), checkpoints)
+}
+
+fn assert_state(db: &mut MyDB<io::Cursor<Vec<u8>>>, state: &State) {
+    assert_eq!(db.len(), state.len());
+    for (key, value) in state {
+        assert_eq!(db.get(key).unwrap().as_ref(), Some(value));
+    }
+    db.check_invariants().unwrap();
+}
+
+#[test]
+fn test_truncate_everywhere() {
+    let (data, checkpoints) = write_ops();
+    assert_eq!(checkpoints.last().unwrap().0, data.len());
+
+    for len in 0..=data.len() {
+        // The state is the one after the last write which fully fits.
+        let (_, state) = checkpoints
+            .iter()
+            .rev()
+            .find(|(checkpoint, _)| *checkpoint <= len)
+            .unwrap();
+
+        let storage = io::Cursor::new(data[..len].to_vec());
+        let mut db = MyDB::new_from_storage(storage)
+            .unwrap_or_else(|err| panic!("unable to open file truncated at {}: {:?}", len, err));
+        assert_state(&mut db, state);
+
+        // The database keeps working after recovery.
+        let mut state = state.clone();
+        db.set("after", "crash").unwrap();
+        state.insert("after".to_string(), "crash".to_string());
+        assert_state(&mut db, &state);
+    }
+}
@@ -1,6 +1,20 @@
-use mydb::MyDB;
+use mydb::{
+    estimate_records, format, fuzz, BatchReport, DecodeMode, EntryMeta, Error, IoCounters,
+    KeyNormalizer, LogReader, MyDB, OpenOptions, ReadSource, RecoveryReport, SharedDB,
+    SizeHistogram, Storage, SyncPolicy,
+};
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs;
+use std::io;
 use std::path;
+use std::rc::Rc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time;
 
 #[test]
 fn test_basic() {
@@ -9,7 +23,7 @@ fn test_basic() {
         .tempfile()
         .unwrap()
         .into_file();
-    let mut db = MyDB::new_from_file(file).unwrap();
+    let mut db = MyDB::new_from_storage(file).unwrap();
 
     assert_eq!(db.get("unknown_key").unwrap(), None);
 
@@ -38,12 +52,3517 @@ fn test_load() {
     db.set("foo", "bar").unwrap();
     db.set("bar", "foo").unwrap();
     db.set("hello", "mars").unwrap();
-    drop(db);
 
-    let mut db = MyDB::new(filename).unwrap();
+    let mut db = db.reopen().unwrap();
     assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
     assert_eq!(db.get("bar").unwrap(), Some("foo".to_string()));
     assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
 
     fs::remove_file(filename).unwrap();
 }
+
+#[test]
+fn test_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("reopen.db");
+
+    let mut db = MyDB::new_split(&path)
+        .unwrap()
+        .with_sync_policy(SyncPolicy::Never)
+        .with_skip_redundant_writes(true);
+    db.set("hello", "world").unwrap();
+    let mut db = db.reopen().unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("world".to_string()));
+    assert!(!db.set("hello", "world").unwrap());
+    db.check_invariants().unwrap();
+    assert!(path.with_extension("keys").exists());
+    drop(db);
+
+    let mut db = MyDB::open_temp().unwrap();
+    let temp_path = db.path().unwrap().to_owned();
+    db.set("hello", "world").unwrap();
+    let mut db = db.reopen().unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("world".to_string()));
+    drop(db);
+    assert!(!temp_path.exists());
+
+    let db = MyDB::new_from_storage(tempfile::tempfile().unwrap()).unwrap();
+    match db.reopen() {
+        Err(Error::IoError(err)) => assert_eq!(err.kind(), io::ErrorKind::Unsupported),
+        Err(err) => panic!("unexpected error: {:?}", err),
+        Ok(_) => panic!("database without a path was reopened"),
+    }
+}
+
+#[test]
+fn test_reopen_dir() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut db = MyDB::open_dir(dir.path()).unwrap();
+    db.set("old", "1").unwrap();
+    db.roll_segment().unwrap();
+    db.set("new", "2").unwrap();
+
+    // Sealed segments are opened again along with the active one.
+    let mut db = db.reopen().unwrap();
+    assert_eq!(db.path(), Some(dir.path().join("1.db").as_path()));
+    assert_eq!(db.len(), 2);
+    assert_eq!(db.get("old").unwrap(), Some("1".to_string()));
+    assert_eq!(db.get("new").unwrap(), Some("2".to_string()));
+    db.roll_segment().unwrap();
+    assert_eq!(db.path(), Some(dir.path().join("2.db").as_path()));
+}
+
+#[test]
+fn test_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("path.db");
+
+    let db = MyDB::new(&path).unwrap();
+    assert_eq!(db.path(), Some(path.as_path()));
+
+    let db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    assert_eq!(db.path(), None);
+}
+
+#[test]
+fn test_open_with_base() {
+    let dir = tempfile::tempdir().unwrap();
+    let base_path = dir.path().join("base.db");
+    let overlay_path = dir.path().join("overlay.db");
+
+    let mut base = MyDB::new(&base_path).unwrap();
+    base.set("base", "only").unwrap();
+    base.set("shadowed", "base").unwrap();
+    let base_len = fs::metadata(&base_path).unwrap().len();
+
+    let mut db = MyDB::open_with_base(&overlay_path, base).unwrap();
+    db.set("overlay", "only").unwrap();
+    db.set("shadowed", "overlay").unwrap();
+    assert_eq!(db.get("base").unwrap(), Some("only".to_string()));
+    assert_eq!(db.get("overlay").unwrap(), Some("only".to_string()));
+    assert_eq!(db.get("shadowed").unwrap(), Some("overlay".to_string()));
+    assert_eq!(db.get("missing").unwrap(), None);
+
+    db.delete("shadowed").unwrap();
+    assert_eq!(db.get("shadowed").unwrap(), Some("base".to_string()));
+    drop(db);
+
+    assert_eq!(fs::metadata(&base_path).unwrap().len(), base_len);
+    let mut overlay = MyDB::new(&overlay_path).unwrap();
+    assert_eq!(overlay.get("base").unwrap(), None);
+    assert_eq!(overlay.get("overlay").unwrap(), Some("only".to_string()));
+}
+
+#[test]
+fn test_read_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("read_only.db");
+
+    let mut writer = MyDB::new(&path).unwrap();
+    writer.set("hello", "world").unwrap();
+
+    let mut reader = MyDB::open_read_only(&path).unwrap().with_read_cache(10);
+    assert_eq!(reader.get("hello").unwrap(), Some("world".to_string()));
+    assert!(!reader.reload().unwrap());
+
+    writer.set("foo", "bar").unwrap();
+    writer.set("hello", "mars").unwrap();
+    assert_eq!(reader.get("foo").unwrap(), None);
+    assert_eq!(reader.get("hello").unwrap(), Some("world".to_string()));
+
+    assert!(reader.reload().unwrap());
+    assert_eq!(reader.get("foo").unwrap(), Some("bar".to_string()));
+    assert_eq!(reader.get("hello").unwrap(), Some("mars".to_string()));
+
+    writer.delete("foo").unwrap();
+    assert!(reader.reload().unwrap());
+    assert_eq!(reader.get("foo").unwrap(), None);
+    assert_eq!(reader.len(), 1);
+    reader.check_invariants().unwrap();
+
+    assert!(matches!(reader.set("foo", "baz"), Err(Error::ReadOnly)));
+    assert!(matches!(reader.delete("hello"), Err(Error::ReadOnly)));
+    assert!(matches!(reader.compact(), Err(Error::ReadOnly)));
+
+    // A record which is still being written is skipped until it's complete.
+    drop(writer);
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    io::Write::write_all(&mut file, &[1, 2, 3]).unwrap();
+    assert!(!reader.reload().unwrap());
+    assert_eq!(reader.get("hello").unwrap(), Some("mars".to_string()));
+}
+
+#[test]
+fn test_get_or() {
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    db.set("hello", "world").unwrap();
+    let disk_size = db.disk_size();
+
+    assert_eq!(db.get_or("hello", "default").unwrap(), "world");
+    assert_eq!(db.get_or("foo", "default").unwrap(), "default");
+    assert_eq!(db.get("foo").unwrap(), None);
+    assert_eq!(db.disk_size(), disk_size);
+}
+
+#[test]
+fn test_get_sorted() {
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    db.set("bar", "foo").unwrap();
+
+    let values = db.get_sorted(&["hello", "missing", "bar", "foo"]).unwrap();
+    let values: Vec<_> = values
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    assert_eq!(values, [("bar", "foo"), ("foo", "bar"), ("hello", "world")]);
+
+    assert!(db.get_sorted(&["missing"]).unwrap().is_empty());
+}
+
+#[test]
+fn test_get_required() {
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    db.set("hello", "world").unwrap();
+
+    assert_eq!(db.get_required("hello").unwrap(), "world");
+    match db.get_required("foo") {
+        Err(Error::KeyNotFound(key)) => assert_eq!(key, "foo"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn test_clock_before_epoch() {
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new()))
+        .unwrap()
+        .with_clock(|| time::UNIX_EPOCH - time::Duration::from_secs(1));
+
+    assert!(matches!(db.set("key", "value"), Err(Error::ClockError(_))));
+    assert_eq!(db.get("key").unwrap(), None);
+    assert!(db.is_empty());
+    db.check_invariants().unwrap();
+}
+
+#[test]
+fn test_keys_changed_since() {
+    let now = Arc::new(AtomicU64::new(1000));
+    let clock = {
+        let now = now.clone();
+        move || time::UNIX_EPOCH + time::Duration::from_secs(now.load(Ordering::SeqCst))
+    };
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new()))
+        .unwrap()
+        .with_clock(clock);
+
+    db.set("old", "value").unwrap();
+    db.set("overwritten", "value").unwrap();
+    db.set("deleted", "value").unwrap();
+    now.store(2000, Ordering::SeqCst);
+    db.set("new", "value").unwrap();
+    db.set("overwritten", "new value").unwrap();
+    db.delete("deleted").unwrap();
+    now.store(3000, Ordering::SeqCst);
+    db.set("newest", "value").unwrap();
+
+    let changed = |db: &MyDB<_>, ts| {
+        let mut keys: Vec<_> = db.keys_changed_since(ts).map(str::to_owned).collect();
+        keys.sort();
+        keys
+    };
+    assert_eq!(changed(&db, 0), ["new", "newest", "old", "overwritten"]);
+    assert_eq!(changed(&db, 1001), ["new", "newest", "overwritten"]);
+    assert_eq!(changed(&db, 2000), ["new", "newest", "overwritten"]);
+    assert_eq!(changed(&db, 2001), ["newest"]);
+    assert!(changed(&db, 3001).is_empty());
+    assert_eq!(db.metadata("newest").unwrap().timestamp, 3000);
+}
+
+#[test]
+fn test_load_stats() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("load_stats.db");
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.load_stats().records_scanned, 0);
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    db.set("hello", "mars").unwrap();
+    db.delete("foo").unwrap();
+    drop(db);
+
+    let db = MyDB::new(&path).unwrap();
+    let stats = db.load_stats();
+    assert_eq!(stats.records_scanned, 4);
+    assert_eq!(
+        stats.bytes_scanned as u64,
+        fs::metadata(&path).unwrap().len()
+    );
+}
+
+#[test]
+fn test_iter() {
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    assert_eq!(db.iter().count(), 0);
+
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    db.set("hello", "mars").unwrap();
+    db.set("bar", "foo").unwrap();
+    db.delete("bar").unwrap();
+
+    let mut items: Vec<_> = db.iter().map(Result::unwrap).collect();
+    items.sort();
+    assert_eq!(
+        items,
+        [
+            ("foo".to_string(), "bar".to_string()),
+            ("hello".to_string(), "mars".to_string())
+        ]
+    );
+}
+
+#[test]
+fn test_values() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("values.db");
+
+    let mut db = MyDB::new(&path).unwrap();
+    db.extend([("hello", "world"), ("foo", "bar"), ("bar", "foo")])
+        .unwrap();
+    db.set("hello", "mars").unwrap();
+    db.delete("bar").unwrap();
+
+    let mut values: Vec<_> = db.values().map(Result::unwrap).collect();
+    values.sort();
+    assert_eq!(values, ["bar", "mars"]);
+    drop(db);
+
+    // Corrupt the value of "foo", other values can still be read.
+    let mut bytes = fs::read(&path).unwrap();
+    let offset = bytes.windows(3).position(|bytes| bytes == b"bar").unwrap();
+    bytes[offset] = 0xff;
+    fs::write(&path, bytes).unwrap();
+
+    let mut db = MyDB::new(&path).unwrap();
+    let values: Vec<_> = db.values().collect();
+    assert_eq!(values.len(), 2);
+    assert_eq!(values.iter().filter(|value| value.is_err()).count(), 1);
+    assert!(values
+        .iter()
+        .any(|value| value.as_deref().ok() == Some("mars")));
+}
+
+#[test]
+fn test_iter_sequential() {
+    let (storage, counters) = CountingStorage::new();
+    let mut db = MyDB::new_from_storage(storage).unwrap();
+    for i in 0..50 {
+        db.set(&format!("key-{}", i), &format!("value-{}", i))
+            .unwrap();
+    }
+    for i in (0..50).step_by(3) {
+        db.set(&format!("key-{}", i), "updated").unwrap();
+    }
+
+    counters.seeks.borrow_mut().clear();
+    let mut pairs = db.iter_sequential().collect::<Result<Vec<_>, _>>().unwrap();
+    let seeks = counters.seeks.borrow().clone();
+    assert_eq!(seeks.len(), 50);
+    assert!(seeks.windows(2).all(|w| w[0] < w[1]), "{:?}", seeks);
+
+    let mut expected = db.iter().collect::<Result<Vec<_>, _>>().unwrap();
+    pairs.sort();
+    expected.sort();
+    assert_eq!(pairs, expected);
+}
+
+#[test]
+fn test_for_each() {
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    db.extend((0..100).map(|i| (format!("key-{}", i), "x".repeat(i))))
+        .unwrap();
+    db.set("key-0", "overwritten").unwrap();
+    db.delete("key-1").unwrap();
+
+    let mut total = 0;
+    let mut count = 0;
+    db.for_each(|key, value| {
+        assert!(key.starts_with("key-"));
+        total += value.len();
+        count += 1;
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(count, 99);
+    assert_eq!(total, (2..100).sum::<usize>() + "overwritten".len());
+
+    // Errors returned by the visitor stop the scan.
+    let mut visited = 0;
+    let result = db.for_each(|_, _| {
+        visited += 1;
+        Err(Error::KeyNotFound("stop".to_string()))
+    });
+    assert!(matches!(result, Err(Error::KeyNotFound(_))));
+    assert_eq!(visited, 1);
+}
+
+#[test]
+fn test_extend() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("extend.db");
+
+    let mut db = MyDB::new(&path).unwrap();
+    db.set("hello", "world").unwrap();
+    db.extend([("foo", "bar"), ("hello", "mars"), ("foo", "baz")])
+        .unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+    assert_eq!(db.get("foo").unwrap(), Some("baz".to_string()));
+    assert_eq!(db.len(), 2);
+    drop(db);
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+    assert_eq!(db.get("foo").unwrap(), Some("baz".to_string()));
+    db.check_invariants().unwrap();
+}
+
+#[test]
+fn test_split_by() {
+    let dir = tempfile::tempdir().unwrap();
+    let paths = [dir.path().join("shard0.db"), dir.path().join("shard1.db")];
+
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    let keys = ["a", "bb", "ccc", "dddd", "eeeee"];
+    for key in keys {
+        db.set(key, &key.to_uppercase()).unwrap();
+    }
+    db.split_by(2, |key| key.len(), &paths).unwrap();
+
+    let mut shards: Vec<_> = paths.iter().map(|path| MyDB::new(path).unwrap()).collect();
+    assert_eq!(shards[0].len(), 2);
+    assert_eq!(shards[1].len(), 3);
+    for key in keys {
+        let (expected, other) = if key.len() % 2 == 0 { (0, 1) } else { (1, 0) };
+        assert_eq!(shards[expected].get(key).unwrap(), Some(key.to_uppercase()));
+        assert_eq!(shards[other].get(key).unwrap(), None);
+    }
+}
+
+#[test]
+fn test_format() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("format.db");
+
+    let mut db = MyDB::new(&path).unwrap();
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    db.delete("hello").unwrap();
+    let metadata = db.metadata("foo").unwrap();
+    drop(db);
+
+    let bytes = fs::read(&path).unwrap();
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let (record, size) = format::decode_record(&bytes[offset..]).unwrap();
+        assert_eq!(
+            format::encode_record(&record).unwrap(),
+            &bytes[offset..offset + size]
+        );
+        records.push(record);
+        offset += size;
+    }
+
+    let summary: Vec<_> = records
+        .iter()
+        .map(|record| (record.key.as_str(), record.value.as_deref()))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("hello", Some("world")),
+            ("foo", Some("bar")),
+            ("hello", None)
+        ]
+    );
+    assert_eq!(records[1].timestamp, metadata.timestamp);
+
+    assert!(matches!(
+        format::decode_record(&bytes[..format::HEADER_SIZE + 2]),
+        Err(Error::DecodeError(_))
+    ));
+}
+
+#[test]
+fn test_fuzz_decode_record() {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&10u32.to_le_bytes());
+    bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+    bytes.extend_from_slice(&5u32.to_le_bytes());
+    bytes.extend_from_slice(b"hello");
+    assert!(matches!(
+        fuzz::decode_record(&bytes),
+        Err(Error::DecodeError(_))
+    ));
+
+    bytes[8..12].copy_from_slice(&(u32::MAX - 1).to_le_bytes());
+    assert!(matches!(
+        fuzz::decode_record(&bytes),
+        Err(Error::DecodeError(_))
+    ));
+
+    for _ in 0..1000 {
+        let len = rand::random::<usize>() % 64;
+        let bytes: Vec<u8> = (0..len).map(|_| rand::random()).collect();
+        let _ = fuzz::decode_record(&bytes);
+    }
+}
+
+#[test]
+fn test_csv() {
+    let items = [
+        ("hello", "world"),
+        ("comma", "a, b and c"),
+        ("quotes", "\"quoted\" value"),
+        ("newline", "first line\r\nsecond line"),
+        ("empty", ""),
+        ("key, \"with\" everything\n", ",\""),
+    ];
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    db.extend(items).unwrap();
+
+    let mut csv = Vec::new();
+    db.export_csv(&mut csv).unwrap();
+    assert!(String::from_utf8(csv.clone())
+        .unwrap()
+        .starts_with("comma,\"a, b and c\"\nempty,\n"));
+
+    let mut copy = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    assert_eq!(copy.import_csv(csv.as_slice()).unwrap(), items.len());
+    assert_eq!(copy.len(), items.len());
+    for (key, value) in items {
+        assert_eq!(copy.get(key).unwrap(), Some(value.to_string()));
+    }
+
+    assert!(matches!(
+        copy.import_csv("foo,bar\nbaz\n".as_bytes()),
+        Err(Error::DecodeError(_))
+    ));
+    assert_eq!(copy.get("foo").unwrap(), None);
+}
+
+#[test]
+fn test_touch() {
+    let now = Arc::new(AtomicU64::new(1000));
+    let clock = {
+        let now = now.clone();
+        move || time::UNIX_EPOCH + time::Duration::from_secs(now.load(Ordering::SeqCst))
+    };
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new()))
+        .unwrap()
+        .with_clock(clock)
+        .with_skip_redundant_writes(true);
+
+    db.set("hello", "world").unwrap();
+    assert_eq!(db.metadata("hello").unwrap().timestamp, 1000);
+
+    now.store(2000, Ordering::SeqCst);
+    assert!(db.touch("hello").unwrap());
+    assert_eq!(db.metadata("hello").unwrap().timestamp, 2000);
+    assert_eq!(db.get("hello").unwrap(), Some("world".to_string()));
+
+    let disk_size = db.disk_size();
+    assert!(!db.touch("foo").unwrap());
+    assert_eq!(db.get("foo").unwrap(), None);
+    assert_eq!(db.disk_size(), disk_size);
+}
+
+#[test]
+fn test_log_reader() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("log.db");
+
+    let mut db = MyDB::new(&path).unwrap();
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    db.set("hello", "mars").unwrap();
+    db.delete("foo").unwrap();
+    let timestamp = db.metadata("hello").unwrap().timestamp;
+    drop(db);
+
+    let mut reader = LogReader::open(&path).unwrap();
+    let first = reader.next_record().unwrap().unwrap();
+    assert_eq!(first.offset, 0);
+    assert_eq!(first.key, "hello");
+    assert_eq!(first.value, Some("world".to_string()));
+
+    let records: Vec<_> = reader.map(Result::unwrap).collect();
+    let summary: Vec<_> = records
+        .iter()
+        .map(|record| (record.offset, record.key.as_str(), record.value.as_deref()))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (22, "foo", Some("bar")),
+            (40, "hello", Some("mars")),
+            (61, "foo", None)
+        ]
+    );
+    assert_eq!(records[1].timestamp, timestamp);
+
+    // An incomplete record marks the end of the log.
+    let bytes = fs::read(&path).unwrap();
+    let mut reader = LogReader::new(&bytes[..bytes.len() - 1]);
+    assert_eq!(reader.by_ref().count(), 3);
+    assert!(reader.next_record().unwrap().is_none());
+
+    // The size of a record isn't trusted to allocate its buffer.
+    let mut bytes = vec![1, 0, 0, 0, 5, 0, 0, 0];
+    bytes.extend_from_slice(&(u32::MAX - 1).to_le_bytes());
+    bytes.extend_from_slice(b"hello");
+    assert!(LogReader::new(&bytes[..]).next_record().unwrap().is_none());
+}
+
+#[test]
+fn test_monotonic_timestamps() {
+    let now = Arc::new(AtomicU64::new(1000));
+    let clock = |now: &Arc<AtomicU64>| {
+        let now = now.clone();
+        move || time::UNIX_EPOCH + time::Duration::from_secs(now.fetch_sub(100, Ordering::SeqCst))
+    };
+
+    // Timestamps follow the clock by default.
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new()))
+        .unwrap()
+        .with_clock(clock(&now));
+    db.set("a", "value").unwrap();
+    db.set("b", "value").unwrap();
+    assert_eq!(db.metadata("a").unwrap().timestamp, 1000);
+    assert_eq!(db.metadata("b").unwrap().timestamp, 900);
+
+    now.store(1000, Ordering::SeqCst);
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new()))
+        .unwrap()
+        .with_clock(clock(&now))
+        .with_monotonic_timestamps(true);
+    for key in ["a", "b", "c"] {
+        db.set(key, "value").unwrap();
+    }
+    db.delete("c").unwrap();
+    db.set("d", "value").unwrap();
+    assert_eq!(db.metadata("a").unwrap().timestamp, 1000);
+    assert_eq!(db.metadata("b").unwrap().timestamp, 1001);
+    assert_eq!(db.metadata("d").unwrap().timestamp, 1004);
+
+    // Timestamps don't go back when the clock catches up.
+    now.store(1002, Ordering::SeqCst);
+    db.set("e", "value").unwrap();
+    assert_eq!(db.metadata("e").unwrap().timestamp, 1005);
+    now.store(2000, Ordering::SeqCst);
+    db.set("f", "value").unwrap();
+    assert_eq!(db.metadata("f").unwrap().timestamp, 2000);
+}
+
+#[test]
+fn test_load_large_key() {
+    let dir = tempfile::tempdir().unwrap();
+    for split in [false, true] {
+        let path = dir.path().join(format!("large_key_{}.db", split));
+        let open = |path: &path::Path| {
+            if split {
+                MyDB::new_split(path).unwrap()
+            } else {
+                MyDB::new(path).unwrap()
+            }
+        };
+
+        let large_key = "k".repeat(2 << 20);
+        let mut db = open(&path);
+        db.set(&large_key, "large").unwrap();
+        db.extend((0..1000).map(|i| (format!("key-{}", i), format!("value-{}", i))))
+            .unwrap();
+        drop(db);
+
+        let mut db = open(&path);
+        assert_eq!(db.len(), 1001);
+        assert_eq!(db.get(&large_key).unwrap(), Some("large".to_string()));
+        for i in 0..1000 {
+            assert_eq!(
+                db.get(&format!("key-{}", i)).unwrap(),
+                Some(format!("value-{}", i))
+            );
+        }
+        db.check_invariants().unwrap();
+    }
+}
+
+#[test]
+fn test_prefetch() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut db = MyDB::new(dir.path().join("prefetch.db")).unwrap();
+    let mut split = MyDB::new_split(dir.path().join("prefetch_split.db")).unwrap();
+    let mut memory = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    for i in 0..100 {
+        let (key, value) = (format!("key-{}", i), format!("value-{}", i));
+        db.set(&key, &value).unwrap();
+        split.set(&key, &value).unwrap();
+        memory.set(&key, &value).unwrap();
+    }
+
+    let keys: Vec<_> = (0..100).map(|i| format!("key-{}", i)).collect();
+    let mut keys: Vec<_> = keys.iter().map(String::as_str).collect();
+    keys.push("missing");
+    db.prefetch(&keys);
+    split.prefetch(&keys);
+    memory.prefetch(&keys);
+
+    for i in 0..100 {
+        let (key, value) = (format!("key-{}", i), Some(format!("value-{}", i)));
+        assert_eq!(db.get(&key).unwrap(), value);
+        assert_eq!(split.get(&key).unwrap(), value);
+        assert_eq!(memory.get(&key).unwrap(), value);
+    }
+    assert_eq!(db.get("missing").unwrap(), None);
+}
+
+#[test]
+fn test_open_without_create() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("missing.db");
+
+    let options = OpenOptions::new().with_create(false);
+    match options.open(&path) {
+        Err(Error::IoError(err)) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+        Err(err) => panic!("unexpected error: {:?}", err),
+        Ok(_) => panic!("database was created"),
+    }
+    assert!(!path.exists());
+
+    MyDB::new(&path).unwrap().set("hello", "world").unwrap();
+    let mut db = options.open(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("world".to_string()));
+    assert_eq!(db.path(), Some(path.as_path()));
+}
+
+#[test]
+fn test_memory_usage() {
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    let empty = db.memory_usage();
+
+    db.extend((0..1000).map(|i| (format!("key-{:04}", i), "value".to_string())))
+        .unwrap();
+    let short = db.memory_usage();
+    assert!(short >= empty + 1000 * "key-0000".len());
+
+    let mut long = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    long.extend((0..1000).map(|i| (format!("{:0100}", i), "value".to_string())))
+        .unwrap();
+    assert!(long.memory_usage() >= short + 1000 * (100 - "key-0000".len()));
+
+    // Values aren't held in memory.
+    let mut large = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    large
+        .extend((0..1000).map(|i| (format!("key-{:04}", i), "v".repeat(1000))))
+        .unwrap();
+    assert_eq!(large.memory_usage(), short);
+}
+
+#[test]
+fn test_set_map() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("set_map.db");
+    let mut db = MyDB::new(&path).unwrap();
+
+    let map: std::collections::BTreeMap<_, _> = (0..100)
+        .map(|i| (format!("key-{}", i), format!("value-{}", i)))
+        .collect();
+    db.set_map(map.clone()).unwrap();
+
+    // Only the last value of duplicated keys is written.
+    let pairs = vec![
+        ("dup".to_string(), "first".to_string()),
+        ("dup".to_string(), "last".to_string()),
+    ];
+    db.set_map(pairs).unwrap();
+    assert_eq!(db.disk_size(), db.live_bytes());
+
+    let mut db = db.reopen().unwrap();
+    assert_eq!(db.len(), 101);
+    for (key, value) in &map {
+        assert_eq!(db.get(key).unwrap().as_ref(), Some(value));
+    }
+    assert_eq!(db.get("dup").unwrap(), Some("last".to_string()));
+    db.check_invariants().unwrap();
+}
+
+#[test]
+fn test_replace_contents() {
+    let dir = tempfile::tempdir().unwrap();
+    for split in [false, true] {
+        let path = dir.path().join(format!("replace_{}.db", split));
+        let open = |path: &path::Path| {
+            if split {
+                MyDB::new_split(path).unwrap()
+            } else {
+                MyDB::new(path).unwrap()
+            }
+        };
+
+        let mut db = open(&path);
+        db.extend((0..100).map(|i| (format!("old-{}", i), "value")))
+            .unwrap();
+        db.set("kept", "old value").unwrap();
+
+        let contents = vec![
+            ("kept".to_string(), "first".to_string()),
+            ("new".to_string(), "value".to_string()),
+            ("kept".to_string(), "new value".to_string()),
+        ];
+        db.replace_contents(contents).unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.get("old-0").unwrap(), None);
+        db.check_invariants().unwrap();
+        drop(db);
+
+        let mut db = open(&path);
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.get("old-0").unwrap(), None);
+        assert_eq!(db.get("kept").unwrap(), Some("new value".to_string()));
+        assert_eq!(db.get("new").unwrap(), Some("value".to_string()));
+        assert_eq!(db.disk_size(), db.live_bytes());
+        db.check_invariants().unwrap();
+    }
+
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    db.set("old", "value").unwrap();
+    db.replace_contents([("new", "value")]).unwrap();
+    assert_eq!(db.get("old").unwrap(), None);
+    assert_eq!(db.get("new").unwrap(), Some("value".to_string()));
+    assert!(matches!(
+        db.replace_contents([("k".repeat(1 << 24), "value")]),
+        Err(Error::KeyTooLong)
+    ));
+    assert_eq!(db.get("new").unwrap(), Some("value".to_string()));
+}
+
+#[test]
+fn test_replace_contents_dir() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut db = MyDB::open_dir(dir.path()).unwrap();
+    db.set("old", "1").unwrap();
+    db.roll_segment().unwrap();
+    db.set("other", "1").unwrap();
+    db.replace_contents([("new", "2")]).unwrap();
+    assert_eq!(db.get("old").unwrap(), None);
+    assert_eq!(db.len(), 1);
+    assert!(!dir.path().join("0.db").exists());
+
+    let mut db = db.reopen().unwrap();
+    assert_eq!(db.len(), 1);
+    assert_eq!(db.get("new").unwrap(), Some("2".to_string()));
+}
+
+#[test]
+fn test_get_traced() {
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    db.set("hello", "world").unwrap();
+    let hit = Some(("world".to_string(), ReadSource::Disk));
+    assert_eq!(db.get_traced("hello").unwrap(), hit);
+    assert_eq!(db.get_traced("hello").unwrap(), hit);
+    assert_eq!(db.get_traced("missing").unwrap(), None);
+
+    let mut db = db.with_read_cache(10);
+    assert_eq!(db.get_traced("hello").unwrap(), hit);
+    assert_eq!(
+        db.get_traced("hello").unwrap(),
+        Some(("world".to_string(), ReadSource::Cache))
+    );
+
+    db.set("hello", "again").unwrap();
+    assert_eq!(
+        db.get_traced("hello").unwrap(),
+        Some(("again".to_string(), ReadSource::Disk))
+    );
+}
+
+#[test]
+fn test_wal() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("wal.db");
+    let wal_path = dir.path().join("wal.db.wal");
+    let options = OpenOptions::new().with_wal(true);
+
+    let mut db = options.open(&path).unwrap();
+    db.set("hello", "world").unwrap();
+    db.set("overwritten", "old").unwrap();
+    db.checkpoint().unwrap();
+    assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+    let checkpointed = fs::metadata(&path).unwrap().len();
+
+    db.set("overwritten", "new").unwrap();
+    db.set("foo", "bar").unwrap();
+    db.delete("hello").unwrap();
+    assert!(fs::metadata(&wal_path).unwrap().len() > 0);
+
+    // Simulate a crash before the writes made it to the database file, while the last entry of
+    // the log was being written.
+    std::mem::forget(db);
+    let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(checkpointed).unwrap();
+    let mut wal = fs::OpenOptions::new().append(true).open(&wal_path).unwrap();
+    io::Write::write_all(&mut wal, &[1, 2, 3]).unwrap();
+
+    let mut db = options.open(&path).unwrap();
+    assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+    assert_eq!(db.len(), 2);
+    assert_eq!(db.get("hello").unwrap(), None);
+    assert_eq!(db.get("overwritten").unwrap(), Some("new".to_string()));
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+    db.check_invariants().unwrap();
+
+    db.set("last", "write").unwrap();
+    db.compact().unwrap();
+    db.set("after", "compaction").unwrap();
+    drop(db);
+    assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.len(), 4);
+    assert_eq!(db.get("last").unwrap(), Some("write".to_string()));
+    assert_eq!(db.get("after").unwrap(), Some("compaction".to_string()));
+}
+
+#[test]
+fn test_wal_recovered_without_wal() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("stale_wal.db");
+    let wal_path = dir.path().join("stale_wal.db.wal");
+    let options = OpenOptions::new().with_wal(true);
+
+    let mut db = options.open(&path).unwrap();
+    db.set("a", "11").unwrap();
+    std::mem::forget(db);
+    assert!(fs::metadata(&wal_path).unwrap().len() > 0);
+
+    // Opening without the log applies it, so that it isn't replayed over newer writes later.
+    let mut db = MyDB::new(&path).unwrap();
+    assert!(!wal_path.exists());
+    assert_eq!(db.get("a").unwrap(), Some("11".to_string()));
+    db.set("a", "22").unwrap();
+    db.compact().unwrap();
+    drop(db);
+
+    let mut db = options.open(&path).unwrap();
+    assert_eq!(db.get("a").unwrap(), Some("22".to_string()));
+}
+
+#[cfg(feature = "log")]
+#[test]
+fn test_drop_unsynced_warning() {
+    use std::sync::Mutex;
+
+    static MESSAGES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct Logger;
+
+    impl log::Log for Logger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            if record.level() == log::Level::Warn {
+                MESSAGES.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    log::set_logger(&Logger).unwrap();
+    log::set_max_level(log::LevelFilter::Warn);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("unsynced.db");
+    let warned = || {
+        let needle = format!("{:?}", path);
+        let messages = MESSAGES.lock().unwrap();
+        messages.iter().any(|message| message.contains(&needle))
+    };
+
+    let mut db = MyDB::new(&path).unwrap();
+    db.set("hello", "world").unwrap();
+    drop(db);
+    assert!(!warned());
+
+    let mut db = MyDB::new(&path)
+        .unwrap()
+        .with_sync_policy(SyncPolicy::Never);
+    db.set("hello", "world").unwrap();
+    db.close().unwrap();
+    assert!(!warned());
+
+    let mut db = MyDB::new(&path)
+        .unwrap()
+        .with_sync_policy(SyncPolicy::Never);
+    db.set("hello", "world").unwrap();
+    drop(db);
+    assert!(warned());
+}
+
+#[test]
+fn test_open_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let segment = |name: &str, secs: u64| {
+        MyDB::new(dir.path().join(name))
+            .unwrap()
+            .with_clock(move || time::UNIX_EPOCH + time::Duration::from_secs(secs))
+    };
+
+    let mut db = segment("1.db", 1000);
+    db.extend([("a", "1"), ("b", "1"), ("c", "1"), ("d", "1")])
+        .unwrap();
+    drop(db);
+    let mut db = segment("2.db", 2000);
+    db.set("a", "2").unwrap();
+    db.delete("a").unwrap();
+    db.set("b", "2").unwrap();
+    drop(db);
+    // Older than the first segment.
+    let mut db = segment("3.db", 500);
+    db.set("c", "3").unwrap();
+    db.set("e", "3").unwrap();
+    drop(db);
+    fs::write(dir.path().join("notes.txt"), "not a segment").unwrap();
+
+    let mut db = MyDB::open_dir(dir.path()).unwrap();
+    assert_eq!(db.path(), Some(dir.path().join("3.db").as_path()));
+    assert_eq!(db.len(), 4);
+    assert_eq!(db.get("a").unwrap(), None);
+    assert_eq!(db.get("b").unwrap(), Some("2".to_string()));
+    assert_eq!(db.get("c").unwrap(), Some("1".to_string()));
+    assert_eq!(db.get("d").unwrap(), Some("1".to_string()));
+    assert_eq!(db.get("e").unwrap(), Some("3".to_string()));
+    assert_eq!(db.count_prefix(""), db.len());
+    assert_eq!(db.metadata("b").unwrap().timestamp, 2000);
+    assert_eq!(db.value_len("c"), Some(1));
+    assert_eq!(db.metadata("a"), None);
+
+    db.set("c", "new").unwrap();
+    assert!(db.delete("d").unwrap());
+    assert!(!db.delete("a").unwrap());
+    assert_eq!(db.len(), 3);
+    drop(db);
+
+    let mut db = MyDB::open_dir(dir.path()).unwrap();
+    assert_eq!(db.len(), 3);
+    assert_eq!(db.get("c").unwrap(), Some("new".to_string()));
+    assert_eq!(db.get("d").unwrap(), None);
+    assert_eq!(db.get("b").unwrap(), Some("2".to_string()));
+
+    let empty = tempfile::tempdir().unwrap();
+    let mut db = MyDB::open_dir(empty.path()).unwrap();
+    assert!(db.is_empty());
+    db.set("hello", "world").unwrap();
+    assert!(empty.path().join("0.db").exists());
+}
+
+#[test]
+fn test_roll_segment_preallocated() {
+    let dir = tempfile::tempdir().unwrap();
+    let file_len = |name: &str| fs::metadata(dir.path().join(name)).unwrap().len();
+
+    let mut db = MyDB::open_dir(dir.path())
+        .unwrap()
+        .with_preallocated_segment_size(4096);
+    db.set("a", "1").unwrap();
+    db.set("b", "1").unwrap();
+    assert_eq!(file_len("0.db"), 4096);
+    let first_size = db.disk_size();
+    assert!(first_size < 4096);
+
+    db.roll_segment().unwrap();
+    assert_eq!(db.path(), Some(dir.path().join("1.db").as_path()));
+    assert_eq!(file_len("0.db"), first_size);
+    db.set("b", "2").unwrap();
+    db.set("c", "2").unwrap();
+    assert_eq!(file_len("1.db"), 4096);
+    assert_eq!(db.disk_size(), first_size);
+    assert_eq!(db.len(), 3);
+    assert_eq!(db.get("a").unwrap(), Some("1".to_string()));
+    assert_eq!(db.get("b").unwrap(), Some("2".to_string()));
+
+    for _ in 0..9 {
+        db.roll_segment().unwrap();
+    }
+    db.set("d", "11").unwrap();
+    drop(db);
+
+    // Segments are ordered numerically, so that 10.db comes after 9.db.
+    let mut db = MyDB::open_dir(dir.path()).unwrap();
+    assert_eq!(db.path(), Some(dir.path().join("10.db").as_path()));
+    assert_eq!(file_len("10.db"), db.disk_size());
+    assert_eq!(db.len(), 4);
+    assert_eq!(db.get("a").unwrap(), Some("1".to_string()));
+    assert_eq!(db.get("b").unwrap(), Some("2".to_string()));
+    assert_eq!(db.get("d").unwrap(), Some("11".to_string()));
+
+    let mut db = MyDB::open_temp().unwrap();
+    assert!(matches!(
+        db.roll_segment(),
+        Err(Error::IoError(err)) if err.kind() == io::ErrorKind::Unsupported
+    ));
+}
+
+#[test]
+fn test_compact_dir_keeps_tombstones() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut db = MyDB::open_dir(dir.path()).unwrap();
+    db.set("k", "old").unwrap();
+    db.set("other", "1").unwrap();
+    db.roll_segment().unwrap();
+    db.delete("k").unwrap();
+    db.set("new", "2").unwrap();
+    db.compact().unwrap();
+    assert_eq!(db.get("k").unwrap(), None);
+    db.check_invariants().unwrap();
+
+    // The tombstone still hides the value of the sealed segment.
+    let mut db = db.reopen().unwrap();
+    assert_eq!(db.get("k").unwrap(), None);
+    assert_eq!(db.len(), 2);
+    let db = SharedDB::new(db);
+    db.compact().unwrap();
+    let mut db = db.into_inner().reopen().unwrap();
+    assert_eq!(db.get("k").unwrap(), None);
+    assert_eq!(db.get("other").unwrap(), Some("1".to_string()));
+    assert_eq!(db.get("new").unwrap(), Some("2".to_string()));
+
+    db.compact_keep_versions(2).unwrap();
+    let mut db = db.reopen().unwrap();
+    assert_eq!(db.get("k").unwrap(), None);
+    assert_eq!(db.len(), 2);
+}
+
+#[test]
+fn test_merge_segments() {
+    let dir = tempfile::tempdir().unwrap();
+    let segment = |name: &str| dir.path().join(name);
+
+    let mut db = MyDB::open_dir(dir.path()).unwrap();
+    db.set("a", "1").unwrap();
+    db.set("b", "1").unwrap();
+    db.roll_segment().unwrap();
+    db.set("b", "2").unwrap();
+    db.set("c", "2").unwrap();
+    db.delete("a").unwrap();
+    db.roll_segment().unwrap();
+    db.set("d", "3").unwrap();
+    db.roll_segment().unwrap();
+    db.set("e", "4").unwrap();
+
+    assert!(matches!(
+        db.merge_segments(&[0, 3]),
+        Err(Error::IoError(err)) if err.kind() == io::ErrorKind::InvalidInput
+    ));
+    // Segments which aren't next to each other can't be merged.
+    assert!(matches!(
+        db.merge_segments(&[0, 2]),
+        Err(Error::IoError(err)) if err.kind() == io::ErrorKind::InvalidInput
+    ));
+    db.merge_segments(&[1, 0]).unwrap();
+    assert!(!segment("0.db").exists());
+    assert_eq!(db.len(), 4);
+    assert_eq!(db.get("a").unwrap(), None);
+    assert_eq!(db.get("b").unwrap(), Some("2".to_string()));
+    assert_eq!(db.get("c").unwrap(), Some("2".to_string()));
+    assert_eq!(db.get("d").unwrap(), Some("3".to_string()));
+    assert_eq!(db.get("e").unwrap(), Some("4".to_string()));
+
+    // The merged segment only has the latest records of the merged segments.
+    let merged = MyDB::open_read_only(segment("1.db")).unwrap();
+    assert_eq!(merged.len(), 2);
+    assert_eq!(estimate_records(segment("1.db")).unwrap(), 3);
+
+    db.merge_segments(&[1, 2]).unwrap();
+    assert_eq!(db.get("b").unwrap(), Some("2".to_string()));
+    assert_eq!(db.get("d").unwrap(), Some("3".to_string()));
+    let mut db = db.reopen().unwrap();
+    assert_eq!(db.len(), 4);
+    assert_eq!(db.get("a").unwrap(), None);
+    assert_eq!(db.get("c").unwrap(), Some("2".to_string()));
+    db.set("d", "5").unwrap();
+    assert_eq!(db.get("d").unwrap(), Some("5".to_string()));
+
+    let mut db = MyDB::open_temp().unwrap();
+    assert!(matches!(
+        db.merge_segments(&[0]),
+        Err(Error::IoError(err)) if err.kind() == io::ErrorKind::Unsupported
+    ));
+}
+
+#[test]
+fn test_open_dir_negative_lookups() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut db = MyDB::new(dir.path().join("1.db")).unwrap();
+    db.set("sealed", "value").unwrap();
+    drop(db);
+    let mut db = MyDB::new(dir.path().join("2.db")).unwrap();
+    db.set("active", "value").unwrap();
+    drop(db);
+
+    let mut db = MyDB::open_dir(dir.path()).unwrap();
+    assert!(db.contains_key("sealed"));
+    assert!(db.contains_key("active"));
+
+    // Reading the sealed segment now fails, so lookups only succeed if they don't read it.
+    fs::OpenOptions::new()
+        .write(true)
+        .open(dir.path().join("1.db"))
+        .unwrap()
+        .set_len(0)
+        .unwrap();
+    assert!(!db.contains_key("absent"));
+    assert_eq!(db.get("absent").unwrap(), None);
+    assert_eq!(db.get("active").unwrap(), Some("value".to_string()));
+    assert!(db.get("sealed").is_err());
+}
+
+#[test]
+fn test_open_dir_bloom_filters() {
+    let dir = tempfile::tempdir().unwrap();
+    for i in 1..=3 {
+        let mut db = MyDB::new(dir.path().join(format!("{}.db", i))).unwrap();
+        db.extend((0..10).map(|j| (format!("key-{}-{}", i, j), "value".to_string())))
+            .unwrap();
+    }
+
+    // Without filters, every lookup of a key missing from the last segment looks up the keys
+    // of the sealed segments.
+    let db = MyDB::open_dir(dir.path()).unwrap();
+    assert!(!db.contains_key("absent"));
+    assert_eq!(db.segment_lookups(), 1);
+
+    let mut db = db.with_bloom_filters(10);
+    assert!(!db.contains_key("absent"));
+    assert_eq!(db.get("absent").unwrap(), None);
+    assert_eq!(db.segment_lookups(), 1);
+    assert!(db.contains_key("key-1-0"));
+    assert_eq!(db.get("key-2-5").unwrap(), Some("value".to_string()));
+    assert_eq!(db.segment_lookups(), 3);
+    assert!(db.contains_key("key-3-0"));
+    assert_eq!(db.segment_lookups(), 3);
+
+    // Rolled and merged segments get their own filters.
+    db.roll_segment().unwrap();
+    assert!(db.contains_key("key-3-0"));
+    assert_eq!(db.segment_lookups(), 4);
+    db.merge_segments(&[1, 2]).unwrap();
+    assert!(db.contains_key("key-2-5"));
+    assert!(!db.contains_key("absent"));
+    assert_eq!(db.segment_lookups(), 5);
+    db.check_invariants().unwrap();
+}
+
+#[test]
+fn test_try_set_batch() {
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    let long_key = "k".repeat(1 << 24);
+    let entries = [
+        ("a", "1".to_string()),
+        (long_key.as_str(), "2".to_string()),
+        ("b", "3".to_string()),
+        (long_key.as_str(), "4".to_string()),
+    ];
+
+    let report = db.try_set_batch(entries.clone(), false).unwrap();
+    assert_eq!(report.written, 0);
+    let rejected: Vec<_> = report.rejected.iter().map(|(i, _)| *i).collect();
+    assert_eq!(rejected, [1, 3]);
+    assert!(report
+        .rejected
+        .iter()
+        .all(|(_, err)| matches!(err, Error::KeyTooLong)));
+    assert!(db.is_empty());
+    assert_eq!(db.disk_size(), 0);
+
+    let BatchReport { written, rejected } = db.try_set_batch(entries, true).unwrap();
+    assert_eq!(written, 2);
+    assert_eq!(rejected.len(), 2);
+    assert_eq!(db.len(), 2);
+    assert_eq!(db.get("a").unwrap(), Some("1".to_string()));
+    assert_eq!(db.get("b").unwrap(), Some("3".to_string()));
+
+    let report = db.try_set_batch([("c", "5")], false).unwrap();
+    assert_eq!(report.written, 1);
+    assert!(report.rejected.is_empty());
+    assert_eq!(db.get("c").unwrap(), Some("5".to_string()));
+}
+
+#[test]
+fn test_io_counters() {
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    assert_eq!(db.io_counters(), IoCounters::default());
+
+    // Records of these pairs are 22 bytes long.
+    db.set("hello", "world").unwrap();
+    db.set("hello", "there").unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("there".to_string()));
+    assert_eq!(db.get("missing").unwrap(), None);
+    assert_eq!(
+        db.io_counters(),
+        IoCounters {
+            bytes_read: 5,
+            bytes_written: 44,
+            reads: 1,
+            writes: 2,
+        }
+    );
+
+    db.compact().unwrap();
+    assert_eq!(
+        db.io_counters(),
+        IoCounters {
+            bytes_read: 27,
+            bytes_written: 66,
+            reads: 2,
+            writes: 3,
+        }
+    );
+
+    let mut db = db.with_read_cache(10);
+    db.get("hello").unwrap();
+    db.get("hello").unwrap();
+    assert_eq!(db.io_counters().reads, 3);
+}
+
+#[test]
+fn test_compact_preserves_timestamps() {
+    let dir = tempfile::tempdir().unwrap();
+    let now = Arc::new(AtomicU64::new(1000));
+    let clock = || {
+        let now = now.clone();
+        move || time::UNIX_EPOCH + time::Duration::from_secs(now.load(Ordering::SeqCst))
+    };
+
+    for split in [false, true] {
+        let path = dir.path().join(format!("timestamps_{}.db", split));
+        let open = |path: &path::Path| {
+            let db = if split {
+                MyDB::new_split(path).unwrap()
+            } else {
+                MyDB::new(path).unwrap()
+            };
+            db.with_clock(clock())
+        };
+        let timestamps = |db: &MyDB| ["a", "b", "c"].map(|key| db.metadata(key).unwrap().timestamp);
+
+        now.store(1000, Ordering::SeqCst);
+        let mut db = open(&path);
+        db.set("a", "1").unwrap();
+        db.set("b", "1").unwrap();
+        now.store(2000, Ordering::SeqCst);
+        db.set("b", "2").unwrap();
+        db.set("c", "2").unwrap();
+        db.set("deleted", "2").unwrap();
+        db.delete("deleted").unwrap();
+        assert_eq!(timestamps(&db), [1000, 2000, 2000]);
+
+        now.store(5000, Ordering::SeqCst);
+        db.compact().unwrap();
+        assert_eq!(timestamps(&db), [1000, 2000, 2000]);
+        drop(db);
+
+        let db = open(&path);
+        assert_eq!(timestamps(&db), [1000, 2000, 2000]);
+    }
+
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new()))
+        .unwrap()
+        .with_clock(clock());
+    now.store(1000, Ordering::SeqCst);
+    db.set("a", "1").unwrap();
+    db.set("a", "2").unwrap();
+    now.store(3000, Ordering::SeqCst);
+    db.compact().unwrap();
+    assert_eq!(db.metadata("a").unwrap().timestamp, 1000);
+}
+
+#[test]
+fn test_from_bytes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bytes.db");
+    let mut db = MyDB::new(&path).unwrap();
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    db.set("hello", "mars").unwrap();
+    db.delete("foo").unwrap();
+    db.set("unicode", "héllo wörld").unwrap();
+    drop(db);
+
+    let mut data = fs::read(&path).unwrap();
+    // An incomplete record is ignored.
+    data.extend_from_slice(&[1, 2, 3]);
+    let db = MyDB::from_bytes(&data).unwrap();
+    assert_eq!(db.len(), 2);
+    assert!(!db.is_empty());
+    assert!(db.contains_key("hello"));
+    assert!(!db.contains_key("foo"));
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+    assert_eq!(db.get("foo").unwrap(), None);
+    assert_eq!(db.get("unicode").unwrap(), Some("héllo wörld".to_string()));
+
+    assert!(MyDB::from_bytes(&[]).unwrap().is_empty());
+}
+
+#[test]
+fn test_cache_max_value_size() {
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new()))
+        .unwrap()
+        .with_read_cache(10)
+        .with_cache_max_value_size(100);
+    let large = "v".repeat(101);
+    db.set("small", "value").unwrap();
+    db.set("limit", &"v".repeat(100)).unwrap();
+    db.set("large", &large).unwrap();
+
+    for key in ["small", "limit", "large"] {
+        let (_, source) = db.get_traced(key).unwrap().unwrap();
+        assert_eq!(source, ReadSource::Disk);
+    }
+    let source = |db: &mut MyDB<_>, key| db.get_traced(key).unwrap().unwrap().1;
+    assert_eq!(source(&mut db, "small"), ReadSource::Cache);
+    assert_eq!(source(&mut db, "limit"), ReadSource::Cache);
+    assert_eq!(source(&mut db, "large"), ReadSource::Disk);
+    assert_eq!(db.get("large").unwrap(), Some(large));
+}
+
+#[test]
+fn test_save_keydir() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("keydir.db");
+
+    let mut db = MyDB::new(&path).unwrap();
+    db.extend((0..100).map(|i| (format!("key-{}", i), format!("value-{}", i))))
+        .unwrap();
+    db.set("key-1", "new value").unwrap();
+    db.delete("key-2").unwrap();
+    let mut snapshot = Vec::new();
+    db.save_keydir(&mut snapshot).unwrap();
+    // Written after the snapshot was saved.
+    db.set("key-3", "newer value").unwrap();
+    db.delete("key-4").unwrap();
+    drop(db);
+
+    let mut scanned = MyDB::new(&path).unwrap();
+    let mut db = MyDB::new_with_keydir(&path, &snapshot[..]).unwrap();
+    assert_eq!(db.load_stats().records_scanned, 2);
+    assert_eq!(db.debug_entries(), scanned.debug_entries());
+    assert_eq!(db.disk_size(), scanned.disk_size());
+    for i in 0..100 {
+        let key = format!("key-{}", i);
+        assert_eq!(db.get(&key).unwrap(), scanned.get(&key).unwrap());
+        assert_eq!(db.metadata(&key), scanned.metadata(&key));
+    }
+    db.check_invariants().unwrap();
+    db.set("key-5", "after reload").unwrap();
+    drop(db);
+    drop(scanned);
+
+    let mut corrupted = snapshot.clone();
+    corrupted[30] ^= 1;
+    assert!(matches!(
+        MyDB::new_with_keydir(&path, &corrupted[..]),
+        Err(Error::Corruption(_))
+    ));
+    assert!(matches!(
+        MyDB::new_with_keydir(&path, &snapshot[..10]),
+        Err(Error::Corruption(_))
+    ));
+
+    // The file no longer matches the snapshot once compacted.
+    MyDB::new(&path).unwrap().compact().unwrap();
+    assert!(matches!(
+        MyDB::new_with_keydir(&path, &snapshot[..]),
+        Err(Error::Corruption(_))
+    ));
+
+    let db = MyDB::new_split(dir.path().join("split.db")).unwrap();
+    assert!(matches!(
+        db.save_keydir(&mut Vec::new()),
+        Err(Error::IoError(err)) if err.kind() == io::ErrorKind::Unsupported
+    ));
+}
+
+#[test]
+fn test_compact_with_hint() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("hint.db");
+    let hint = dir.path().join("hint.db.hint");
+
+    let mut db = MyDB::new(&path).unwrap();
+    db.extend((0..50).map(|i| (format!("key-{}", i), format!("value-{}", i))))
+        .unwrap();
+    for i in 0..25 {
+        db.set(&format!("key-{}", i), "updated").unwrap();
+    }
+    db.delete("key-49").unwrap();
+    db.compact_with_hint().unwrap();
+    assert!(hint.exists());
+    db.set("key-48", "after compaction").unwrap();
+    drop(db);
+
+    let mut db = MyDB::new_with_hint(&path).unwrap();
+    assert_eq!(db.load_stats().records_scanned, 1);
+    db.check_invariants().unwrap();
+    assert_eq!(db.len(), 49);
+    assert_eq!(db.get("key-0").unwrap(), Some("updated".to_string()));
+    assert_eq!(db.get("key-30").unwrap(), Some("value-30".to_string()));
+    assert_eq!(
+        db.get("key-48").unwrap(),
+        Some("after compaction".to_string())
+    );
+    assert_eq!(db.get("key-49").unwrap(), None);
+
+    // Compacting without a hint removes the hint, which would no longer match.
+    db.set("key-0", "again").unwrap();
+    db.compact().unwrap();
+    assert!(!hint.exists());
+    drop(db);
+    let mut db = MyDB::new_with_hint(&path).unwrap();
+    assert_eq!(db.load_stats().records_scanned, 49);
+    assert_eq!(db.get("key-0").unwrap(), Some("again".to_string()));
+
+    assert!(matches!(
+        MyDB::new_from_storage(io::Cursor::new(Vec::new()))
+            .unwrap()
+            .compact_with_hint(),
+        Err(Error::IoError(err)) if err.kind() == io::ErrorKind::Unsupported
+    ));
+}
+
+#[test]
+fn test_get_error_context() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("context.db");
+    let mut db = MyDB::new(&path).unwrap();
+    db.set("first", "value").unwrap();
+    db.set("corrupted", "value").unwrap();
+    let offset = db.metadata("corrupted").unwrap().offset;
+    drop(db);
+
+    let mut data = fs::read(&path).unwrap();
+    let last = data.len() - 1;
+    data[last] = 0xff;
+    fs::write(&path, &data).unwrap();
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.get("first").unwrap(), Some("value".to_string()));
+    match db.get("corrupted") {
+        Err(Error::DecodeError(message)) => {
+            assert!(message.contains("\"corrupted\""), "{}", message);
+            assert!(
+                message.contains(&format!("offset {}", offset)),
+                "{}",
+                message
+            );
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_get_stale_entry() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("stale.db");
+    let mut db = MyDB::new(&path).unwrap();
+    db.set("kept", "value").unwrap();
+    let len = db.disk_size();
+    db.set("truncated", "value").unwrap();
+
+    // Another process truncates the file behind the handle's back.
+    let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(len).unwrap();
+    drop(file);
+
+    assert_eq!(db.get("kept").unwrap(), Some("value".to_string()));
+    match db.get("truncated") {
+        Err(Error::StaleEntry(message)) => {
+            assert!(message.contains("\"truncated\""), "{}", message);
+            assert!(message.contains("reload"), "{}", message);
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_new_with_report() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("report.db");
+
+    let (mut db, report) = MyDB::new_with_report(&path).unwrap();
+    assert_eq!(report, RecoveryReport::default());
+    db.set("hello", "world").unwrap();
+    let len = db.disk_size();
+    db.set("foo", "bar").unwrap();
+    drop(db);
+
+    // Simulate a crash in the middle of writing the last record.
+    let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(len + 5).unwrap();
+    drop(file);
+
+    let (mut db, report) = MyDB::new_with_report(&path).unwrap();
+    assert_eq!(
+        report,
+        RecoveryReport {
+            bytes_truncated: 5,
+            records_skipped: 1,
+        }
+    );
+    assert_eq!(db.get("hello").unwrap(), Some("world".to_string()));
+    assert_eq!(db.get("foo").unwrap(), None);
+    assert_eq!(db.disk_size(), len);
+
+    // Reserved space is released but it isn't an incomplete record.
+    db.reserve(100).unwrap();
+    drop(db);
+    let (_, report) = MyDB::new_with_report(&path).unwrap();
+    assert_eq!(
+        report,
+        RecoveryReport {
+            bytes_truncated: 100,
+            records_skipped: 0,
+        }
+    );
+}
+
+fn assert_send<T: Send>() {}
+
+fn assert_sync<T: Sync>() {}
+
+#[test]
+fn test_send() {
+    assert_send::<MyDB>();
+    assert_send::<MyDB<io::Cursor<Vec<u8>>>>();
+    assert_send::<SharedDB>();
+    assert_sync::<SharedDB>();
+
+    let db = Arc::new(SharedDB::new(MyDB::open_temp().unwrap()));
+    let handles = (0..4)
+        .map(|i| {
+            let db = db.clone();
+            thread::spawn(move || db.set(&format!("key-{}", i), "value").unwrap())
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let db = Arc::into_inner(db).unwrap().into_inner();
+    assert_eq!(db.len(), 4);
+}
+
+#[test]
+fn test_compact_if_needed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("compact_if_needed.db");
+    let mut db = MyDB::new(&path).unwrap();
+    assert!(!db.compact_if_needed(0.5).unwrap());
+
+    for i in 0..10 {
+        db.set(&format!("key-{}", i), "value").unwrap();
+    }
+    // A fifth of the bytes are dead.
+    db.set("key-0", "other").unwrap();
+    db.set("key-1", "other").unwrap();
+    let size = fs::metadata(&path).unwrap().len();
+    assert!(!db.compact_if_needed(0.5).unwrap());
+    assert_eq!(fs::metadata(&path).unwrap().len(), size);
+
+    assert!(db.compact_if_needed(0.1).unwrap());
+    assert!(fs::metadata(&path).unwrap().len() < size);
+    assert_eq!(db.disk_size(), db.live_bytes());
+    assert_eq!(db.get("key-0").unwrap(), Some("other".to_string()));
+    assert!(!db.compact_if_needed(0.0).unwrap());
+}
+
+#[test]
+fn test_replay() {
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    for i in 0..30 {
+        db.set(&format!("key-{}", i % 7), &format!("value-{}", i))
+            .unwrap();
+    }
+    db.delete("key-6").unwrap();
+
+    let writes = db
+        .replay(HashMap::new(), |writes, key, value| {
+            if value.is_some() {
+                *writes.entry(key.to_string()).or_insert(0) += 1;
+            } else {
+                writes.remove(key);
+            }
+        })
+        .unwrap();
+    assert_eq!(writes.len(), 6);
+    for (key, count) in &writes {
+        assert_eq!(*count, db.history(key).unwrap().len(), "{}", key);
+    }
+
+    let last = db
+        .replay(None, |last, key, value| {
+            *last = Some((key.to_string(), value.map(<[u8]>::to_vec)))
+        })
+        .unwrap();
+    assert_eq!(last, Some(("key-6".to_string(), None)));
+
+    // Only the latest records are left after compaction.
+    db.compact().unwrap();
+    let records = db.replay(0, |records, _, _| *records += 1).unwrap();
+    assert_eq!(records, 6);
+}
+
+#[test]
+fn test_tail() {
+    let now = Arc::new(AtomicU64::new(1000));
+    let clock = {
+        let now = now.clone();
+        move || time::UNIX_EPOCH + time::Duration::from_secs(now.load(Ordering::SeqCst))
+    };
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new()))
+        .unwrap()
+        .with_clock(clock);
+    assert_eq!(db.tail(3).unwrap(), vec![]);
+
+    for (i, (key, value)) in [("a", "1"), ("b", "2"), ("a", "3"), ("c", "4")]
+        .into_iter()
+        .enumerate()
+    {
+        now.store(1000 + i as u64, Ordering::SeqCst);
+        db.set(key, value).unwrap();
+    }
+    db.delete("c").unwrap();
+
+    let expected = |records: &[(&str, &str, u32)]| {
+        records
+            .iter()
+            .map(|&(key, value, timestamp)| (key.to_string(), value.to_string(), timestamp))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        db.tail(3).unwrap(),
+        expected(&[("b", "2", 1001), ("a", "3", 1002), ("c", "4", 1003)])
+    );
+    assert_eq!(db.tail(10).unwrap().len(), 4);
+    assert_eq!(db.tail(0).unwrap(), vec![]);
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_cipher() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("cipher.db");
+    let key = [7; 32];
+
+    let mut db = MyDB::new(&path).unwrap().with_cipher(&key);
+    db.set("hello", "secret value").unwrap();
+    db.extend([("foo", "other secret")]).unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("secret value".to_string()));
+    assert!(!db.set_idempotent("hello", "secret value").unwrap());
+    drop(db);
+
+    let bytes = fs::read(&path).unwrap();
+    let contains = |needle: &[u8]| bytes.windows(needle.len()).any(|window| window == needle);
+    assert!(contains(b"hello"));
+    assert!(!contains(b"secret"));
+
+    let mut db = MyDB::new(&path).unwrap().with_cipher(&key);
+    assert_eq!(db.get("hello").unwrap(), Some("secret value".to_string()));
+    assert_eq!(db.get("foo").unwrap(), Some("other secret".to_string()));
+    assert_eq!(db.history("hello").unwrap(), vec!["secret value"]);
+    drop(db);
+
+    let mut db = MyDB::new(&path).unwrap().with_cipher(&[8; 32]);
+    assert!(matches!(db.get("hello"), Err(Error::DecryptionFailed)));
+    let mut db = MyDB::new(&path).unwrap();
+    assert_ne!(db.get("hello").unwrap(), Some("secret value".to_string()));
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_cipher_roll_segment() {
+    let dir = tempfile::tempdir().unwrap();
+    let key = [7; 32];
+
+    let mut db = MyDB::open_dir(dir.path()).unwrap().with_cipher(&key);
+    db.set("sealed", "secret value").unwrap();
+    db.roll_segment().unwrap();
+    db.set("active", "other secret").unwrap();
+    assert_eq!(db.get("sealed").unwrap(), Some("secret value".to_string()));
+
+    let mut db = db.reopen().unwrap();
+    assert_eq!(db.get("sealed").unwrap(), Some("secret value".to_string()));
+    db.roll_segment().unwrap();
+    db.merge_segments(&[0, 1]).unwrap();
+    assert_eq!(db.get("active").unwrap(), Some("other secret".to_string()));
+    drop(db);
+
+    let mut db = MyDB::open_dir(dir.path()).unwrap().with_cipher(&key);
+    assert_eq!(db.get("sealed").unwrap(), Some("secret value".to_string()));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_open_with_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("private.db");
+    let mode = |path: &path::Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+    let mut db = OpenOptions::new()
+        .with_mode(0o600)
+        .with_wal(true)
+        .open(&path)
+        .unwrap();
+    assert_eq!(mode(&path), 0o600);
+    assert_eq!(mode(&dir.path().join("private.db.wal")), 0o600);
+
+    db.set("hello", "world").unwrap();
+    db.set("hello", "again").unwrap();
+    db.compact().unwrap();
+    assert_eq!(mode(&path), 0o600);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sync_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sync_dir.db");
+    let mut db = MyDB::new(&path).unwrap();
+    db.set("hello", "world").unwrap();
+    db.set("hello", "mars").unwrap();
+    db.compact().unwrap();
+    db.sync().unwrap();
+
+    // The directory entry of the compacted file is durable, syncing it again is harmless.
+    fs::File::open(dir.path()).unwrap().sync_all().unwrap();
+    drop(db);
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+}
+
+#[test]
+fn test_merge() {
+    fn add(_key: &str, existing: Option<&str>, operand: &str) -> String {
+        let existing: i64 = existing.map_or(0, |value| value.parse().unwrap());
+        (existing + operand.parse::<i64>().unwrap()).to_string()
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("merge.db");
+    let mut db = MyDB::new(&path).unwrap();
+    assert!(matches!(
+        db.merge("counter", "1"),
+        Err(Error::IoError(err)) if err.kind() == io::ErrorKind::Unsupported
+    ));
+
+    let mut db = db.with_merge_operator(add);
+    db.merge("counter", "1").unwrap();
+    db.merge("counter", "2").unwrap();
+    db.merge("counter", "-10").unwrap();
+    db.set("other", "5").unwrap();
+    db.merge("other", "5").unwrap();
+    assert_eq!(db.get("counter").unwrap(), Some("-7".to_string()));
+    assert_eq!(db.get("other").unwrap(), Some("10".to_string()));
+    assert_eq!(db.len(), 2);
+
+    // Setting a value discards the previous operands.
+    db.set("other", "1").unwrap();
+    db.merge("other", "1").unwrap();
+    assert_eq!(db.get("other").unwrap(), Some("2".to_string()));
+    db.close().unwrap();
+
+    let mut db = MyDB::new(&path).unwrap().with_merge_operator(add);
+    assert_eq!(db.get("counter").unwrap(), Some("-7".to_string()));
+    assert_eq!(db.get("other").unwrap(), Some("2".to_string()));
+
+    let size = db.disk_size();
+    db.compact().unwrap();
+    assert!(db.disk_size() < size);
+    assert_eq!(db.get("counter").unwrap(), Some("-7".to_string()));
+    db.check_invariants().unwrap();
+    db.close().unwrap();
+
+    // Compaction collapsed the operands, so no operator is needed anymore.
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.get("counter").unwrap(), Some("-7".to_string()));
+    assert_eq!(db.get("other").unwrap(), Some("2".to_string()));
+}
+
+#[test]
+fn test_merge_reads() {
+    fn concat(_key: &str, existing: Option<&str>, operand: &str) -> String {
+        format!("{}{}", existing.unwrap_or_default(), operand)
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("merge_reads.db");
+    let mut db = MyDB::new(&path).unwrap().with_merge_operator(concat);
+    db.set("k", "1").unwrap();
+    db.merge("k", "2").unwrap();
+    db.merge("k", "3").unwrap();
+    db.set("plain", "value").unwrap();
+
+    assert_eq!(db.history("k").unwrap(), ["1", "12", "123"]);
+    assert_eq!(db.get_value_range("k", 1, 2).unwrap(), Some(b"23".to_vec()));
+    assert!(matches!(
+        db.get_value_range("k", 1, 3),
+        Err(Error::InvalidRange)
+    ));
+    let mut pairs = Vec::new();
+    db.for_each(|key, value| {
+        pairs.push((key.to_string(), String::from_utf8(value.to_vec()).unwrap()));
+        Ok(())
+    })
+    .unwrap();
+    pairs.sort();
+    assert_eq!(
+        pairs,
+        [
+            ("k".to_string(), "123".to_string()),
+            ("plain".to_string(), "value".to_string())
+        ]
+    );
+
+    // Without a merge operator, in-memory images can't merge operands.
+    let bytes = fs::read(&path).unwrap();
+    let image = MyDB::from_bytes(&bytes).unwrap();
+    assert!(matches!(
+        image.get("k"),
+        Err(Error::IoError(err)) if err.kind() == io::ErrorKind::Unsupported
+    ));
+    assert_eq!(image.get("plain").unwrap(), Some("value".to_string()));
+
+    // Operands were never values on their own, so they aren't kept as versions.
+    db.compact_keep_versions(2).unwrap();
+    assert_eq!(db.history("k").unwrap(), ["1", "123"]);
+    assert_eq!(db.get("k").unwrap(), Some("123".to_string()));
+    db.check_invariants().unwrap();
+    let mut db = db.reopen().unwrap();
+    assert_eq!(db.history("k").unwrap(), ["1", "123"]);
+}
+
+#[test]
+fn test_key_normalizer() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("normalized.db");
+
+    // Records written without a normalizer collide once loaded with one.
+    let mut db = MyDB::new(&path).unwrap();
+    db.set("Hello", "first").unwrap();
+    db.set("HELLO", "second").unwrap();
+    db.close().unwrap();
+
+    let options = OpenOptions::new().with_key_normalizer(KeyNormalizer::lowercase());
+    let mut db = options.open(&path).unwrap();
+    assert_eq!(db.len(), 1);
+    assert_eq!(db.get("hello").unwrap(), Some("second".to_string()));
+
+    db.set("Hello", "third").unwrap();
+    assert_eq!(db.len(), 1);
+    assert_eq!(db.get("hello").unwrap(), Some("third".to_string()));
+    assert!(db.contains_key("hELLO"));
+    assert_eq!(db.value_len("hELLO"), Some(5));
+    assert!(db.metadata("HELLO").is_some());
+    db.check_invariants().unwrap();
+
+    let mut db = db.reopen().unwrap();
+    assert_eq!(db.get("HeLLo").unwrap(), Some("third".to_string()));
+    assert!(db.delete("HELLO").unwrap());
+    assert_eq!(db.get("hello").unwrap(), None);
+    db.close().unwrap();
+
+    let db = options.open(&path).unwrap();
+    assert!(db.is_empty());
+}
+
+#[test]
+fn test_key_normalizer_bulk() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("normalized_bulk.db");
+
+    let options = OpenOptions::new().with_key_normalizer(KeyNormalizer::lowercase());
+    let mut db = options.open(&path).unwrap();
+    db.extend([("Foo", "1"), ("FOO", "2")]).unwrap();
+    let report = db.try_set_batch([("Bar", "1")], true).unwrap();
+    assert_eq!(report.written, 1);
+    db.set_map([("BAZ".to_string(), "1".to_string())]).unwrap();
+    db.import_csv("User1,1\nuser2,2\n".as_bytes()).unwrap();
+    assert_eq!(db.len(), 5);
+    assert_eq!(db.get("foo").unwrap(), Some("2".to_string()));
+    assert_eq!(db.get("bar").unwrap(), Some("1".to_string()));
+    assert_eq!(db.get("baz").unwrap(), Some("1".to_string()));
+    assert_eq!(db.get("user1").unwrap(), Some("1".to_string()));
+    assert_eq!(db.count_prefix("User"), 2);
+    db.check_invariants().unwrap();
+
+    db.replace_contents([("Qux", "1")]).unwrap();
+    assert_eq!(db.get("qux").unwrap(), Some("1".to_string()));
+    let mut db = db.reopen().unwrap();
+    assert_eq!(db.len(), 1);
+    assert_eq!(db.get("QUX").unwrap(), Some("1".to_string()));
+}
+
+#[test]
+fn test_key_normalizer_rename_delete_many() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("normalized_rename.db");
+
+    let options = OpenOptions::new().with_key_normalizer(KeyNormalizer::lowercase());
+    let mut db = options.open(&path).unwrap();
+    db.set("hello", "value").unwrap();
+    assert!(db.rename("Hello", "World").unwrap());
+    assert_eq!(db.get("World").unwrap(), Some("value".to_string()));
+    assert!(!db.contains_key("hello"));
+
+    // Keys which only differ by case are the same key.
+    assert!(db.rename("WORLD", "world").unwrap());
+    db.check_invariants().unwrap();
+    let mut db = db.reopen().unwrap();
+    assert_eq!(db.get("world").unwrap(), Some("value".to_string()));
+    assert_eq!(db.history("World").unwrap(), ["value"]);
+    assert_eq!(
+        db.get_value_range("WORLD", 0, 3).unwrap(),
+        Some(b"val".to_vec())
+    );
+    assert_eq!(db.value_hash("WORLD"), db.value_hash("world"));
+    assert!(db.value_hash("WORLD").is_some());
+
+    db.set("abc", "1").unwrap();
+    assert_eq!(db.delete_many(&["ABC", "abc", "missing"]).unwrap(), 1);
+    assert!(!db.contains_key("abc"));
+    assert_eq!(db.len(), 1);
+}
+
+#[test]
+fn test_strict_keys() {
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    db.set("hello\0world", "value").unwrap();
+    assert_eq!(db.get("hello\0world").unwrap(), Some("value".to_string()));
+
+    let mut db = db.with_strict_keys(true);
+    assert!(matches!(
+        db.set("foo\0bar", "value"),
+        Err(Error::InvalidKey)
+    ));
+    assert!(matches!(
+        db.extend([("ok", "value"), ("line\nbreak", "value")]),
+        Err(Error::InvalidKey)
+    ));
+    assert_eq!(db.get("ok").unwrap(), None);
+    db.set("héllo wörld", "value").unwrap();
+
+    // Existing keys can still be read and deleted.
+    assert_eq!(db.get("hello\0world").unwrap(), Some("value".to_string()));
+    assert!(db.delete("hello\0world").unwrap());
+}
+
+#[test]
+fn test_group_commit() {
+    let storage = SyncTrackingStorage {
+        inner: io::Cursor::new(Vec::new()),
+        syncs: Arc::new(AtomicU64::new(0)),
+        synced_len: Arc::new(AtomicU64::new(0)),
+    };
+    let (syncs, synced_len) = (storage.syncs.clone(), storage.synced_len.clone());
+    let db = MyDB::new_from_storage(storage).unwrap();
+    let db = Arc::new(SharedDB::new(db).with_group_commit(16, time::Duration::from_millis(5)));
+
+    let (threads, writes) = (8, 50);
+    let handles = (0..threads)
+        .map(|i| {
+            let (db, synced_len) = (db.clone(), synced_len.clone());
+            thread::spawn(move || {
+                for j in 0..writes {
+                    let key = format!("key-{}-{}", i, j);
+                    db.set(&key, "value").unwrap();
+                    // The record is durable once `set` returns.
+                    let meta = db.lock().metadata(&key).unwrap();
+                    let end = (meta.offset + meta.size as usize) as u64;
+                    assert!(synced_len.load(Ordering::SeqCst) >= end);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let syncs = syncs.load(Ordering::SeqCst);
+    assert!(syncs > 0);
+    assert!(syncs < threads * writes / 4, "{} syncs", syncs);
+    assert_eq!(db.lock().len(), (threads * writes) as usize);
+}
+
+#[test]
+fn test_shared_compact() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("shared_compact.db");
+    let mut db = MyDB::new(&path)
+        .unwrap()
+        .with_sync_policy(SyncPolicy::Never);
+    for round in 0..5 {
+        db.extend((0..1000).map(|i| (format!("key-{}", i), format!("value-{}-{}", i, round))))
+            .unwrap();
+    }
+    let db = Arc::new(SharedDB::new(db));
+    let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+    let reader = {
+        let (db, done) = (db.clone(), done.clone());
+        thread::spawn(move || {
+            let mut scans = 0;
+            while scans == 0 || !done.load(Ordering::SeqCst) {
+                for i in (0..500).step_by(7) {
+                    let value = db.get(&format!("key-{}", i)).unwrap();
+                    assert_eq!(value, Some(format!("value-{}-4", i)));
+                }
+                scans += 1;
+            }
+            scans
+        })
+    };
+    let writer = {
+        let db = db.clone();
+        thread::spawn(move || {
+            for i in 500..1000 {
+                db.set(&format!("key-{}", i), "new value").unwrap();
+                db.set(&format!("new-{}", i), "value").unwrap();
+            }
+        })
+    };
+
+    for _ in 0..3 {
+        db.compact().unwrap();
+    }
+    writer.join().unwrap();
+    done.store(true, Ordering::SeqCst);
+    assert!(reader.join().unwrap() > 0);
+
+    let mut db = Arc::into_inner(db).unwrap().into_inner();
+    db.check_invariants().unwrap();
+    assert_eq!(db.len(), 1500);
+    db.compact().unwrap();
+    assert_eq!(db.disk_size(), db.live_bytes());
+
+    let mut db = db.reopen().unwrap();
+    for i in 0..1000 {
+        let expected = match i {
+            0..500 => format!("value-{}-4", i),
+            _ => "new value".to_string(),
+        };
+        assert_eq!(db.get(&format!("key-{}", i)).unwrap(), Some(expected));
+    }
+    assert_eq!(db.get("new-999").unwrap(), Some("value".to_string()));
+}
+
+#[test]
+fn test_shared_compact_roll_segment() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut db = MyDB::open_dir(dir.path())
+        .unwrap()
+        .with_sync_policy(SyncPolicy::Never);
+    for round in 0..5 {
+        db.extend((0..1000).map(|i| (format!("key-{}", i), format!("value-{}-{}", i, round))))
+            .unwrap();
+    }
+    let db = Arc::new(SharedDB::new(db));
+
+    // Segments rolled during compaction aren't replaced by the compacted former segment.
+    let roller = {
+        let db = db.clone();
+        thread::spawn(move || {
+            for i in 0..20 {
+                db.lock().roll_segment().unwrap();
+                db.set(&format!("key-{}", i), "rolled").unwrap();
+            }
+        })
+    };
+    for _ in 0..5 {
+        db.compact().unwrap();
+    }
+    roller.join().unwrap();
+
+    let mut db = Arc::into_inner(db).unwrap().into_inner();
+    db.check_invariants().unwrap();
+    let mut db = db.reopen().unwrap();
+    assert_eq!(db.len(), 1000);
+    for i in 0..1000 {
+        let expected = match i {
+            0..20 => "rolled".to_string(),
+            _ => format!("value-{}-4", i),
+        };
+        assert_eq!(db.get(&format!("key-{}", i)).unwrap(), Some(expected));
+    }
+}
+
+#[test]
+fn test_from_iter() {
+    let pairs: Vec<_> = (0..100)
+        .map(|i| (format!("key-{}", i), format!("value-{}", i)))
+        .collect();
+
+    let mut db: MyDB = pairs.clone().into_iter().collect();
+    assert_eq!(db.len(), 100);
+    assert_eq!(db.get("key-42").unwrap(), Some("value-42".to_string()));
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("from_iter.db");
+    let db = MyDB::try_from_iter(&path, pairs.clone()).unwrap();
+    drop(db);
+    let mut db = MyDB::new(&path).unwrap();
+    for (key, value) in &pairs {
+        assert_eq!(db.get(key).unwrap().as_ref(), Some(value));
+    }
+
+    let long_key = "k".repeat(1 << 24);
+    let path = dir.path().join("invalid.db");
+    assert!(matches!(
+        MyDB::try_from_iter(&path, [(long_key, "value")]),
+        Err(Error::KeyTooLong)
+    ));
+}
+
+#[test]
+fn test_value_len() {
+    let dir = tempfile::tempdir().unwrap();
+    for split in [false, true] {
+        let path = dir.path().join(format!("value_len_{}.db", split));
+        let mut db = if split {
+            MyDB::new_split(&path).unwrap()
+        } else {
+            MyDB::new(&path).unwrap()
+        };
+        let values = [
+            ("hello", "world"),
+            ("empty", ""),
+            ("unicode", "héllo wörld"),
+        ];
+        db.extend(values).unwrap();
+        db.set("hello", "mars!!").unwrap();
+        db.delete("empty").unwrap();
+
+        assert_eq!(db.value_len("hello"), Some("mars!!".len()));
+        assert_eq!(db.value_len("empty"), None);
+        assert_eq!(db.value_len("unicode"), Some("héllo wörld".len()));
+        assert_eq!(db.value_len("unknown"), None);
+        for key in ["hello", "unicode"] {
+            assert_eq!(
+                db.value_len(key),
+                db.get(key).unwrap().map(|value| value.len())
+            );
+        }
+    }
+}
+
+#[test]
+fn test_debug_entries() {
+    let file = tempfile::Builder::new()
+        .append(true)
+        .tempfile()
+        .unwrap()
+        .into_file();
+    let mut db = MyDB::new_from_storage(file).unwrap();
+
+    for i in 0..10 {
+        db.set(&format!("key{}", i), &"x".repeat(i)).unwrap();
+    }
+
+    let entries = db.debug_entries();
+    assert_eq!(entries.len(), 10);
+    assert_eq!(entries[0].2, 0);
+    for pair in entries.windows(2) {
+        let (_, size, offset) = &pair[0];
+        let (_, _, next_offset) = &pair[1];
+        assert_eq!(offset + *size as usize, *next_offset);
+    }
+}
+
+#[test]
+fn test_split() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("split.db");
+
+    let big = "v".repeat(64 * 1024);
+    let mut db = MyDB::new_split(&path).unwrap();
+    db.set("hello", "world").unwrap();
+    db.set("big", &big).unwrap();
+    db.set("hello", "mars").unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+    assert_eq!(db.get("big").unwrap(), Some(big.clone()));
+    drop(db);
+
+    let mut db = MyDB::new_split(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+    assert_eq!(db.get("big").unwrap(), Some(big.clone()));
+    assert_eq!(db.get("unknown_key").unwrap(), None);
+
+    db.set("foo", "bar").unwrap();
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+    assert_eq!(db.get("big").unwrap(), Some(big));
+
+    // Loading only scans the keys file, which doesn't contain any value.
+    let keys_len = fs::metadata(path.with_extension("keys")).unwrap().len();
+    let values_len = fs::metadata(path.with_extension("values")).unwrap().len();
+    assert!(keys_len < 128);
+    assert!(values_len > 64 * 1024);
+}
+
+#[test]
+fn test_metadata() {
+    let file = tempfile::Builder::new()
+        .append(true)
+        .tempfile()
+        .unwrap()
+        .into_file();
+    let mut db = MyDB::new_from_storage(file).unwrap();
+
+    assert_eq!(db.metadata("hello"), None);
+
+    let before = now();
+    db.set("hello", "world").unwrap();
+    let after = now();
+
+    let meta = db.metadata("hello").unwrap();
+    assert!(before <= meta.timestamp && meta.timestamp <= after);
+    assert_eq!(
+        meta,
+        EntryMeta {
+            timestamp: meta.timestamp,
+            size: 12 + 5 + 5, // header + key + value
+            offset: 0,
+        }
+    );
+
+    db.set("foo", "bar").unwrap();
+    assert_eq!(db.metadata("foo").unwrap().offset, 22);
+}
+
+fn now() -> u32 {
+    let time = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap();
+    time.as_secs().try_into().unwrap()
+}
+
+#[test]
+fn test_storage_cursor() {
+    let storage = io::Cursor::new(Vec::new());
+    let mut db = MyDB::new_from_storage(storage).unwrap();
+
+    assert_eq!(db.get("unknown_key").unwrap(), None);
+
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("world".to_string()));
+
+    db.set("hello", "mars").unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+}
+
+#[test]
+fn test_set_after_load() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = MyDB::new(&path).unwrap();
+    db.set("hello", "world").unwrap();
+    drop(db);
+
+    let mut db = MyDB::new(&path).unwrap();
+    db.set("foo", "bar").unwrap();
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+    drop(db);
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("world".to_string()));
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+}
+
+#[test]
+fn test_get_value_range() {
+    let storage = io::Cursor::new(Vec::new());
+    let mut db = MyDB::new_from_storage(storage).unwrap();
+
+    let value: String = (0..1000)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect();
+    db.set("foo", "bar").unwrap();
+    db.set("large", &value).unwrap();
+
+    let range = db.get_value_range("large", 495, 10).unwrap().unwrap();
+    assert_eq!(range, value.as_bytes()[495..505].to_vec());
+
+    assert_eq!(
+        db.get_value_range("large", 990, 10).unwrap().unwrap().len(),
+        10
+    );
+    assert_eq!(db.get_value_range("large", 1000, 0).unwrap(), Some(vec![]));
+    assert!(matches!(
+        db.get_value_range("large", 995, 10),
+        Err(Error::InvalidRange)
+    ));
+    assert!(matches!(
+        db.get_value_range("large", usize::MAX, 2),
+        Err(Error::InvalidRange)
+    ));
+    assert_eq!(db.get_value_range("unknown_key", 0, 1).unwrap(), None);
+}
+
+#[test]
+fn test_skip_redundant_writes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.db");
+    let mut db = MyDB::new(&path).unwrap().with_skip_redundant_writes(true);
+
+    assert!(db.set("hello", "world").unwrap());
+    let len = fs::metadata(&path).unwrap().len();
+
+    assert!(!db.set("hello", "world").unwrap());
+    assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+    assert!(db.set("hello", "mars").unwrap());
+    assert!(fs::metadata(&path).unwrap().len() > len);
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+}
+
+#[test]
+fn test_read_cache() {
+    let (storage, counters) = CountingStorage::new();
+    let mut db = MyDB::new_from_storage(storage).unwrap().with_read_cache(2);
+
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    db.set("bar", "foo").unwrap();
+
+    let reads = counters.reads.get();
+    assert_eq!(db.get("hello").unwrap(), Some("world".to_string()));
+    assert!(counters.reads.get() > reads);
+
+    let reads = counters.reads.get();
+    assert_eq!(db.get("hello").unwrap(), Some("world".to_string()));
+    assert_eq!(counters.reads.get(), reads);
+
+    // Writing a key invalidates its cached value.
+    db.set("hello", "mars").unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+    assert!(counters.reads.get() > reads);
+
+    // Reading two other keys evicts the least recently used one.
+    db.get("foo").unwrap();
+    db.get("bar").unwrap();
+    let reads = counters.reads.get();
+    assert_eq!(db.get("bar").unwrap(), Some("foo".to_string()));
+    assert_eq!(counters.reads.get(), reads);
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+    assert!(counters.reads.get() > reads);
+}
+
+#[test]
+fn test_compact() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.db");
+    let mut db = MyDB::new(&path).unwrap();
+
+    for i in 0..10 {
+        db.set("hello", &format!("world{}", i)).unwrap();
+        db.set(&format!("key{}", i), "value").unwrap();
+    }
+    let len = fs::metadata(&path).unwrap().len();
+
+    let mut progress = Vec::new();
+    db.compact_with_progress(|p| progress.push(p)).unwrap();
+
+    assert_eq!(progress.len(), 11);
+    for pair in progress.windows(2) {
+        assert!(pair[0].records_processed < pair[1].records_processed);
+        assert!(pair[0].bytes_written < pair[1].bytes_written);
+    }
+    let last = progress.last().unwrap();
+    assert_eq!(last.records_processed, 11);
+    assert_eq!(
+        last.bytes_written as u64,
+        fs::metadata(&path).unwrap().len()
+    );
+    assert!(fs::metadata(&path).unwrap().len() < len);
+
+    assert_eq!(db.get("hello").unwrap(), Some("world9".to_string()));
+    db.set("foo", "bar").unwrap();
+    drop(db);
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("world9".to_string()));
+    assert_eq!(db.get("key5").unwrap(), Some("value".to_string()));
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+}
+
+#[test]
+fn test_compact_split() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("split.db");
+    let mut db = MyDB::new_split(&path).unwrap();
+
+    for i in 0..10 {
+        db.set("hello", &format!("world{}", i)).unwrap();
+    }
+    db.set("foo", "bar").unwrap();
+    db.compact().unwrap();
+    assert_eq!(
+        fs::metadata(path.with_extension("values")).unwrap().len(),
+        ("world9".len() + "bar".len()) as u64
+    );
+    drop(db);
+
+    let mut db = MyDB::new_split(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("world9".to_string()));
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+}
+
+#[test]
+fn test_count_prefix() {
+    let storage = io::Cursor::new(Vec::new());
+    let mut db = MyDB::new_from_storage(storage).unwrap();
+
+    assert!(db.is_empty());
+    assert_eq!(db.count_prefix(""), 0);
+
+    for key in ["user:1", "user:2", "user:3", "order:1", "order:2", "misc"] {
+        db.set(key, "value").unwrap();
+    }
+    db.set("user:1", "other value").unwrap();
+
+    assert_eq!(db.len(), 6);
+    assert_eq!(db.count_prefix("user:"), 3);
+    assert_eq!(db.count_prefix("order:"), 2);
+    assert_eq!(db.count_prefix("misc"), 1);
+    assert_eq!(db.count_prefix("unknown"), 0);
+    assert_eq!(db.count_prefix(""), db.len());
+}
+
+#[test]
+fn test_compact_keep_versions() {
+    for split in [false, true] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("versions.db");
+        let open = |path: &path::Path| {
+            if split {
+                MyDB::new_split(path).unwrap()
+            } else {
+                MyDB::new(path).unwrap()
+            }
+        };
+        let mut db = open(&path);
+
+        for i in 1..=5 {
+            db.set("hello", &format!("world {}", i)).unwrap();
+        }
+        db.set("foo", "bar").unwrap();
+        db.set("deleted", "value").unwrap();
+        db.delete("deleted").unwrap();
+        assert_eq!(db.history("hello").unwrap().len(), 5);
+
+        db.compact_keep_versions(2).unwrap();
+        assert_eq!(db.history("hello").unwrap(), ["world 4", "world 5"]);
+        assert_eq!(db.history("foo").unwrap(), ["bar"]);
+        assert!(db.history("deleted").unwrap().is_empty());
+        assert_eq!(db.get("hello").unwrap(), Some("world 5".to_string()));
+        assert_eq!(db.len(), 2);
+        db.check_invariants().unwrap();
+        drop(db);
+
+        let mut db = open(&path);
+        assert_eq!(db.history("hello").unwrap(), ["world 4", "world 5"]);
+        assert_eq!(db.get("hello").unwrap(), Some("world 5".to_string()));
+        assert_eq!(db.get("deleted").unwrap(), None);
+        assert_eq!(db.len(), 2);
+    }
+}
+
+#[test]
+fn test_disk_size() {
+    let dir = tempfile::tempdir().unwrap();
+    for split in [false, true] {
+        let path = dir.path().join(format!("disk_size_{}.db", split));
+        let mut db = if split {
+            MyDB::new_split(&path).unwrap()
+        } else {
+            MyDB::new(&path).unwrap()
+        };
+        assert_eq!(db.disk_size(), 0);
+        assert_eq!(db.live_bytes(), 0);
+
+        db.set("hello", "world").unwrap();
+        db.set("foo", "bar").unwrap();
+        assert_eq!(db.disk_size(), db.live_bytes());
+
+        db.set("hello", "mars").unwrap();
+        db.set("foo", "baz").unwrap();
+        assert!(db.disk_size() > db.live_bytes());
+
+        db.compact().unwrap();
+        assert_eq!(db.disk_size(), db.live_bytes());
+        if !split {
+            assert_eq!(db.disk_size(), fs::metadata(&path).unwrap().len());
+        }
+    }
+}
+
+#[test]
+fn test_file_checksum() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("checksum.db");
+    let backup = dir.path().join("backup.db");
+
+    let mut db = MyDB::new(&path).unwrap();
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    let checksum = db.file_checksum().unwrap();
+    assert_eq!(db.file_checksum().unwrap(), checksum);
+
+    fs::copy(&path, &backup).unwrap();
+    let mut copy = MyDB::new(&backup).unwrap();
+    assert_eq!(copy.file_checksum().unwrap(), checksum);
+
+    copy.set("hello", "mars").unwrap();
+    assert_ne!(copy.file_checksum().unwrap(), checksum);
+}
+
+#[test]
+fn test_reserve() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("reserve.db");
+
+    let mut db = MyDB::new(&path).unwrap();
+    db.set("hello", "world").unwrap();
+    db.reserve(4096).unwrap();
+    let len = fs::metadata(&path).unwrap().len();
+    assert!(len >= db.disk_size() + 4096);
+
+    db.set("foo", "bar").unwrap();
+    db.set("hello", "mars").unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    assert_eq!(db.history("hello").unwrap(), ["world", "mars"]);
+    db.check_invariants().unwrap();
+    drop(db);
+
+    // Reserved space isn't mistaken for records.
+    assert_eq!(estimate_records(&path).unwrap(), 3);
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+    assert_eq!(db.len(), 2);
+
+    db.reserve(4096).unwrap();
+    db.set("bar", "foo").unwrap();
+    db.truncate_to_fit().unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), db.disk_size());
+    drop(db);
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.get("bar").unwrap(), Some("foo".to_string()));
+}
+
+#[test]
+fn test_empty_record_at_epoch() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("empty_record_at_epoch.db");
+
+    // An empty key and value written at the epoch isn't mistaken for reserved space.
+    let mut db = MyDB::new(&path).unwrap().with_clock(|| time::UNIX_EPOCH);
+    db.set("", "").unwrap();
+    db.set("a", "b").unwrap();
+    assert_eq!(db.metadata("").unwrap().timestamp, 1);
+    drop(db);
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.len(), 2);
+    assert_eq!(db.get("").unwrap(), Some(String::new()));
+    assert_eq!(db.get("a").unwrap(), Some("b".to_string()));
+    assert_eq!(fs::metadata(&path).unwrap().len(), db.disk_size());
+
+    let record = format::Record {
+        timestamp: 0,
+        key: String::new(),
+        value: Some(String::new()),
+    };
+    let bytes = format::encode_record(&record).unwrap();
+    assert_eq!(format::decode_record(&bytes).unwrap().0.timestamp, 1);
+}
+
+#[test]
+fn test_max_file_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("max_file_size.db");
+
+    // Records of a 5-byte key and a 5-byte value take 22 bytes.
+    let mut db = MyDB::new(&path).unwrap().with_max_file_size(44);
+    db.set("hello", "world").unwrap();
+    db.set("hello", "mars!").unwrap();
+    assert_eq!(db.disk_size(), 44);
+
+    assert!(matches!(
+        db.set("foo", "bar"),
+        Err(Error::FileSizeLimitExceeded)
+    ));
+    assert!(matches!(
+        db.delete("hello"),
+        Err(Error::FileSizeLimitExceeded)
+    ));
+    assert_eq!(fs::metadata(&path).unwrap().len(), 44);
+    assert_eq!(db.get("hello").unwrap(), Some("mars!".to_string()));
+    assert_eq!(db.get("foo").unwrap(), None);
+
+    // Compaction makes room again.
+    db.compact().unwrap();
+    db.set("foo", "bar").unwrap();
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+}
+
+#[test]
+fn test_compact_temp_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data").join("compact.db");
+    let temp_dir = dir.path().join("scratch");
+    fs::create_dir(path.parent().unwrap()).unwrap();
+    fs::create_dir(&temp_dir).unwrap();
+
+    let mut db = MyDB::new(&path).unwrap().with_temp_dir(&temp_dir).unwrap();
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    db.set("hello", "mars").unwrap();
+
+    let mut progress = Vec::new();
+    db.compact_with_progress(|stats| {
+        progress.push(stats);
+        // The compacted file is written to the temporary directory.
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 1);
+    })
+    .unwrap();
+    assert_eq!(progress.len(), 2);
+    assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+    assert_eq!(db.disk_size(), fs::metadata(&path).unwrap().len());
+    drop(db);
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+    assert_eq!(db.history("hello").unwrap(), ["mars"]);
+
+    let missing = dir.path().join("missing");
+    assert!(matches!(db.with_temp_dir(&missing), Err(Error::IoError(_))));
+}
+
+#[test]
+fn test_delete() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.db");
+    let mut db = MyDB::new(&path).unwrap();
+
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    assert!(db.delete("hello").unwrap());
+    assert_eq!(db.get("hello").unwrap(), None);
+    assert_eq!(db.len(), 1);
+
+    let len = fs::metadata(&path).unwrap().len();
+    assert!(!db.delete("hello").unwrap());
+    assert!(!db.delete("unknown_key").unwrap());
+    assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    drop(db);
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), None);
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+
+    db.set("hello", "mars").unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+
+    db.compact().unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+    assert!(db.delete("foo").unwrap());
+    drop(db);
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.get("foo").unwrap(), None);
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+}
+
+#[test]
+fn test_delete_split() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("split.db");
+    let mut db = MyDB::new_split(&path).unwrap();
+
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    assert!(db.delete("hello").unwrap());
+    assert_eq!(db.get("hello").unwrap(), None);
+    drop(db);
+
+    let mut db = MyDB::new_split(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), None);
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+}
+
+#[test]
+fn test_delete_many() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("delete_many.db");
+
+    let mut db = MyDB::new(&path).unwrap();
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    db.set("bar", "foo").unwrap();
+
+    assert_eq!(db.delete_many(&[]).unwrap(), 0);
+    assert_eq!(
+        db.delete_many(&["hello", "missing", "bar", "hello"])
+            .unwrap(),
+        2
+    );
+    assert_eq!(db.get("hello").unwrap(), None);
+    assert_eq!(db.get("bar").unwrap(), None);
+    assert_eq!(db.len(), 1);
+    drop(db);
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), None);
+    assert_eq!(db.get("bar").unwrap(), None);
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+    assert_eq!(db.len(), 1);
+}
+
+#[test]
+fn test_pop() {
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    assert_eq!(db.pop_first().unwrap(), None);
+    assert_eq!(db.pop_last().unwrap(), None);
+
+    db.extend([("b", "2"), ("d", "4"), ("a", "1"), ("c", "3")])
+        .unwrap();
+    assert_eq!(
+        db.pop_first().unwrap(),
+        Some(("a".to_string(), "1".to_string()))
+    );
+    assert_eq!(
+        db.pop_last().unwrap(),
+        Some(("d".to_string(), "4".to_string()))
+    );
+    assert_eq!(db.get("a").unwrap(), None);
+    assert_eq!(db.get("d").unwrap(), None);
+    assert_eq!(db.len(), 2);
+
+    assert_eq!(
+        db.pop_last().unwrap(),
+        Some(("c".to_string(), "3".to_string()))
+    );
+    assert_eq!(
+        db.pop_last().unwrap(),
+        Some(("b".to_string(), "2".to_string()))
+    );
+    assert_eq!(db.pop_first().unwrap(), None);
+    assert!(db.is_empty());
+}
+
+#[test]
+fn test_rename() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("rename.db");
+    let mut db = MyDB::new(&path).unwrap();
+
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    assert!(db.rename("hello", "foo").unwrap());
+    assert_eq!(db.get("hello").unwrap(), None);
+    assert_eq!(db.get("foo").unwrap(), Some("world".to_string()));
+    assert_eq!(db.len(), 1);
+    drop(db);
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), None);
+    assert_eq!(db.get("foo").unwrap(), Some("world".to_string()));
+}
+
+#[test]
+fn test_rename_absent() {
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    db.set("foo", "bar").unwrap();
+    let entries = db.debug_entries();
+
+    assert!(!db.rename("hello", "foo").unwrap());
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+    assert_eq!(db.debug_entries(), entries);
+}
+
+#[test]
+fn test_rename_same_key() {
+    let mut db = MyDB::new_from_storage(io::Cursor::new(Vec::new())).unwrap();
+    db.set("foo", "bar").unwrap();
+    let entries = db.debug_entries();
+
+    assert!(db.rename("foo", "foo").unwrap());
+    assert!(!db.rename("hello", "hello").unwrap());
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+    assert_eq!(db.debug_entries(), entries);
+}
+
+#[test]
+fn test_rename_split() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("split.db");
+    let mut db = MyDB::new_split(&path).unwrap();
+
+    db.set("hello", "world").unwrap();
+    assert!(db.rename("hello", "foo").unwrap());
+    drop(db);
+
+    let mut db = MyDB::new_split(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), None);
+    assert_eq!(db.get("foo").unwrap(), Some("world".to_string()));
+}
+
+#[test]
+fn test_sync_policy() {
+    let dir = tempfile::tempdir().unwrap();
+
+    for policy in [SyncPolicy::All, SyncPolicy::Data] {
+        let path = dir.path().join(format!("{:?}.db", policy));
+
+        let mut db = MyDB::new(&path).unwrap().with_sync_policy(policy);
+        db.set("hello", "world").unwrap();
+        db.set("foo", "bar").unwrap();
+        db.delete("foo").unwrap();
+        db.compact().unwrap();
+        db.set("hello", "mars").unwrap();
+        drop(db);
+
+        let mut db = MyDB::new(&path).unwrap();
+        assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+        assert_eq!(db.get("foo").unwrap(), None);
+    }
+}
+
+#[test]
+fn test_in_place_updates() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.db");
+    let mut db = MyDB::new(&path).unwrap().with_in_place_updates(true);
+
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    let len = fs::metadata(&path).unwrap().len();
+
+    db.set("hello", "mars!").unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    assert_eq!(db.get("hello").unwrap(), Some("mars!".to_string()));
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+
+    // Values of another length are still appended.
+    db.set("hello", "mars").unwrap();
+    assert!(fs::metadata(&path).unwrap().len() > len);
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+    drop(db);
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+}
+
+#[test]
+fn test_in_place_updates_hint() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("in_place_hint.db");
+    let hint = dir.path().join("in_place_hint.db.hint");
+    let now = Arc::new(AtomicU64::new(1000));
+    let clock = {
+        let now = now.clone();
+        move || time::UNIX_EPOCH + time::Duration::from_secs(now.load(Ordering::SeqCst))
+    };
+    let mut db = MyDB::new(&path)
+        .unwrap()
+        .with_clock(clock)
+        .with_in_place_updates(true);
+
+    db.set("hello", "world").unwrap();
+    db.compact_with_hint().unwrap();
+    assert!(hint.exists());
+
+    // The overwritten record has a new timestamp the hint file doesn't know about.
+    now.store(2000, Ordering::SeqCst);
+    let len = fs::metadata(&path).unwrap().len();
+    db.set("hello", "mars!").unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    assert!(!hint.exists());
+    drop(db);
+
+    let mut db = MyDB::new_with_hint(&path).unwrap();
+    db.check_invariants().unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("mars!".to_string()));
+}
+
+#[test]
+fn test_size_histogram() {
+    let storage = io::Cursor::new(Vec::new());
+    let mut db = MyDB::new_from_storage(storage).unwrap();
+
+    assert_eq!(db.size_histogram(), SizeHistogram::default());
+
+    for i in 1..=100 {
+        db.set(&format!("key{}", i), &"x".repeat(i)).unwrap();
+    }
+    db.set("key100", &"x".repeat(100)).unwrap();
+
+    assert_eq!(
+        db.size_histogram(),
+        SizeHistogram {
+            count: 100,
+            min: 1,
+            max: 100,
+            mean: 50.5,
+            p50: 50,
+            p90: 90,
+            p99: 99,
+        }
+    );
+}
+
+#[test]
+fn test_get_reads_value_only() {
+    let (storage, counters) = CountingStorage::new();
+    let mut db = MyDB::new_from_storage(storage).unwrap();
+
+    let key = "k".repeat(100);
+    db.set(&key, "world").unwrap();
+    db.set("foo", "bar").unwrap();
+
+    let bytes_read = counters.bytes_read.get();
+    assert_eq!(db.get(&key).unwrap(), Some("world".to_string()));
+    assert_eq!(counters.bytes_read.get() - bytes_read, "world".len());
+
+    let bytes_read = counters.bytes_read.get();
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+    assert_eq!(counters.bytes_read.get() - bytes_read, "bar".len());
+}
+
+#[test]
+fn test_background_sync() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.db");
+    let mut db = MyDB::new(&path)
+        .unwrap()
+        .with_background_sync(time::Duration::from_millis(10))
+        .unwrap();
+
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    thread::sleep(time::Duration::from_millis(50));
+    db.set("hello", "mars").unwrap();
+    drop(db);
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+    assert_eq!(db.get("foo").unwrap(), Some("bar".to_string()));
+}
+
+#[test]
+fn test_estimate_records() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(estimate_records(&path).unwrap(), 0);
+
+    for i in 0..10 {
+        db.set(&format!("key{}", i), "value").unwrap();
+    }
+    db.set("key0", "other value").unwrap();
+    db.delete("key1").unwrap();
+    drop(db);
+
+    assert_eq!(estimate_records(&path).unwrap(), 12);
+
+    // A truncated record isn't counted.
+    let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(file.metadata().unwrap().len() - 1).unwrap();
+    assert_eq!(estimate_records(&path).unwrap(), 11);
+}
+
+#[test]
+fn test_decode_mode() {
+    // Record for key "hello" with a value which isn't valid utf-8.
+    let mut data = Vec::new();
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(&5u32.to_le_bytes());
+    data.extend_from_slice(&3u32.to_le_bytes());
+    data.extend_from_slice(b"hello");
+    data.extend_from_slice(&[b'f', 0xff, b'o']);
+
+    let storage = io::Cursor::new(data.clone());
+    let mut db = MyDB::new_from_storage(storage).unwrap();
+    assert!(matches!(db.get("hello"), Err(Error::DecodeError(_))));
+
+    let storage = io::Cursor::new(data);
+    let mut db = MyDB::new_from_storage(storage)
+        .unwrap()
+        .with_decode_mode(DecodeMode::Lossy);
+    assert_eq!(db.get("hello").unwrap(), Some("f\u{FFFD}o".to_string()));
+}
+
+#[test]
+fn test_set_idempotent() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.db");
+    let mut db = MyDB::new(&path).unwrap();
+
+    assert!(db.set_idempotent("hello", "world").unwrap());
+    let len = fs::metadata(&path).unwrap().len();
+
+    assert!(!db.set_idempotent("hello", "world").unwrap());
+    assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+    // Plain writes are still appended.
+    assert!(db.set("hello", "world").unwrap());
+    assert!(fs::metadata(&path).unwrap().len() > len);
+
+    assert!(db.set_idempotent("hello", "mars").unwrap());
+    assert_eq!(db.get("hello").unwrap(), Some("mars".to_string()));
+}
+
+#[test]
+fn test_interrupted_calls() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("interrupted.db");
+    let open = || {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .unwrap();
+        MyDB::new_from_storage(InterruptingStorage::new(file)).unwrap()
+    };
+
+    let mut db = open();
+    db.set("hello", "world").unwrap();
+    db.set("foo", "bar").unwrap();
+    db.delete("foo").unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("world".to_string()));
+    drop(db);
+
+    let mut db = open();
+    assert_eq!(db.get("hello").unwrap(), Some("world".to_string()));
+    assert_eq!(db.get("foo").unwrap(), None);
+    db.compact().unwrap();
+    db.check_invariants().unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("world".to_string()));
+}
+
+#[test]
+fn test_compact_bounded_memory() {
+    let (storage, counters) = CountingStorage::new();
+    let mut db = MyDB::new_from_storage(storage).unwrap();
+
+    // Live records are much larger than what compaction is allowed to read at once.
+    let value = |i: usize, version: usize| format!("{}-{}", i, version).repeat(50_000);
+    for version in 0..3 {
+        for i in 0..4 {
+            db.set(&i.to_string(), &value(i, version)).unwrap();
+        }
+    }
+
+    counters.max_read.set(0);
+    db.compact().unwrap();
+    assert!(counters.max_read.get() <= 64 * 1024);
+
+    db.check_invariants().unwrap();
+    assert_eq!(db.disk_size(), db.live_bytes());
+    for i in 0..4 {
+        assert_eq!(db.get(&i.to_string()).unwrap(), Some(value(i, 2)));
+    }
+}
+
+#[test]
+fn test_max_unsynced_bytes() {
+    let (storage, counters) = CountingStorage::new();
+    let mut db = MyDB::new_from_storage(storage)
+        .unwrap()
+        .with_sync_policy(SyncPolicy::Never)
+        .with_max_unsynced_bytes(50);
+
+    // Records of a 5-byte key and a 5-byte value take 22 bytes.
+    db.set("key-1", "value").unwrap();
+    db.set("key-2", "value").unwrap();
+    assert_eq!(counters.syncs.get(), 0);
+    db.set("key-3", "value").unwrap();
+    assert_eq!(counters.syncs.get(), 1);
+
+    db.set("key-4", "value").unwrap();
+    db.set("key-5", "value").unwrap();
+    assert_eq!(counters.syncs.get(), 1);
+    db.set("key-6", "value").unwrap();
+    assert_eq!(counters.syncs.get(), 2);
+
+    // Syncing explicitly resets the count.
+    db.set("key-7", "value").unwrap();
+    db.sync().unwrap();
+    assert_eq!(counters.syncs.get(), 3);
+    db.set("key-8", "value").unwrap();
+    db.set("key-9", "value").unwrap();
+    assert_eq!(counters.syncs.get(), 3);
+}
+
+#[test]
+fn test_close() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("close.db");
+
+    let (storage, counters) = CountingStorage::new();
+    let mut db = MyDB::new_from_storage(storage)
+        .unwrap()
+        .with_sync_policy(SyncPolicy::Never);
+    db.set("hello", "world").unwrap();
+    assert_eq!(counters.syncs.get(), 0);
+    db.close().unwrap();
+    assert_eq!(counters.syncs.get(), 1);
+
+    let mut db = MyDB::new(&path)
+        .unwrap()
+        .with_background_sync(time::Duration::from_secs(3600))
+        .unwrap();
+    db.set("hello", "world").unwrap();
+    db.close().unwrap();
+    let mut db = MyDB::open_read_only(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("world".to_string()));
+    db.close().unwrap();
+}
+
+#[test]
+fn test_sync_policy_never_batches() {
+    let (storage, counters) = CountingStorage::new();
+    let mut db = MyDB::new_from_storage(storage)
+        .unwrap()
+        .with_sync_policy(SyncPolicy::Never);
+    let flushes = counters.flushes.get();
+    for i in 0..10 {
+        db.set(&format!("key-{}", i), "value").unwrap();
+    }
+    db.delete("key-0").unwrap();
+    assert_eq!(counters.flushes.get(), flushes);
+    assert_eq!(counters.syncs.get(), 0);
+
+    db.sync().unwrap();
+    assert_eq!(counters.flushes.get(), flushes + 1);
+    assert_eq!(counters.syncs.get(), 1);
+
+    let mut db = db.with_sync_policy(SyncPolicy::All);
+    db.set("hello", "world").unwrap();
+    assert_eq!(counters.flushes.get(), flushes + 2);
+    assert_eq!(counters.syncs.get(), 2);
+}
+
+#[test]
+fn test_set_writes_same_records() {
+    let dir = tempfile::tempdir().unwrap();
+    let write = |path: &path::Path, options: OpenOptions| {
+        let mut db = options
+            .open(path)
+            .unwrap()
+            .with_sync_policy(SyncPolicy::Never)
+            .with_clock(|| time::UNIX_EPOCH + time::Duration::from_secs(1000));
+        for i in 0..2000 {
+            db.set(&format!("key-{}", i % 300), &"v".repeat(i % 50))
+                .unwrap();
+            if i % 7 == 0 {
+                db.delete(&format!("key-{}", i % 300)).unwrap();
+            }
+        }
+        db.close().unwrap();
+        fs::read(path).unwrap()
+    };
+
+    // Records are written from their parts, unless the write is logged.
+    let direct = write(&dir.path().join("direct.db"), OpenOptions::new());
+    let logged = write(
+        &dir.path().join("logged.db"),
+        OpenOptions::new().with_wal(true),
+    );
+    assert_eq!(direct, logged);
+
+    let mut offset = 0;
+    while offset < direct.len() {
+        let (_, size) = format::decode_record(&direct[offset..]).unwrap();
+        offset += size;
+    }
+    assert_eq!(offset, direct.len());
+}
+
+#[test]
+fn test_sync_on_drop() {
+    let (storage, counters) = CountingStorage::new();
+    let mut db = MyDB::new_from_storage(storage)
+        .unwrap()
+        .with_sync_policy(SyncPolicy::Never)
+        .with_sync_on_drop(false);
+    db.set("hello", "world").unwrap();
+    let flushes = counters.flushes.get();
+    drop(db);
+    assert_eq!(counters.flushes.get(), flushes + 1);
+    assert_eq!(counters.syncs.get(), 0);
+
+    // Without syncing the database file, the write-ahead log isn't emptied but it still has the
+    // writes.
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.db");
+    let wal_len = || fs::metadata(dir.path().join("test.db.wal")).unwrap().len();
+    let options = OpenOptions::new().with_wal(true);
+    let mut db = options.open(&path).unwrap().with_sync_on_drop(false);
+    db.set("hello", "world").unwrap();
+    drop(db);
+    assert!(wal_len() > 0);
+
+    let mut db = options.open(&path).unwrap();
+    assert_eq!(db.get("hello").unwrap(), Some("world".to_string()));
+    db.set("foo", "bar").unwrap();
+    drop(db);
+    assert_eq!(wal_len(), 0);
+}
+
+#[test]
+fn test_value_hash() {
+    let (storage, counters) = CountingStorage::new();
+    let mut db = MyDB::new_from_storage(storage).unwrap();
+    db.set("a", "same").unwrap();
+    db.set("b", "same").unwrap();
+    db.set("c", "different").unwrap();
+    assert_eq!(db.value_hash("a"), db.value_hash("b"));
+    assert_ne!(db.value_hash("a"), db.value_hash("c"));
+    assert!(db.value_hash("a").is_some());
+    assert_eq!(db.value_hash("missing"), None);
+
+    // The current value isn't read to compare it.
+    let reads = counters.reads.get();
+    assert!(!db.set_idempotent("a", "same").unwrap());
+    assert!(db.set_idempotent("a", "new").unwrap());
+    assert_eq!(counters.reads.get(), reads);
+    assert_eq!(db.get("a").unwrap(), Some("new".to_string()));
+    assert_ne!(db.value_hash("a"), db.value_hash("b"));
+}
+
+#[test]
+fn test_value_hash_after_open() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("hash.db");
+    let mut db = MyDB::new(&path).unwrap();
+    db.set("hello", "world").unwrap();
+    let hash = db.value_hash("hello");
+    drop(db);
+
+    let mut db = MyDB::new(&path).unwrap();
+    assert_eq!(db.value_hash("hello"), None);
+    assert!(!db.set_idempotent("hello", "world").unwrap());
+    assert_eq!(db.value_hash("hello"), hash);
+}
+
+#[test]
+fn test_value_hash_reload_split() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut db = MyDB::new_split(dir.path().join("hash_split.db")).unwrap();
+    db.set("hello", "world").unwrap();
+    let hash = db.value_hash("hello");
+    assert!(hash.is_some());
+    assert!(!db.reload().unwrap());
+    assert_eq!(db.value_hash("hello"), hash);
+}
+
+/// Counters of the calls made to a [`CountingStorage`].
+#[derive(Default)]
+struct Counters {
+    reads: Cell<usize>,
+    bytes_read: Cell<usize>,
+    max_read: Cell<usize>, // largest buffer passed to a single read
+    syncs: Cell<usize>,
+    flushes: Cell<usize>,
+    seeks: RefCell<Vec<u64>>, // positions of the seeks from the start
+}
+
+/// In-memory storage which counts the calls made to it.
+struct CountingStorage {
+    inner: io::Cursor<Vec<u8>>,
+    counters: Rc<Counters>,
+}
+
+impl CountingStorage {
+    fn new() -> (Self, Rc<Counters>) {
+        let counters = Rc::new(Counters::default());
+        let storage = CountingStorage {
+            inner: io::Cursor::new(Vec::new()),
+            counters: counters.clone(),
+        };
+        (storage, counters)
+    }
+}
+
+impl io::Read for CountingStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.counters.reads.set(self.counters.reads.get() + 1);
+        let max_read = std::cmp::max(self.counters.max_read.get(), buf.len());
+        self.counters.max_read.set(max_read);
+        let n = self.inner.read(buf)?;
+        self.counters
+            .bytes_read
+            .set(self.counters.bytes_read.get() + n);
+        Ok(n)
+    }
+}
+
+impl io::Write for CountingStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.counters.flushes.set(self.counters.flushes.get() + 1);
+        self.inner.flush()
+    }
+}
+
+impl io::Seek for CountingStorage {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        if let io::SeekFrom::Start(offset) = pos {
+            self.counters.seeks.borrow_mut().push(offset);
+        }
+        self.inner.seek(pos)
+    }
+}
+
+impl Storage for CountingStorage {
+    fn sync(&mut self) -> io::Result<()> {
+        self.counters.syncs.set(self.counters.syncs.get() + 1);
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+}
+
+/// In-memory storage which can be moved to another thread and tracks how much of it is synced.
+struct SyncTrackingStorage {
+    inner: io::Cursor<Vec<u8>>,
+    syncs: Arc<AtomicU64>,
+    synced_len: Arc<AtomicU64>, // length of the storage when it was last synced
+}
+
+impl io::Read for SyncTrackingStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl io::Write for SyncTrackingStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl io::Seek for SyncTrackingStorage {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl Storage for SyncTrackingStorage {
+    fn sync(&mut self) -> io::Result<()> {
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        let len = self.inner.get_ref().len() as u64;
+        self.synced_len.store(len, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+}
+
+/// File storage where every read, write and seek is interrupted once before succeeding.
+struct InterruptingStorage {
+    inner: fs::File,
+    interrupted: bool,
+}
+
+impl InterruptingStorage {
+    fn new(inner: fs::File) -> Self {
+        InterruptingStorage {
+            inner,
+            interrupted: false,
+        }
+    }
+
+    /// Fails with [`io::ErrorKind::Interrupted`] every other call.
+    fn interrupt(&mut self) -> io::Result<()> {
+        self.interrupted = !self.interrupted;
+        if self.interrupted {
+            return Err(io::ErrorKind::Interrupted.into());
+        }
+        Ok(())
+    }
+}
+
+impl io::Read for InterruptingStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.interrupt()?;
+        self.inner.read(buf)
+    }
+}
+
+impl io::Write for InterruptingStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.interrupt()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl io::Seek for InterruptingStorage {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.interrupt()?;
+        self.inner.seek(pos)
+    }
+}
+
+impl Storage for InterruptingStorage {
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync_all()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+}